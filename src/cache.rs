@@ -1,79 +1,211 @@
 #![allow(unused)]
 use anyhow::Context;
-use hickory_proto::rr::{Record, RecordType};
+use hickory_proto::op::ResponseCode;
+use hickory_proto::rr::{DNSClass, Record, RecordType};
 use lru::LruCache;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::num::NonZeroUsize;
 use std::sync::{Mutex, MutexGuard};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant};
 use tokio_util::bytes::BufMut;
 
-pub struct Inner(LruCache<String, Vec<Record>>);
+struct Cached {
+    expires_at: Instant,
+    record: Record,
+    /// 已发起预取，避免同一记录重复刷新
+    prefetching: bool,
+}
+
+/// 否定应答（NXDOMAIN / NODATA）
+struct Negative {
+    expires_at: Instant,
+    class: DNSClass,
+    /// None 表示 NXDOMAIN，对该类别的所有类型生效
+    rtype: Option<RecordType>,
+    soa: Record,
+}
+
+pub struct Inner {
+    records: LruCache<String, Vec<Cached>>,
+    negatives: LruCache<String, Vec<Negative>>,
+}
 
 impl Inner {
     fn with_capacity(capacity: usize) -> Self {
-        Self(LruCache::new(NonZeroUsize::new(capacity).unwrap()))
+        let capacity = NonZeroUsize::new(capacity).unwrap();
+        Self {
+            records: LruCache::new(capacity),
+            negatives: LruCache::new(capacity),
+        }
     }
 
+    /// 缓存记录，同类别同类型的旧记录会被替换
     pub fn put(&mut self, domain: String, rrs: &[Record]) {
-        if self.0.contains(&domain) {
-            let vec = self.0.get_mut(&domain).unwrap();
-            Self::clean_expired(vec);
-            vec.extend_from_slice(rrs);
+        let now = Instant::now();
+        let cached = rrs.iter().map(|rr| Cached {
+            expires_at: now + Duration::from_secs(rr.ttl() as u64),
+            record: rr.clone(),
+            prefetching: false,
+        });
+        if let Some(vec) = self.records.get_mut(&domain) {
+            vec.retain(|it| {
+                it.expires_at > now
+                    && rrs.iter().all(|rr| {
+                        rr.record_type() != it.record.record_type()
+                            || rr.dns_class() != it.record.dns_class()
+                    })
+            });
+            vec.extend(cached);
         } else {
-            self.0.put(domain, rrs.to_vec());
+            self.records.put(domain, cached.collect());
         }
     }
-    pub fn get(&mut self, domain: &str, rtype: RecordType) -> Option<Vec<Record>> {
-        let rrs = match self.0.get_mut(domain) {
-            Some(rss) => rss,
-            None => return None,
-        };
-        Self::clean_expired(rrs);
+    /// 返回未过期的记录，TTL 为剩余时间
+    pub fn get(&mut self, domain: &str, rtype: RecordType, class: DNSClass) -> Option<Vec<Record>> {
+        let now = Instant::now();
+        let rrs = self.records.get_mut(domain)?;
+        rrs.retain(|it| it.expires_at > now);
         if rrs.is_empty() {
-            self.0.pop(domain);
+            self.records.pop(domain);
             return None;
         }
-        return Some(
+        Some(
             rrs.iter()
-                .filter(|rr| rr.record_type() == rtype)
-                .cloned()
+                .filter(|it| it.record.record_type() == rtype && it.record.dns_class() == class)
+                .map(|it| remaining(&it.record, it.expires_at, now))
                 .collect::<Vec<_>>(),
-        );
+        )
     }
-    fn clean_expired(rrs: &mut Vec<Record>) {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        rrs.retain(|rr| rr.ttl() as u64 <= now);
+    /// 记录已消耗的 TTL 比例达到 threshold 且尚未预取时标记为预取中，返回是否需要发起预取
+    pub fn claim_prefetch(
+        &mut self,
+        domain: &str,
+        rtype: RecordType,
+        class: DNSClass,
+        threshold: f64,
+    ) -> bool {
+        let now = Instant::now();
+        let Some(rrs) = self.records.peek_mut(domain) else {
+            return false;
+        };
+        let mut matched = rrs
+            .iter_mut()
+            .filter(|it| {
+                it.expires_at > now
+                    && it.record.record_type() == rtype
+                    && it.record.dns_class() == class
+            })
+            .peekable();
+        let Some(first) = matched.peek() else {
+            return false;
+        };
+        let ttl = first.record.ttl() as f64;
+        let left = first.expires_at.duration_since(now).as_secs_f64();
+        if first.prefetching || ttl <= 0.0 || left > ttl * (1.0 - threshold) {
+            return false;
+        }
+        matched.for_each(|it| it.prefetching = true);
+        true
+    }
+    /// 缓存否定应答，rtype 为 None 时表示 NXDOMAIN
+    pub fn put_negative(
+        &mut self,
+        domain: String,
+        class: DNSClass,
+        rtype: Option<RecordType>,
+        soa: Record,
+        ttl: u32,
+    ) {
+        let now = Instant::now();
+        let negative = Negative {
+            expires_at: now + Duration::from_secs(ttl as u64),
+            class,
+            rtype,
+            soa,
+        };
+        if let Some(vec) = self.negatives.get_mut(&domain) {
+            vec.retain(|it| it.expires_at > now && (it.class != class || it.rtype != rtype));
+            vec.push(negative);
+        } else {
+            self.negatives.put(domain, vec![negative]);
+        }
+    }
+    /// 返回命中的否定应答的响应码及 SOA 记录
+    pub fn get_negative(
+        &mut self,
+        domain: &str,
+        rtype: RecordType,
+        class: DNSClass,
+    ) -> Option<(ResponseCode, Record)> {
+        let now = Instant::now();
+        let negatives = self.negatives.get_mut(domain)?;
+        negatives.retain(|it| it.expires_at > now);
+        if negatives.is_empty() {
+            self.negatives.pop(domain);
+            return None;
+        }
+        negatives.iter().filter(|it| it.class == class).find_map(|it| match it.rtype {
+            None => Some((ResponseCode::NXDomain, remaining(&it.soa, it.expires_at, now))),
+            Some(t) if t == rtype => {
+                Some((ResponseCode::NoError, remaining(&it.soa, it.expires_at, now)))
+            }
+            _ => None,
+        })
     }
 }
 
+fn remaining(record: &Record, expires_at: Instant, now: Instant) -> Record {
+    let mut record = record.clone();
+    record.set_ttl(expires_at.duration_since(now).as_secs() as u32);
+    record
+}
+
+/// 按域名哈希分片，每个分片独立加锁以降低并发查询时的锁竞争
+const SHARDS: usize = 16;
+
 pub struct Cache {
-    inner: Option<Mutex<Inner>>,
+    shards: Vec<Mutex<Inner>>,
 }
 
 impl Cache {
     pub fn enabled(&self) -> bool {
-        self.inner.is_some()
+        !self.shards.is_empty()
     }
     pub fn with_capacity(capacity: usize) -> Self {
-        if capacity == 0 {
-            Self { inner: None }
+        let count = SHARDS.min(capacity);
+        let shard_capacity = if count == 0 {
+            0
         } else {
-            Self {
-                inner: Some(Mutex::new(Inner::with_capacity(capacity))),
-            }
+            capacity.div_ceil(count)
+        };
+        Self {
+            shards: (0..count)
+                .map(|_| Mutex::new(Inner::with_capacity(shard_capacity)))
+                .collect(),
         }
     }
-    pub fn access(&self) -> anyhow::Result<Option<MutexGuard<Inner>>> {
-        if let Some(inner) = &self.inner {
-            Ok(Some(inner.lock().map_err(|err| {
-                anyhow::format_err!("Failed to lock cache, reason: {}", err)
-            })?))
-        } else {
-            Ok(None)
+    /// 各分片中已缓存的域名数量
+    pub fn entries(&self) -> usize {
+        self.shards
+            .iter()
+            .filter_map(|shard| shard.lock().ok().map(|it| it.records.len()))
+            .sum()
+    }
+    /// 获取域名所在分片的锁
+    pub fn access(&self, domain: &str) -> anyhow::Result<Option<MutexGuard<'_, Inner>>> {
+        if !self.enabled() {
+            return Ok(None);
         }
+        let shard = &self.shards[self.shard_index(domain)];
+        Ok(Some(shard.lock().map_err(|err| {
+            anyhow::format_err!("Failed to lock cache, reason: {}", err)
+        })?))
+    }
+    fn shard_index(&self, domain: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        domain.to_ascii_lowercase().hash(&mut hasher);
+        hasher.finish() as usize % self.shards.len()
     }
 }
 
@@ -84,3 +216,156 @@ where
     let mut i = 0;
     move |item| (f(i, item), i += 1).0
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::rr::{rdata, Name, RData};
+    use std::sync::{mpsc, Arc, Barrier};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn negative_entries() {
+        let mut inner = Inner::with_capacity(16);
+        let soa = Record::from_rdata(
+            Name::from_ascii("example.com.").unwrap(),
+            3600,
+            RData::SOA(rdata::SOA::new(
+                Name::root(),
+                Name::root(),
+                1,
+                3600,
+                600,
+                86400,
+                60,
+            )),
+        );
+        let (a, b) = ("a.example.com.".to_string(), "b.example.com.".to_string());
+        inner.put_negative(a, DNSClass::IN, Some(RecordType::AAAA), soa.clone(), 30);
+        inner.put_negative(b, DNSClass::IN, None, soa, 30);
+        assert!(inner.get_negative("a.example.com.", RecordType::A, DNSClass::IN).is_none());
+        let (code, soa) = inner
+            .get_negative("a.example.com.", RecordType::AAAA, DNSClass::IN)
+            .unwrap();
+        assert_eq!(code, ResponseCode::NoError);
+        assert!(soa.ttl() <= 30);
+        let (code, _) = inner
+            .get_negative("b.example.com.", RecordType::MX, DNSClass::IN)
+            .unwrap();
+        assert_eq!(code, ResponseCode::NXDomain);
+    }
+
+    #[test]
+    fn prefetch_claimed_once() {
+        let mut inner = Inner::with_capacity(8);
+        let name = Name::from_ascii("example.com.").unwrap();
+        let record = |ttl| Record::from_rdata(name.clone(), ttl, RData::A(rdata::A::new(10, 0, 0, 1)));
+        inner.put("example.com.".to_string(), &[record(300)]);
+        assert!(!inner.claim_prefetch("example.com.", RecordType::A, DNSClass::IN, 0.9));
+        // TTL 为 0 的记录立即过期，不会触发预取
+        inner.put("zero.example.com.".to_string(), &[record(0)]);
+        assert!(!inner.claim_prefetch("zero.example.com.", RecordType::A, DNSClass::IN, 0.9));
+        inner.put("example.com.".to_string(), &[record(1)]);
+        std::thread::sleep(Duration::from_millis(950));
+        assert!(inner.claim_prefetch("example.com.", RecordType::A, DNSClass::IN, 0.9));
+        assert!(!inner.claim_prefetch("example.com.", RecordType::A, DNSClass::IN, 0.9));
+        assert!(!inner.claim_prefetch("example.com.", RecordType::AAAA, DNSClass::IN, 0.9));
+    }
+
+    #[test]
+    fn class_separated_entries() {
+        let mut inner = Inner::with_capacity(16);
+        let name = Name::from_ascii("version.example.").unwrap();
+        let mut chaos = Record::from_rdata(
+            name.clone(),
+            60,
+            RData::TXT(rdata::TXT::new(vec!["chaos".to_string()])),
+        );
+        chaos.set_dns_class(DNSClass::CH);
+        let internet = Record::from_rdata(
+            name.clone(),
+            60,
+            RData::TXT(rdata::TXT::new(vec!["internet".to_string()])),
+        );
+        inner.put(name.to_utf8(), std::slice::from_ref(&chaos));
+        assert!(inner.get(&name.to_utf8(), RecordType::TXT, DNSClass::IN).unwrap().is_empty());
+        // 不同类别的记录互不替换
+        inner.put(name.to_utf8(), std::slice::from_ref(&internet));
+        assert_eq!(
+            inner.get(&name.to_utf8(), RecordType::TXT, DNSClass::CH).unwrap()[0].data(),
+            chaos.data()
+        );
+        assert_eq!(
+            inner.get(&name.to_utf8(), RecordType::TXT, DNSClass::IN).unwrap()[0].data(),
+            internet.data()
+        );
+
+        let soa = Record::from_rdata(
+            name.clone(),
+            60,
+            RData::SOA(rdata::SOA::new(Name::root(), Name::root(), 1, 3600, 600, 86400, 60)),
+        );
+        inner.put_negative("missing.example.".to_string(), DNSClass::CH, None, soa, 30);
+        assert!(inner
+            .get_negative("missing.example.", RecordType::TXT, DNSClass::IN)
+            .is_none());
+        assert!(inner
+            .get_negative("missing.example.", RecordType::TXT, DNSClass::CH)
+            .is_some());
+    }
+
+    #[test]
+    fn sharded_access() {
+        let cache = Arc::new(Cache::with_capacity(1024));
+        let names = (0..64).map(|i| format!("host{}.lan.", i)).collect::<Vec<_>>();
+        let (held, other) = {
+            let first = cache.shard_index(&names[0]);
+            let other = names
+                .iter()
+                .find(|it| cache.shard_index(it) != first)
+                .unwrap();
+            (names[0].clone(), other.clone())
+        };
+        // 持有一个分片的锁时，其它分片仍可访问
+        let _guard = cache.access(&held).unwrap().unwrap();
+        let (tx, rx) = mpsc::channel();
+        {
+            let cache = cache.clone();
+            thread::spawn(move || {
+                let mut guard = cache.access(&other).unwrap().unwrap();
+                guard.put(other.clone(), &[]);
+                tx.send(()).unwrap();
+            });
+        }
+        rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    }
+
+    #[test]
+    fn concurrent_access() {
+        let cache = Arc::new(Cache::with_capacity(1024));
+        let barrier = Arc::new(Barrier::new(8));
+        let workers = (0..8)
+            .map(|worker| {
+                let cache = cache.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    for i in 0..1000 {
+                        let name = format!("host{}-{}.lan.", worker, i % 32);
+                        cache.access(&name).unwrap().unwrap().put(name.clone(), &[]);
+                        cache
+                            .access(&name)
+                            .unwrap()
+                            .unwrap()
+                            .get(&name, RecordType::A, DNSClass::IN);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert!(cache.shards.len() > 1);
+    }
+}
@@ -0,0 +1,169 @@
+use crate::config::{parse_key_value_pair, Inner};
+use anyhow::Context;
+use hickory_proto::rr::Name;
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use url::Url;
+
+/// 命中拦截列表时的应答方式
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BlockAction {
+    #[default]
+    NxDomain,
+    NoData,
+    /// A 应答 0.0.0.0，AAAA 应答 ::
+    Sinkhole,
+    Refused,
+}
+
+impl FromStr for BlockAction {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nxdomain" => Ok(BlockAction::NxDomain),
+            "nodata" => Ok(BlockAction::NoData),
+            "sinkhole" => Ok(BlockAction::Sinkhole),
+            "refused" => Ok(BlockAction::Refused),
+            _ => anyhow::bail!("Unknown block action '{}'", s),
+        }
+    }
+}
+
+pub struct Blocklist {
+    pub name: String,
+    pub path: PathBuf,
+    /// 从 URL 下载的列表，path 为下载内容的缓存文件
+    pub url: Option<Url>,
+    domains: HashSet<Name>,
+    pub action: Option<BlockAction>,
+}
+
+/// 重载时未变化的拦截列表在新旧配置间共享
+pub type Blocklists = Vec<Arc<Blocklist>>;
+
+impl std::fmt::Debug for Blocklist {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Blocklist")
+            .field("name", &self.name)
+            .field("url", &self.url.as_ref().map(Url::as_str))
+            .field("domains", &self.domains.len())
+            .field("action", &self.action)
+            .finish()
+    }
+}
+
+impl Blocklist {
+    /// 重新读取列表文件，名称及应答方式不变
+    pub fn reload(&self) -> anyhow::Result<Self> {
+        Ok(Self {
+            name: self.name.clone(),
+            path: self.path.clone(),
+            url: self.url.clone(),
+            domains: read_blocklist(&self.path)?,
+            action: self.action,
+        })
+    }
+    /// 域名本身或其任一上级域名在列表中即视为命中
+    pub fn contains(&self, domain: &Name) -> bool {
+        let mut name = domain.clone();
+        name.set_fqdn(true);
+        loop {
+            if self.domains.contains(&name) {
+                return true;
+            }
+            if name.is_root() {
+                return false;
+            }
+            name = name.base_name();
+        }
+    }
+}
+
+/// format: {name}  {path | url cache-path} [action]
+pub fn parse(
+    row: usize,
+    line: &str,
+    inner: &mut Inner,
+    watch_paths: &mut HashSet<PathBuf>,
+) -> anyhow::Result<()> {
+    let (key, value, _) = parse_key_value_pair(line)
+        .map_err(|(err, col)| anyhow::format_err!("{} in line {}:{}", err, row, col))?;
+    let mut parts = value.split_whitespace();
+    let source = parts
+        .next()
+        .with_context(|| format!("Missing blocklist path in line {}", row))?;
+    let url = if source.starts_with("http://") || source.starts_with("https://") {
+        Some(
+            Url::parse(source)
+                .with_context(|| format!("Invalid blocklist url '{}' in line {}", source, row))?,
+        )
+    } else {
+        None
+    };
+    let path = match url {
+        Some(_) => PathBuf::from(
+            parts
+                .next()
+                .with_context(|| format!("Missing blocklist cache path in line {}", row))?,
+        ),
+        None => PathBuf::from(source),
+    };
+    let action = parts
+        .next()
+        .map(BlockAction::from_str)
+        .transpose()
+        .with_context(|| format!("Invalid blocklist action in line {}", row))?;
+    // 尚未下载过的列表先以空列表加载，下载成功后替换
+    let domains = if url.is_some() && !path.exists() {
+        tracing::warn!(
+            "Blocklist '{}' has not been downloaded yet, cache path: '{:?}'",
+            key,
+            path
+        );
+        HashSet::new()
+    } else {
+        read_blocklist(&path)?
+    };
+    watch_paths.insert(path.clone());
+    inner.blocklists.push(Arc::new(Blocklist {
+        name: key,
+        path,
+        url,
+        domains,
+        action,
+    }));
+    Ok(())
+}
+
+/// 校验下载的列表内容，返回其中的域名数量
+pub fn validate(text: &str) -> anyhow::Result<usize> {
+    Ok(parse_domains(text)?.len())
+}
+
+fn read_blocklist(path: &PathBuf) -> anyhow::Result<HashSet<Name>> {
+    if !path.is_file() {
+        anyhow::bail!("Blocklist file does not exist, path: '{:?}'", path);
+    }
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Unable to read blocklist file '{:?}'", path))?;
+    parse_domains(&text).with_context(|| format!("Invalid blocklist '{:?}'", path))
+}
+
+fn parse_domains(text: &str) -> anyhow::Result<HashSet<Name>> {
+    let mut domains = HashSet::new();
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let domain = line.trim_start_matches("*.").trim_start_matches('.');
+        let mut name =
+            Name::from_ascii(domain).with_context(|| format!("Invalid domain '{}'", domain))?;
+        name.set_fqdn(true);
+        domains.insert(name);
+    }
+    Ok(domains)
+}
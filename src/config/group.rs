@@ -1,11 +1,11 @@
-use crate::config::parse_key_value_pair;
+use crate::config::{match_ipaddr, parse_key_value_pair};
 use anyhow::Context;
 use std::collections::HashMap;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::ops::Range;
 use std::str::FromStr;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum IpRange {
     Single(IpAddr),
     Range(Range<IpAddr>),
@@ -13,14 +13,51 @@ pub enum IpRange {
 
 pub type Groups = HashMap<String, Vec<IpRange>>;
 
-pub fn parse(row: usize, line: &str, groups: &mut Groups) -> anyhow::Result<()> {
+impl IpRange {
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match self {
+            IpRange::Single(single) => match_ipaddr(single, addr),
+            IpRange::Range(range) => {
+                if range.is_empty() {
+                    return false;
+                };
+                match addr {
+                    IpAddr::V4(addr) => range.contains(&IpAddr::from(addr.to_ipv6_mapped())),
+                    _ => range.contains(addr),
+                }
+            }
+        }
+    }
+}
+
+/// 分组继承关系：子分组 -> 父分组
+pub type GroupParents = HashMap<String, String>;
+
+/// format: {name}  {ranges} [@inherit {parent}]
+pub fn parse(
+    row: usize,
+    line: &str,
+    groups: &mut Groups,
+    parents: &mut GroupParents,
+) -> anyhow::Result<()> {
     let (key, value, _) = parse_key_value_pair(line)
         .map_err(|(err, col)| anyhow::format_err!("{} in line {}:{}", err, row, col))?;
-    groups.insert(key, parse_ip_range(&value)?);
+    let ranges = match value.split_once("@inherit") {
+        Some((ranges, parent)) => {
+            let parent = parent.trim();
+            if parent.is_empty() {
+                anyhow::bail!("Missing parent group after '@inherit' in line {}", row);
+            }
+            parents.insert(key.clone(), parent.to_string());
+            ranges.trim()
+        }
+        None => value.as_str(),
+    };
+    groups.insert(key, parse_ip_range(ranges)?);
     Ok(())
 }
 
-fn parse_ip_range(input: &str) -> anyhow::Result<Vec<IpRange>> {
+pub fn parse_ip_range(input: &str) -> anyhow::Result<Vec<IpRange>> {
     let mut list = Vec::new();
     for part in input.split(',').map(|it| it.trim()) {
         if part.contains('-') {
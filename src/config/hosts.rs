@@ -2,46 +2,227 @@ use crate::config::{parse_key_value_pair, read_hosts, Inner, DEFAULT_GROUP};
 use anyhow::Context;
 use hickory_proto::rr::Name;
 use std::collections::{HashMap, HashSet};
-use std::net::IpAddr;
-use std::path::PathBuf;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// 分组的 hosts 条目，加载时按域名及地址建立索引，同一域名或地址均保留文件中的顺序
+#[derive(Debug, Default, Clone)]
+pub struct Hosts {
+    names: HashMap<Name, Vec<IpAddr>>,
+    addrs: HashMap<IpAddr, Name>,
+    synth6: HashMap<Name, Synth6Prefix>,
+}
+
+/// AAAA 合成规则使用的 IPv6 前缀，按 RFC 6052 将 IPv4 地址嵌入前缀中
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Synth6Prefix {
+    prefix: Ipv6Addr,
+    len: u8,
+}
+
+impl FromStr for Synth6Prefix {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, len) = s
+            .split_once('/')
+            .with_context(|| format!("Missing prefix length in '{}'", s))?;
+        let prefix = prefix
+            .parse::<Ipv6Addr>()
+            .with_context(|| format!("Invalid IPv6 prefix '{}'", prefix))?;
+        let len = len
+            .parse::<u8>()
+            .with_context(|| format!("Invalid prefix length '{}'", len))?;
+        if ![32, 40, 48, 56, 64, 96].contains(&len) {
+            anyhow::bail!("Prefix length must be one of 32, 40, 48, 56, 64 or 96, got {}", len);
+        }
+        Ok(Self { prefix, len })
+    }
+}
+
+impl Synth6Prefix {
+    pub fn embed(&self, addr: Ipv4Addr) -> Ipv6Addr {
+        let mut bytes = self.prefix.octets();
+        let start = self.len as usize / 8;
+        bytes[start..].fill(0);
+        // 第 64~71 位保留为 0，IPv4 地址跳过该字节
+        let positions = (start..16).filter(|it| *it != 8);
+        for (pos, octet) in positions.zip(addr.octets()) {
+            bytes[pos] = octet;
+        }
+        Ipv6Addr::from(bytes)
+    }
+}
 
-pub type Hosts = Vec<(IpAddr, Name)>;
 pub type GroupHostMappings = HashMap<String, Hosts>;
 
+/// hosts 条目的来源，按配置中出现的顺序记录，用于单独重新读取 hosts 文件
+#[derive(Debug, Clone)]
+pub enum HostSource {
+    Entry(IpAddr, Name),
+    Synth6(Name, Synth6Prefix),
+    File(PathBuf),
+}
+
+pub type HostSources = Vec<(String, HostSource)>;
+
+impl Hosts {
+    pub fn insert(&mut self, addr: IpAddr, mut name: Name) {
+        // Name 的哈希区分是否以 '.' 结尾，统一为 FQDN 后再建立索引；
+        // 其相等及哈希不区分大小写，查询无需转换大小写，反向查询保留配置中的大小写
+        name.set_fqdn(true);
+        self.names.entry(name.clone()).or_default().push(addr);
+        self.addrs.entry(addr).or_insert(name);
+    }
+    /// 追加另一组条目，已有的域名及地址优先，已有域名不再合并对方的地址
+    pub fn extend(&mut self, other: &Hosts) {
+        for (name, addrs) in &other.names {
+            self.names.entry(name.clone()).or_insert_with(|| addrs.clone());
+        }
+        for (addr, name) in &other.addrs {
+            self.addrs.entry(*addr).or_insert_with(|| name.clone());
+        }
+        for (name, prefix) in &other.synth6 {
+            self.synth6.entry(name.clone()).or_insert(*prefix);
+        }
+    }
+    /// 域名没有 AAAA 记录时，用其 A 记录嵌入前缀合成 AAAA
+    pub fn insert_synth6(&mut self, mut name: Name, prefix: Synth6Prefix) {
+        name.set_fqdn(true);
+        self.synth6.entry(name).or_insert(prefix);
+    }
+    pub fn addrs(&self, name: &Name) -> Option<&[IpAddr]> {
+        if name.is_fqdn() {
+            self.names.get(name)
+        } else {
+            let mut name = name.clone();
+            name.set_fqdn(true);
+            self.names.get(&name)
+        }
+        .map(|it| it.as_slice())
+    }
+    pub fn name(&self, addr: &IpAddr) -> Option<&Name> {
+        self.addrs.get(addr)
+    }
+    pub fn synth6(&self, name: &Name) -> Option<Synth6Prefix> {
+        let mut name = name.to_lowercase();
+        name.set_fqdn(true);
+        self.synth6.get(&name).copied()
+    }
+}
+
 pub fn parse(sub: &str, row: usize, line: &str, inner: &mut Inner, watch_paths: &mut HashSet<PathBuf>) -> anyhow::Result<()> {
     if sub != DEFAULT_GROUP && !inner.groups.contains_key(sub) {
         anyhow::bail!("Can't find group '{}' definition in {row}:8", sub);
     }
     if let Some(end) = line.trim_start().strip_prefix("@include") {
-        let path = PathBuf::from(end.trim());
-        if !path.is_file() {
-            anyhow::bail!("Include file does not exist, path: '{:?}'", path);
+        for path in expand_include(Path::new(end.trim()))? {
+            read_into(&path, inner.hosts.entry(sub.to_string()).or_default())?;
+            watch_paths.insert(path.clone());
+            inner
+                .host_sources
+                .push((sub.to_string(), HostSource::File(path)));
         }
-        let hosts = read_hosts(&path)?
-            .into_iter()
-            .map(|(addr, name)| {
-                Ok((
-                    addr.parse()
-                        .with_context(|| format!("Invalid ip addr '{}'", addr))?,
-                    name,
-                ))
-            })
-            .collect::<Result<Vec<_>, anyhow::Error>>()?;
-        watch_paths.insert(path);
+    } else if let Some(rule) = line.trim_start().strip_prefix("synth6 ") {
+        // format: synth6 {domain} {prefix}
+        let mut parts = rule.split('#').next().unwrap_or_default().split_whitespace();
+        let (Some(name), Some(prefix), None) = (parts.next(), parts.next(), parts.next()) else {
+            anyhow::bail!("Expected 'synth6 {{domain}} {{prefix}}' in line {}", row);
+        };
+        let name = Name::from_ascii(name)?;
+        let prefix = prefix.parse::<Synth6Prefix>()?;
         inner
             .hosts
             .entry(sub.to_string())
             .or_default()
-            .extend(hosts);
+            .insert_synth6(name.clone(), prefix);
+        inner
+            .host_sources
+            .push((sub.to_string(), HostSource::Synth6(name, prefix)));
     } else {
         let (key, value, _) = parse_key_value_pair(line)
             .map_err(|(err, col)| anyhow::format_err!("{} in line {}:{}", err, row, col))?;
 
-        inner.hosts.entry(sub.to_string()).or_default().push((
-            key.parse()
-                .with_context(|| format!("Invalid ip addr '{}'", key))?,
-            Name::from_ascii(value)?,
-        ));
+        let addr = key
+            .parse()
+            .with_context(|| format!("Invalid ip addr '{}'", key))?;
+        let name = Name::from_ascii(value)?;
+        inner
+            .hosts
+            .entry(sub.to_string())
+            .or_default()
+            .insert(addr, name.clone());
+        inner
+            .host_sources
+            .push((sub.to_string(), HostSource::Entry(addr, name)));
+    }
+    Ok(())
+}
+
+/// 读取 hosts 文件并追加到分组的条目中
+pub fn read_into(path: &PathBuf, hosts: &mut Hosts) -> anyhow::Result<()> {
+    for (addr, name) in read_hosts(path)? {
+        hosts.insert(
+            addr.parse()
+                .with_context(|| format!("Invalid ip addr '{}'", addr))?,
+            name,
+        );
     }
     Ok(())
 }
+
+/// 按记录的来源重新读取全部分组的 hosts 条目，不包含分组继承
+pub fn rebuild(sources: &HostSources) -> anyhow::Result<GroupHostMappings> {
+    let mut mappings = GroupHostMappings::new();
+    for (group, source) in sources {
+        let hosts = mappings.entry(group.clone()).or_default();
+        match source {
+            HostSource::Entry(addr, name) => hosts.insert(*addr, name.clone()),
+            HostSource::Synth6(name, prefix) => hosts.insert_synth6(name.clone(), *prefix),
+            HostSource::File(path) => read_into(path, hosts)?,
+        }
+    }
+    Ok(mappings)
+}
+
+/// 展开 include 路径，文件名中的 `*`、`?` 按通配符匹配同目录下的文件，结果按路径排序
+fn expand_include(path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let pattern = path.file_name().and_then(|it| it.to_str()).unwrap_or_default();
+    if !pattern.contains(['*', '?']) {
+        if !path.is_file() {
+            anyhow::bail!("Include file does not exist, path: '{:?}'", path);
+        }
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut paths = fs::read_dir(dir)
+        .with_context(|| format!("Unable to read include directory '{:?}'", dir))?
+        .filter_map(|entry| entry.ok().map(|it| it.path()))
+        .filter(|it| {
+            it.is_file()
+                && it
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| wildcard_match(pattern.as_bytes(), name.as_bytes()))
+        })
+        .collect::<Vec<_>>();
+    paths.sort();
+    Ok(paths)
+}
+
+fn wildcard_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            wildcard_match(&pattern[1..], name)
+                || (!name.is_empty() && wildcard_match(pattern, &name[1..]))
+        }
+        (Some(b'?'), Some(_)) => wildcard_match(&pattern[1..], &name[1..]),
+        (Some(a), Some(b)) if a == b => wildcard_match(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
@@ -1,14 +1,175 @@
-use crate::config::{parse_key_value_pair, read_hosts, Inner, DEFAULT_GROUP};
+use crate::config::blocklist::BlockAction;
+use crate::config::group::{parse_ip_range, IpRange};
+use crate::config::mmdb::{Database, Mmdb};
+use crate::config::hosts::{read_into, HostSource};
+use crate::config::{parse_key_value_pair, Inner, DEFAULT_GROUP};
+use crate::resolves::dot::read_pem_certs;
+use crate::resolves::{find_cipher_suite, TlsVersion};
 use anyhow::Context;
-use std::path::PathBuf;
+use hickory_proto::op::ResponseCode;
+use hickory_proto::rr::{Name, RecordType};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::rustls::SupportedCipherSuite;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Metadata {
     pub addn_host: Option<PathBuf>,
     pub cache_size: usize,
+    pub prefetch_threshold: Option<f64>,
+    pub cache_exclude_types: HashSet<RecordType>,
     pub bind: String,
-    pub mmdb: Option<maxminddb::Reader<Vec<u8>>>,
+    pub unix_bind: Option<PathBuf>,
+    pub udp_workers: usize,
+    pub tcp_backlog: u32,
+    pub ipv6_v6only: Option<bool>,
+    pub socket_rcvbuf: Option<usize>,
+    pub socket_sndbuf: Option<usize>,
+    pub mmdb: Mmdb,
+    pub group_mmdb: HashMap<String, Mmdb>,
     pub access_log: bool,
+    pub banner: bool,
+    pub dump_wire: bool,
+    pub upstream_strategy: UpstreamStrategy,
+    pub unmatched_policy: UnmatchedPolicy,
+    pub rd_policy: RdPolicy,
+    pub root_query_policy: RootQueryPolicy,
+    pub force_ttl: Option<u32>,
+    pub access_log_groups: Option<HashSet<String>>,
+    pub local_ptr_ranges: Vec<IpRange>,
+    pub allow_query: Option<Vec<IpRange>>,
+    pub rotate_answers: bool,
+    pub refuse_any: bool,
+    pub max_name_labels: Option<usize>,
+    pub max_name_length: Option<usize>,
+    pub max_answers: Option<usize>,
+    pub local_only_names: bool,
+    pub udp_retries: usize,
+    pub udp_retry_timeout: Duration,
+    pub tcp_fallback: bool,
+    pub retry_on_servfail: bool,
+    pub edns_cookies: bool,
+    pub reencode_requests: bool,
+    pub block_response: BlockAction,
+    pub blocklist_update_interval: Option<Duration>,
+    pub connect_timeout: Duration,
+    pub search_domain: Option<Name>,
+    pub chaos_version: Option<String>,
+    pub self_hostname: Option<(Name, Vec<IpAddr>)>,
+    pub status_name: Option<Name>,
+    pub tcp_max_message_size: usize,
+    pub tcp_read_timeout: Duration,
+    pub proxy_protocol: bool,
+    pub proxy_protocol_from: Vec<IpRange>,
+    pub edns_tcp_keepalive: Option<Duration>,
+    pub local_zones: Vec<Name>,
+    pub reverse_servers: Vec<String>,
+    pub query_timeout: Duration,
+    pub slow_query_threshold: Option<Duration>,
+    pub response_delay: Option<(Duration, Duration)>,
+    pub constant_time_response: Option<Duration>,
+    pub negative_cache_min_ttl: u32,
+    pub negative_cache_max_ttl: u32,
+    pub hosts_ttl: u32,
+    pub group_hosts_ttl: HashMap<String, u32>,
+    pub ipv6_denied_ttl: Option<u32>,
+    pub geo_lookup_failure: GeoLookupFailure,
+    pub local_negative_ttl: u32,
+    pub resolution_concurrency: usize,
+    pub failure_response: Option<ResponseCode>,
+    pub stats_interval: Option<Duration>,
+    pub tcp_fastopen: bool,
+    pub dot_max_inflight: Option<usize>,
+    pub dot_idle_timeout: Option<Duration>,
+    pub tls_keylog: bool,
+    pub tls_min_version: TlsVersion,
+    pub tls_cipher_suites: Vec<SupportedCipherSuite>,
+    pub ca_certs: Vec<CertificateDer<'static>>,
+}
+
+/// 分组内首个查询的上游的选择方式
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamStrategy {
+    /// 始终从分组的第一个上游开始
+    #[default]
+    Sequential,
+    /// 按客户端 IP 哈希选择首个上游
+    Sticky,
+}
+
+/// 未匹配任何分组的客户端的处理方式
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum UnmatchedPolicy {
+    /// 使用默认分组的配置应答
+    #[default]
+    Default,
+    /// 直接应答 REFUSED
+    Refuse,
+}
+
+/// 未设置 RD 位（请求迭代查询）时的处理方式，hosts 及缓存中的应答不受影响
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RdPolicy {
+    /// 与 RD=1 的查询一样转发给上游
+    #[default]
+    Forward,
+    /// 不转发，应答 REFUSED
+    Refuse,
+}
+
+/// ipv6_resolution 中 mmdb 查询失败（数据库未加载、出错或地址不在数据库中）时的处理方式
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum GeoLookupFailure {
+    /// 不保留 AAAA 记录
+    #[default]
+    Deny,
+    /// 保留 AAAA 记录
+    Allow,
+}
+
+/// 根域及顶级域（单标签域名）查询的处理方式，hosts 及缓存中的应答不受影响
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RootQueryPolicy {
+    /// 转发给上游
+    #[default]
+    Forward,
+    /// 不转发，应答 REFUSED
+    Refuse,
+    /// 不转发，应答空的 NOERROR
+    NoData,
+}
+
+impl Metadata {
+    /// 判断该分组的查询是否需要输出访问日志
+    pub fn is_access_log_enabled(&self, group: &str) -> bool {
+        match &self.access_log_groups {
+            Some(groups) => groups.contains(group),
+            None => true,
+        }
+    }
+    /// 判断该地址的反向查询是否只在本地应答
+    pub fn is_local_ptr(&self, addr: &IpAddr) -> bool {
+        self.local_ptr_ranges.iter().any(|it| it.contains(addr))
+    }
+    /// 未配置 allow-query 时允许所有客户端
+    pub fn is_query_allowed(&self, addr: &IpAddr) -> bool {
+        match &self.allow_query {
+            Some(ranges) => ranges.iter().any(|it| it.contains(addr)),
+            None => true,
+        }
+    }
+    /// 仅接受来自 proxy-protocol-from 中代理的 PROXY 协议头
+    pub fn is_trusted_proxy(&self, addr: &IpAddr) -> bool {
+        self.proxy_protocol_from.iter().any(|it| it.contains(addr))
+    }
+    /// 返回该域名所属的本地区域
+    pub fn local_zone(&self, name: &Name) -> Option<&Name> {
+        self.local_zones.iter().find(|zone| zone.zone_of(name))
+    }
 }
 
 impl Default for Metadata {
@@ -16,9 +177,75 @@ impl Default for Metadata {
         Self{
             addn_host: None,
             cache_size: 0,
+            prefetch_threshold: None,
+            cache_exclude_types: HashSet::new(),
             bind: String::new(),
-            mmdb: None,
+            unix_bind: None,
+            udp_workers: 1,
+            tcp_backlog: 1024,
+            ipv6_v6only: None,
+            socket_rcvbuf: None,
+            socket_sndbuf: None,
+            mmdb: Mmdb::default(),
+            group_mmdb: HashMap::new(),
             access_log: true,
+            banner: true,
+            dump_wire: false,
+            upstream_strategy: UpstreamStrategy::default(),
+            unmatched_policy: UnmatchedPolicy::default(),
+            rd_policy: RdPolicy::default(),
+            root_query_policy: RootQueryPolicy::default(),
+            force_ttl: None,
+            access_log_groups: None,
+            local_ptr_ranges: Vec::new(),
+            allow_query: None,
+            rotate_answers: false,
+            refuse_any: false,
+            max_name_labels: None,
+            max_name_length: None,
+            max_answers: None,
+            local_only_names: false,
+            udp_retries: 2,
+            udp_retry_timeout: Duration::from_millis(2000),
+            tcp_fallback: false,
+            retry_on_servfail: false,
+            edns_cookies: false,
+            reencode_requests: false,
+            block_response: BlockAction::default(),
+            blocklist_update_interval: Some(Duration::from_secs(86400)),
+            connect_timeout: Duration::from_millis(5000),
+            search_domain: None,
+            chaos_version: None,
+            self_hostname: None,
+            status_name: None,
+            tcp_max_message_size: u16::MAX as usize,
+            tcp_read_timeout: Duration::from_millis(5000),
+            proxy_protocol: false,
+            proxy_protocol_from: Vec::new(),
+            edns_tcp_keepalive: None,
+            local_zones: Vec::new(),
+            reverse_servers: Vec::new(),
+            query_timeout: Duration::from_secs(60),
+            slow_query_threshold: None,
+            response_delay: None,
+            constant_time_response: None,
+            negative_cache_min_ttl: 0,
+            negative_cache_max_ttl: 10800,
+            hosts_ttl: 1,
+            group_hosts_ttl: HashMap::new(),
+            ipv6_denied_ttl: None,
+            geo_lookup_failure: GeoLookupFailure::default(),
+            local_negative_ttl: 60,
+            resolution_concurrency: 8,
+            failure_response: Some(ResponseCode::ServFail),
+            stats_interval: None,
+            tcp_fastopen: false,
+            dot_max_inflight: None,
+            dot_idle_timeout: None,
+            tls_keylog: false,
+            tls_min_version: TlsVersion::default(),
+            tls_cipher_suites: Vec::new(),
+            ca_certs: Vec::new(),
         }
     }
 }
@@ -31,18 +258,10 @@ pub fn parse(row: usize, line: &str, inner: &mut Inner) -> anyhow::Result<()> {
             if !path.is_file() {
                 anyhow::bail!("Add-on host file does not exist, path: '{:?}'", path);
             }
-            let hosts = read_hosts(&path)?;
-            for (addr, name) in hosts {
-                inner
-                    .hosts
-                    .entry(DEFAULT_GROUP.to_string())
-                    .or_default()
-                    .push((
-                        addr.parse()
-                            .with_context(|| format!("Invalid ip addr '{}'", addr))?,
-                        name,
-                    ))
-            }
+            read_into(&path, inner.hosts.entry(DEFAULT_GROUP.to_string()).or_default())?;
+            inner
+                .host_sources
+                .push((DEFAULT_GROUP.to_string(), HostSource::File(path.clone())));
             inner.metadata.addn_host = Some(path)
         }
         "cache-size" => {
@@ -51,27 +270,458 @@ pub fn parse(row: usize, line: &str, inner: &mut Inner) -> anyhow::Result<()> {
                 .with_context(|| format!("Invalid u32 value '{}'", value))?
                 as usize;
         }
+        "prefetch-threshold" => {
+            let threshold = value
+                .parse::<f64>()
+                .with_context(|| format!("Invalid f64 value '{}'", value))?;
+            if !(threshold > 0.0 && threshold < 1.0) {
+                anyhow::bail!("'prefetch-threshold' must be between 0 and 1 in line {}", row);
+            }
+            inner.metadata.prefetch_threshold = Some(threshold);
+        }
+        "cache-exclude-types" => {
+            inner.metadata.cache_exclude_types = value
+                .split([',', ' '])
+                .filter(|it| !it.is_empty())
+                .map(|it| {
+                    RecordType::from_str(&it.to_ascii_uppercase())
+                        .with_context(|| format!("Invalid record type '{}'", it))
+                })
+                .collect::<anyhow::Result<HashSet<_>>>()?;
+        }
         "bind" => {
             inner.metadata.bind = value;
         }
-        "mmdb" => {
-            let value = PathBuf::from(value);
-            if !value.is_file() {
-                anyhow::bail!("GeoIP file does not exist");
+        "unix-bind" => {
+            let path = value.strip_prefix("unix:").unwrap_or(&value);
+            inner.metadata.unix_bind = Some(PathBuf::from(path));
+        }
+        "udp-workers" => {
+            inner.metadata.udp_workers = value
+                .parse::<usize>()
+                .ok()
+                .filter(|it| *it > 0)
+                .with_context(|| format!("Invalid udp-workers '{}' in line {}", value, row))?;
+        }
+        "tcp-backlog" => {
+            inner.metadata.tcp_backlog = value
+                .parse::<u32>()
+                .ok()
+                .filter(|it| *it > 0)
+                .with_context(|| format!("Invalid tcp-backlog '{}' in line {}", value, row))?;
+        }
+        "ipv6-v6only" => {
+            inner.metadata.ipv6_v6only = Some(parse_bool(&value));
+        }
+        "socket-rcvbuf" => {
+            inner.metadata.socket_rcvbuf = Some(
+                value
+                    .parse::<usize>()
+                    .with_context(|| format!("Invalid usize value '{}'", value))?,
+            );
+        }
+        "socket-sndbuf" => {
+            inner.metadata.socket_sndbuf = Some(
+                value
+                    .parse::<usize>()
+                    .with_context(|| format!("Invalid usize value '{}'", value))?,
+            );
+        }
+        "mmdb" | "mmdb-country" => {
+            inner.metadata.mmdb.country = Some(Database::open(PathBuf::from(value))?);
+        }
+        "mmdb-asn" => {
+            inner.metadata.mmdb.asn = Some(Database::open(PathBuf::from(value))?);
+        }
+        "mmdb-city" => {
+            inner.metadata.mmdb.city = Some(Database::open(PathBuf::from(value))?);
+        }
+        // 分组单独使用的数据库：mmdb.{group}、mmdb-asn.{group}、mmdb-city.{group}
+        key if key.starts_with("mmdb") && key.contains('.') => {
+            let (kind, group) = key.split_once('.').unwrap();
+            if !inner.groups.contains_key(group) {
+                anyhow::bail!("Can't find group '{}' definition in line {}", group, row);
             }
-            let reader = maxminddb::Reader::open_readfile(&value)
-                .with_context(|| format!("Failed to parse mmdb file on '{:?}'", value))?;
-            inner.metadata.mmdb = Some(reader);
+            let mmdb = inner.metadata.group_mmdb.entry(group.to_string()).or_default();
+            let database = match kind {
+                "mmdb" | "mmdb-country" => &mut mmdb.country,
+                "mmdb-asn" => &mut mmdb.asn,
+                "mmdb-city" => &mut mmdb.city,
+                _ => anyhow::bail!("Unknown metadata item specified: '{}'", key),
+            };
+            *database = Some(Database::open(PathBuf::from(value))?);
         }
         "access_log" => {
-            let value = match value.as_str() {
-                "on" | "true" | "1" => true,
-                "off" | "false" | "0" => false,
-                _ => true,
+            inner.metadata.access_log = parse_bool(&value);
+        }
+        "banner" => {
+            inner.metadata.banner = parse_bool(&value);
+        }
+        "dump-wire" => {
+            inner.metadata.dump_wire = parse_bool(&value);
+        }
+        "upstream-strategy" => {
+            inner.metadata.upstream_strategy = match value.as_str() {
+                "sequential" => UpstreamStrategy::Sequential,
+                "sticky" => UpstreamStrategy::Sticky,
+                _ => anyhow::bail!("Unknown upstream strategy '{}' in line {}", value, row),
+            };
+        }
+        "unmatched-policy" => {
+            inner.metadata.unmatched_policy = match value.as_str() {
+                "default" => UnmatchedPolicy::Default,
+                "refuse" => UnmatchedPolicy::Refuse,
+                _ => anyhow::bail!("Unknown unmatched policy '{}' in line {}", value, row),
+            };
+        }
+        "rd-policy" => {
+            inner.metadata.rd_policy = match value.as_str() {
+                "forward" => RdPolicy::Forward,
+                "refuse" => RdPolicy::Refuse,
+                _ => anyhow::bail!("Unknown rd policy '{}' in line {}", value, row),
+            };
+        }
+        "root-query-policy" => {
+            inner.metadata.root_query_policy = match value.as_str() {
+                "forward" => RootQueryPolicy::Forward,
+                "refuse" => RootQueryPolicy::Refuse,
+                "nodata" => RootQueryPolicy::NoData,
+                _ => anyhow::bail!("Unknown root query policy '{}' in line {}", value, row),
+            };
+        }
+        "force-ttl" => {
+            inner.metadata.force_ttl = Some(
+                value
+                    .parse::<u32>()
+                    .with_context(|| format!("Invalid u32 value '{}'", value))?,
+            );
+        }
+        "access-log-groups" => {
+            inner.metadata.access_log_groups = Some(
+                value
+                    .split(',')
+                    .map(|it| it.trim().to_string())
+                    .filter(|it| !it.is_empty())
+                    .collect(),
+            );
+        }
+        "local-ptr-ranges" => {
+            inner.metadata.local_ptr_ranges = parse_ip_range(&value)
+                .with_context(|| format!("Invalid local ptr ranges '{}'", value))?;
+        }
+        "allow-query" => {
+            inner.metadata.allow_query = Some(
+                parse_ip_range(&value)
+                    .with_context(|| format!("Invalid allow-query ranges '{}'", value))?,
+            );
+        }
+        "rotate-answers" => {
+            inner.metadata.rotate_answers = parse_bool(&value);
+        }
+        "refuse-any" => {
+            inner.metadata.refuse_any = parse_bool(&value);
+        }
+        "max-name-labels" => {
+            inner.metadata.max_name_labels = Some(
+                value
+                    .parse::<usize>()
+                    .with_context(|| format!("Invalid usize value '{}'", value))?,
+            );
+        }
+        "max-name-length" => {
+            inner.metadata.max_name_length = Some(
+                value
+                    .parse::<usize>()
+                    .with_context(|| format!("Invalid usize value '{}'", value))?,
+            );
+        }
+        "max-answers" => {
+            inner.metadata.max_answers = Some(
+                value
+                    .parse::<usize>()
+                    .with_context(|| format!("Invalid usize value '{}'", value))?,
+            );
+        }
+        "local-only-names" => {
+            inner.metadata.local_only_names = parse_bool(&value);
+        }
+        "udp-retries" => {
+            inner.metadata.udp_retries = value
+                .parse::<u32>()
+                .with_context(|| format!("Invalid u32 value '{}'", value))?
+                as usize;
+        }
+        "udp-retry-timeout" => {
+            inner.metadata.udp_retry_timeout = Duration::from_millis(
+                value
+                    .parse::<u64>()
+                    .with_context(|| format!("Invalid u64 value '{}'", value))?,
+            );
+        }
+        "tcp-fallback" => {
+            inner.metadata.tcp_fallback = parse_bool(&value);
+        }
+        "retry-on-servfail" => {
+            inner.metadata.retry_on_servfail = parse_bool(&value);
+        }
+        "edns-cookies" => {
+            inner.metadata.edns_cookies = parse_bool(&value);
+        }
+        "reencode-requests" => {
+            inner.metadata.reencode_requests = parse_bool(&value);
+        }
+        "block-response" => {
+            inner.metadata.block_response = BlockAction::from_str(&value)?;
+        }
+        // 为 0 时仅在启动时下载
+        "blocklist-update-interval" => {
+            let secs = value
+                .parse::<u64>()
+                .with_context(|| format!("Invalid u64 value '{}'", value))?;
+            inner.metadata.blocklist_update_interval = (secs > 0).then(|| Duration::from_secs(secs));
+        }
+        "connect-timeout" => {
+            inner.metadata.connect_timeout = Duration::from_millis(
+                value
+                    .parse::<u64>()
+                    .with_context(|| format!("Invalid u64 value '{}'", value))?,
+            );
+        }
+        "search-domain" => {
+            let mut domain = Name::from_ascii(&value)
+                .with_context(|| format!("Invalid search domain '{}'", value))?;
+            domain.set_fqdn(true);
+            inner.metadata.search_domain = Some(domain);
+        }
+        "self-hostname" => {
+            let mut parts = value
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|it| !it.is_empty());
+            let mut name = parts
+                .next()
+                .with_context(|| format!("Missing self-hostname in line {}", row))
+                .and_then(|it| Ok(Name::from_ascii(it)?))?;
+            name.set_fqdn(true);
+            let addrs = parts
+                .map(|it| {
+                    it.parse::<IpAddr>()
+                        .with_context(|| format!("Invalid ip addr '{}' in line {}", it, row))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            inner.metadata.self_hostname = Some((name, addrs));
+        }
+        "status-name" => {
+            let mut name = Name::from_ascii(&value)
+                .with_context(|| format!("Invalid status name '{}' in line {}", value, row))?;
+            name.set_fqdn(true);
+            inner.metadata.status_name = Some(name);
+        }
+        "chaos-version" => {
+            inner.metadata.chaos_version = Some(value);
+        }
+        "tcp-max-message-size" => {
+            let size = value
+                .parse::<u16>()
+                .with_context(|| format!("Invalid u16 value '{}'", value))?;
+            inner.metadata.tcp_max_message_size = size as usize;
+        }
+        "tcp-read-timeout" => {
+            inner.metadata.tcp_read_timeout = Duration::from_millis(
+                value
+                    .parse::<u64>()
+                    .with_context(|| format!("Invalid u64 value '{}'", value))?,
+            );
+        }
+        "proxy-protocol" => {
+            inner.metadata.proxy_protocol = parse_bool(&value);
+        }
+        "proxy-protocol-from" => {
+            inner.metadata.proxy_protocol_from = parse_ip_range(&value)
+                .with_context(|| format!("Invalid proxy-protocol-from ranges '{}'", value))?;
+        }
+        "edns-tcp-keepalive" => {
+            let timeout = value
+                .parse::<u64>()
+                .with_context(|| format!("Invalid u64 value '{}'", value))?;
+            // RFC 7828: 超时时间以 100 毫秒为单位，使用两字节表示
+            if timeout / 100 > u16::MAX as u64 {
+                anyhow::bail!("'edns-tcp-keepalive' must not exceed {}ms in line {}", u16::MAX as u64 * 100, row);
+            }
+            inner.metadata.edns_tcp_keepalive = Some(Duration::from_millis(timeout));
+        }
+        "local-zones" => {
+            inner.metadata.local_zones = value
+                .split_whitespace()
+                .map(|it| {
+                    let mut zone = Name::from_ascii(it)
+                        .with_context(|| format!("Invalid local zone '{}'", it))?;
+                    zone.set_fqdn(true);
+                    Ok(zone)
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+        }
+        "reverse-server" => {
+            inner.metadata.reverse_servers = value
+                .split(',')
+                .map(|it| it.trim().to_string())
+                .filter(|it| !it.is_empty())
+                .collect();
+        }
+        "query-timeout" => {
+            inner.metadata.query_timeout = Duration::from_millis(
+                value
+                    .parse::<u64>()
+                    .with_context(|| format!("Invalid u64 value '{}'", value))?,
+            );
+        }
+        "slow-query-threshold-ms" => {
+            inner.metadata.slow_query_threshold = Some(Duration::from_millis(
+                value
+                    .parse::<u64>()
+                    .with_context(|| format!("Invalid u64 value '{}'", value))?,
+            ));
+        }
+        "constant-time-response" => {
+            inner.metadata.constant_time_response = Some(Duration::from_millis(
+                value
+                    .parse::<u64>()
+                    .with_context(|| format!("Invalid u64 value '{}'", value))?,
+            ));
+        }
+        // format: {ms} 或 {min}-{max}，后者在范围内随机延迟
+        "response-delay-ms" => {
+            let parse = |it: &str| {
+                it.trim()
+                    .parse::<u64>()
+                    .map(Duration::from_millis)
+                    .with_context(|| format!("Invalid response-delay-ms '{}' in line {}", value, row))
+            };
+            let (min, max) = match value.split_once('-') {
+                Some((min, max)) => (parse(min)?, parse(max)?),
+                None => (parse(&value)?, parse(&value)?),
+            };
+            if min > max {
+                anyhow::bail!("Invalid response-delay-ms '{}' in line {}", value, row);
+            }
+            inner.metadata.response_delay = Some((min, max));
+        }
+        "negative-cache-min-ttl" => {
+            inner.metadata.negative_cache_min_ttl = value
+                .parse::<u32>()
+                .with_context(|| format!("Invalid u32 value '{}'", value))?;
+        }
+        "negative-cache-max-ttl" => {
+            inner.metadata.negative_cache_max_ttl = value
+                .parse::<u32>()
+                .with_context(|| format!("Invalid u32 value '{}'", value))?;
+        }
+        "hosts-ttl" => {
+            inner.metadata.hosts_ttl = value
+                .parse::<u32>()
+                .with_context(|| format!("Invalid u32 value '{}'", value))?;
+        }
+        // 分组单独使用的 TTL：hosts-ttl.{group}
+        key if key.starts_with("hosts-ttl.") => {
+            let group = key.trim_start_matches("hosts-ttl.");
+            if !inner.groups.contains_key(group) {
+                anyhow::bail!("Can't find group '{}' definition in line {}", group, row);
+            }
+            let ttl = value
+                .parse::<u32>()
+                .with_context(|| format!("Invalid u32 value '{}'", value))?;
+            inner.metadata.group_hosts_ttl.insert(group.to_string(), ttl);
+        }
+        "geo-lookup-failure" => {
+            inner.metadata.geo_lookup_failure = match value.as_str() {
+                "allow" => GeoLookupFailure::Allow,
+                "deny" => GeoLookupFailure::Deny,
+                _ => anyhow::bail!("Unknown geo lookup failure policy '{}' in line {}", value, row),
+            };
+        }
+        "ipv6-denied-ttl" => {
+            inner.metadata.ipv6_denied_ttl = Some(
+                value
+                    .parse::<u32>()
+                    .with_context(|| format!("Invalid u32 value '{}'", value))?,
+            );
+        }
+        "resolution-concurrency" => {
+            inner.metadata.resolution_concurrency = value
+                .parse::<usize>()
+                .ok()
+                .filter(|it| *it > 0)
+                .with_context(|| {
+                    format!("Invalid resolution-concurrency '{}' in line {}", value, row)
+                })?;
+        }
+        "local-negative-ttl" => {
+            inner.metadata.local_negative_ttl = value
+                .parse::<u32>()
+                .with_context(|| format!("Invalid u32 value '{}'", value))?;
+        }
+        "failure-response" => {
+            inner.metadata.failure_response = match value.as_str() {
+                "servfail" => Some(ResponseCode::ServFail),
+                "refused" => Some(ResponseCode::Refused),
+                "drop" => None,
+                _ => anyhow::bail!("Unknown failure response '{}'", value),
             };
-            inner.metadata.access_log = value;
+        }
+        "stats-interval" => {
+            let secs = value
+                .parse::<u64>()
+                .with_context(|| format!("Invalid u64 value '{}'", value))?;
+            inner.metadata.stats_interval = (secs > 0).then(|| Duration::from_secs(secs));
+        }
+        "tcp-fastopen" => {
+            inner.metadata.tcp_fastopen = parse_bool(&value);
+        }
+        "dot-idle-timeout" => {
+            let ms = value
+                .parse::<u64>()
+                .with_context(|| format!("Invalid u64 value '{}'", value))?;
+            inner.metadata.dot_idle_timeout = (ms > 0).then(|| Duration::from_millis(ms));
+        }
+        "dot-max-inflight" => {
+            // 复用连接的查询以 DNS id 区分，同一连接最多 65536 个
+            inner.metadata.dot_max_inflight = Some(
+                value
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|it| (1..=u16::MAX as usize + 1).contains(it))
+                    .with_context(|| {
+                        format!("Invalid dot-max-inflight '{}' in line {}", value, row)
+                    })?,
+            );
+        }
+        "tls-keylog" => {
+            inner.metadata.tls_keylog = parse_bool(&value);
+        }
+        "tls-min-version" => {
+            inner.metadata.tls_min_version = TlsVersion::from_str(&value)
+                .with_context(|| format!("Invalid tls-min-version in line {}", row))?;
+        }
+        "tls-cipher-suites" => {
+            inner.metadata.tls_cipher_suites = value
+                .split(',')
+                .map(|it| find_cipher_suite(it.trim()))
+                .collect::<anyhow::Result<Vec<_>>>()
+                .with_context(|| format!("Invalid tls-cipher-suites in line {}", row))?;
+        }
+        "ca-cert" => {
+            let certs = read_pem_certs(Path::new(&value))
+                .with_context(|| format!("Invalid ca-cert in line {}", row))?;
+            inner.metadata.ca_certs.extend(certs);
         }
         _ => anyhow::bail!("Unknown metadata item specified: '{}'", key),
     }
     Ok(())
 }
+
+fn parse_bool(value: &str) -> bool {
+    match value {
+        "on" | "true" | "1" => true,
+        "off" | "false" | "0" => false,
+        _ => true,
+    }
+}
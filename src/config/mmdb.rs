@@ -0,0 +1,245 @@
+use anyhow::Context;
+use maxminddb::Reader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// 按数据库类型加载的 GeoIP 数据库
+#[derive(Debug, Default, Clone)]
+pub struct Mmdb {
+    pub country: Option<Database>,
+    pub asn: Option<Database>,
+    pub city: Option<Database>,
+}
+
+impl Mmdb {
+    pub fn databases(&self) -> impl Iterator<Item = &Database> {
+        [&self.country, &self.asn, &self.city].into_iter().flatten()
+    }
+    /// 未单独配置的数据库类型使用 fallback 中的数据库
+    pub fn fill_from(&mut self, fallback: &Mmdb) {
+        for (database, fallback) in [
+            (&mut self.country, &fallback.country),
+            (&mut self.asn, &fallback.asn),
+            (&mut self.city, &fallback.city),
+        ] {
+            if database.is_none() {
+                database.clone_from(fallback);
+            }
+        }
+    }
+}
+
+/// 可在运行时替换的 mmdb 读取器，查询持有旧读取器的引用直到结束；
+/// 克隆的实例共享同一读取器，重新读取后同时生效
+#[derive(Clone)]
+pub struct Database {
+    path: PathBuf,
+    reader: Arc<RwLock<Arc<Reader<Vec<u8>>>>>,
+}
+
+impl Database {
+    pub fn open(path: PathBuf) -> anyhow::Result<Self> {
+        Ok(Self {
+            reader: Arc::new(RwLock::new(Arc::new(open(&path)?))),
+            path,
+        })
+    }
+    pub fn reader(&self) -> Arc<Reader<Vec<u8>>> {
+        match self.reader.read() {
+            Ok(reader) => reader.clone(),
+            Err(err) => err.into_inner().clone(),
+        }
+    }
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let reader = Arc::new(open(&self.path)?);
+        match self.reader.write() {
+            Ok(mut current) => *current = reader,
+            Err(err) => *err.into_inner() = reader,
+        }
+        Ok(())
+    }
+    /// 是否与另一实例共享同一读取器
+    pub fn shares(&self, other: &Database) -> bool {
+        Arc::ptr_eq(&self.reader, &other.reader)
+    }
+}
+
+impl std::fmt::Debug for Database {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Database")
+            .field("path", &self.path)
+            .field("database_type", &self.reader().metadata.database_type)
+            .finish()
+    }
+}
+
+impl From<Reader<Vec<u8>>> for Database {
+    fn from(reader: Reader<Vec<u8>>) -> Self {
+        Self {
+            path: PathBuf::new(),
+            reader: Arc::new(RwLock::new(Arc::new(reader))),
+        }
+    }
+}
+
+pub fn open(path: &Path) -> anyhow::Result<Reader<Vec<u8>>> {
+    if !path.is_file() {
+        anyhow::bail!("GeoIP file does not exist, path: '{:?}'", path);
+    }
+    Reader::open_readfile(path).with_context(|| format!("Failed to parse mmdb file on '{:?}'", path))
+}
+
+/// 用于测试的 mmdb 生成器
+#[cfg(test)]
+pub(crate) mod fixture {
+    use maxminddb::Reader;
+    use std::net::IpAddr;
+
+    pub enum Value {
+        Str(&'static str),
+        U32(u32),
+        Map(Vec<(&'static str, Value)>),
+        Array(Vec<Value>),
+    }
+
+    enum Record {
+        Empty,
+        Node(usize),
+        Data(usize),
+    }
+
+    #[derive(Default)]
+    pub struct Builder {
+        entries: Vec<(Vec<bool>, Value)>,
+    }
+
+    impl Builder {
+        pub fn insert(mut self, network: &str, value: Value) -> Self {
+            let (addr, prefix) = network.split_once('/').unwrap();
+            let addr = addr.parse::<IpAddr>().unwrap();
+            let prefix = prefix.parse::<usize>().unwrap();
+            // IPv4 地址位于 IPv6 树的 ::/96 之下
+            let (bytes, prefix) = match addr {
+                IpAddr::V4(addr) => {
+                    let mut bytes = [0u8; 16];
+                    bytes[12..].copy_from_slice(&addr.octets());
+                    (bytes, prefix + 96)
+                }
+                IpAddr::V6(addr) => (addr.octets(), prefix),
+            };
+            let bits = (0..prefix)
+                .map(|i| bytes[i / 8] >> (7 - i % 8) & 1 == 1)
+                .collect();
+            self.entries.push((bits, value));
+            self
+        }
+        pub fn build(self, database_type: &'static str) -> Vec<u8> {
+            let mut nodes: Vec<[Record; 2]> = vec![[Record::Empty, Record::Empty]];
+            let mut data = Vec::new();
+            for (bits, value) in self.entries {
+                let offset = data.len();
+                encode(&value, &mut data);
+                let mut node = 0;
+                for (i, bit) in bits.iter().enumerate() {
+                    let index = *bit as usize;
+                    if i == bits.len() - 1 {
+                        nodes[node][index] = Record::Data(offset);
+                        break;
+                    }
+                    node = match nodes[node][index] {
+                        Record::Node(next) => next,
+                        _ => {
+                            nodes.push([Record::Empty, Record::Empty]);
+                            let next = nodes.len() - 1;
+                            nodes[node][index] = Record::Node(next);
+                            next
+                        }
+                    };
+                }
+            }
+            let node_count = nodes.len();
+            let mut buf = Vec::new();
+            for node in &nodes {
+                for record in node {
+                    let value = match record {
+                        Record::Empty => node_count,
+                        Record::Node(next) => *next,
+                        Record::Data(offset) => node_count + 16 + offset,
+                    };
+                    buf.extend_from_slice(&(value as u32).to_be_bytes());
+                }
+            }
+            buf.extend_from_slice(&[0; 16]);
+            buf.extend_from_slice(&data);
+            buf.extend_from_slice(b"\xAB\xCD\xEFMaxMind.com");
+            let metadata = Value::Map(vec![
+                ("binary_format_major_version", Value::U32(2)),
+                ("binary_format_minor_version", Value::U32(0)),
+                ("build_epoch", Value::U32(0)),
+                ("database_type", Value::Str(database_type)),
+                ("description", Value::Map(vec![])),
+                ("ip_version", Value::U32(6)),
+                ("languages", Value::Array(vec![Value::Str("en")])),
+                ("node_count", Value::U32(node_count as u32)),
+                ("record_size", Value::U32(32)),
+            ]);
+            encode(&metadata, &mut buf);
+            buf
+        }
+        pub fn reader(self, database_type: &'static str) -> Reader<Vec<u8>> {
+            Reader::from_source(self.build(database_type)).unwrap()
+        }
+    }
+
+    fn encode(value: &Value, buf: &mut Vec<u8>) {
+        match value {
+            Value::Str(str) => {
+                buf.push((2 << 5) | str.len() as u8);
+                buf.extend_from_slice(str.as_bytes());
+            }
+            Value::U32(num) => {
+                buf.push((6 << 5) | 4);
+                buf.extend_from_slice(&num.to_be_bytes());
+            }
+            Value::Map(entries) => {
+                buf.push((7 << 5) | entries.len() as u8);
+                for (key, value) in entries {
+                    encode(&Value::Str(key), buf);
+                    encode(value, buf);
+                }
+            }
+            Value::Array(items) => {
+                // 扩展类型：array = 11 - 7
+                buf.push(items.len() as u8);
+                buf.push(4);
+                for item in items {
+                    encode(item, buf);
+                }
+            }
+        }
+    }
+
+    pub fn country(iso_code: &'static str) -> Value {
+        Value::Map(vec![(
+            "country",
+            Value::Map(vec![("iso_code", Value::Str(iso_code))]),
+        )])
+    }
+
+    pub fn asn(number: u32) -> Value {
+        Value::Map(vec![("autonomous_system_number", Value::U32(number))])
+    }
+
+    pub fn city(name: &'static str, region: &'static str) -> Value {
+        Value::Map(vec![
+            (
+                "city",
+                Value::Map(vec![("names", Value::Map(vec![("en", Value::Str(name))]))]),
+            ),
+            (
+                "subdivisions",
+                Value::Array(vec![Value::Map(vec![("iso_code", Value::Str(region))])]),
+            ),
+        ])
+    }
+}
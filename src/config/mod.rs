@@ -1,9 +1,18 @@
+mod blocklist;
 mod group;
 mod hosts;
 mod metadata;
+mod mmdb;
+mod ptr;
 mod resolution;
 mod server;
+mod statics;
+mod zone;
 
+pub use blocklist::{validate as validate_blocklist, BlockAction};
+pub use metadata::{RdPolicy, RootQueryPolicy, UnmatchedPolicy, UpstreamStrategy};
+pub use statics::StaticAnswer;
+pub use zone::Zone;
 use anyhow::Context;
 use hickory_proto::rr::domain::Name;
 use std::collections::{HashMap, HashSet};
@@ -13,18 +22,25 @@ use std::net::IpAddr;
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::atomic::{AtomicPtr, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
 
 static DEFAULT_GROUP: &str = "default";
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Inner {
     groups: group::Groups,
     servers: server::Servers,
     hosts: hosts::GroupHostMappings,
     pub metadata: metadata::Metadata,
     ipv6_resolution: resolution::GroupResolutionMappings,
+    blocklists: blocklist::Blocklists,
+    host_sources: hosts::HostSources,
+    ptr_records: ptr::PtrRecords,
+    zones: zone::Zones,
+    static_answers: statics::StaticAnswers,
+    inheritance: Vec<(String, String)>,
 }
 
 impl Inner {
@@ -52,9 +68,16 @@ impl Inner {
             hosts: HashMap::new(),
             metadata: metadata::Metadata::default(),
             ipv6_resolution: HashMap::new(),
+            blocklists: Vec::new(),
+            host_sources: Vec::new(),
+            ptr_records: HashMap::new(),
+            zones: Vec::new(),
+            static_answers: HashMap::new(),
+            inheritance: Vec::new(),
         };
         let lines = str.lines();
-        let mut section: Option<Section> = None;
+        let mut section: Option<(&str, Section)> = None;
+        let mut parents = group::GroupParents::new();
         let mut row = 0;
         for line in lines {
             let line = line.trim_end();
@@ -62,61 +85,135 @@ impl Inner {
             if line.starts_with('#') || line.is_empty() {
                 continue;
             }
-            if line.starts_with('[') && line.ends_with(']') {
-                let section_name = line.trim_matches(|c| c == '[' || c == ']');
-                section = Some(parse_section(section_name));
+            if let Some(section_name) = section_header(line) {
+                section = Some((section_name, parse_section(section_name)));
                 continue;
             }
-            if let Some(section) = &section {
-                match section {
+            if let Some((section_name, section)) = &section {
+                let result = match section {
                     Section::Unknown(section) => {
-                        anyhow::bail!("Unknown section '{}'", section);
+                        Err(anyhow::format_err!("Unknown section '{}'", section))
                     }
-                    Section::Group => group::parse(row, line, &mut config.groups)?,
-                    Section::Server => server::parse(row, line, &mut config)?,
-                    Section::Host(sub) => hosts::parse(sub, row, line, &mut config, watch_paths)?,
-                    Section::Metadata => metadata::parse(row, line, &mut config)?,
+                    Section::Group => group::parse(row, line, &mut config.groups, &mut parents),
+                    Section::Server => server::parse(row, line, &mut config),
+                    Section::Host(sub) => hosts::parse(sub, row, line, &mut config, watch_paths),
+                    Section::Metadata => metadata::parse(row, line, &mut config),
                     Section::IPv6Resolution => {
-                        resolution::ipv6_resolution_parse(row, line, &mut config)?
+                        resolution::ipv6_resolution_parse(row, line, &mut config)
                     }
-                }
+                    Section::Blocklist => blocklist::parse(row, line, &mut config, watch_paths),
+                    Section::Ptr => ptr::parse(row, line, &mut config),
+                    Section::Zone => zone::parse(row, line, &mut config, watch_paths),
+                    Section::Static => statics::parse(row, line, &mut config),
+                };
+                // 附带所在的节及完整行内容，便于定位大型配置中的错误
+                result.with_context(|| {
+                    format!("in [{}] at line {}: '{}'", section_name, row, line.trim())
+                })?;
             } else {
                 anyhow::bail!("Unexpected error, missing section {}", line)
             }
         }
+        config.inherit_groups(&parents)?;
+        let global_mmdb = config.metadata.mmdb.clone();
+        for mmdb in config.metadata.group_mmdb.values_mut() {
+            mmdb.fill_from(&global_mmdb);
+        }
         if !config.servers.contains_key(DEFAULT_GROUP) {
             anyhow::bail!(
                 "Must specify a default upstream server, missing '{}' field in server section",
                 DEFAULT_GROUP
             )
         }
+        if config.metadata.proxy_protocol && config.metadata.proxy_protocol_from.is_empty() {
+            anyhow::bail!("'proxy-protocol' requires trusted proxies in 'proxy-protocol-from'")
+        }
+        if config.metadata.negative_cache_min_ttl > config.metadata.negative_cache_max_ttl {
+            anyhow::bail!("'negative-cache-min-ttl' must not be greater than 'negative-cache-max-ttl'")
+        }
         Ok(config)
     }
+    /// 子分组继承父分组的上游、hosts、hosts-ttl 及 ipv6_resolution 配置：
+    /// 未配置上游或 hosts-ttl 时使用父分组的配置，hosts 与规则追加在自身条目之后
+    fn inherit_groups(&mut self, parents: &group::GroupParents) -> anyhow::Result<()> {
+        self.inheritance = inheritance_order(&self.groups, parents)?;
+        for (group, parent) in &self.inheritance {
+            if let Some(servers) = self.servers.get(parent).cloned() {
+                self.servers.entry(group.to_string()).or_insert(servers);
+            }
+            if let Some(mmdb) = self.metadata.group_mmdb.get(parent).cloned() {
+                let own = self.metadata.group_mmdb.entry(group.to_string()).or_default();
+                own.fill_from(&mmdb);
+            }
+            if let Some(ttl) = self.metadata.group_hosts_ttl.get(parent).copied() {
+                self.metadata
+                    .group_hosts_ttl
+                    .entry(group.to_string())
+                    .or_insert(ttl);
+            }
+            if let Some(rules) = self.ipv6_resolution.get(parent).cloned() {
+                self.ipv6_resolution
+                    .entry(group.to_string())
+                    .or_default()
+                    .extend(rules);
+            }
+        }
+        self.inherit_hosts();
+        Ok(())
+    }
+    fn inherit_hosts(&mut self) {
+        for (group, parent) in &self.inheritance {
+            if let Some(hosts) = self.hosts.get(parent).cloned() {
+                self.hosts.entry(group.to_string()).or_default().extend(&hosts);
+            }
+        }
+    }
+    /// 仅 hosts 或拦截列表文件变化时，复制当前配置并只重新读取受影响的部分；
+    /// 其他文件变化时返回 None，需要完整重载
+    fn reload_files(&self, changed: &[PathBuf]) -> anyhow::Result<Option<Inner>> {
+        let is_hosts = |path: &PathBuf| {
+            self.host_sources
+                .iter()
+                .any(|(_, source)| matches!(source, hosts::HostSource::File(it) if it == path))
+        };
+        let is_blocklist = |path: &PathBuf| self.blocklists.iter().any(|it| &it.path == path);
+        if changed.is_empty() || !changed.iter().all(|it| is_hosts(it) || is_blocklist(it)) {
+            return Ok(None);
+        }
+        let mut inner = self.clone();
+        if changed.iter().any(is_hosts) {
+            inner.hosts = hosts::rebuild(&inner.host_sources)?;
+            inner.inherit_hosts();
+        }
+        for blocklist in inner.blocklists.iter_mut() {
+            if changed.contains(&blocklist.path) {
+                *blocklist = Arc::new(blocklist.reload()?);
+            }
+        }
+        Ok(Some(inner))
+    }
+    /// 从 URL 下载的拦截列表：名称、地址及缓存文件
+    pub fn blocklist_sources(&self) -> Vec<(String, url::Url, PathBuf)> {
+        self.blocklists
+            .iter()
+            .filter_map(|it| Some((it.name.clone(), it.url.clone()?, it.path.clone())))
+            .collect()
+    }
     pub fn attribute_group(&self, addr: &IpAddr) -> String {
-        let group = self
-            .groups
+        self.find_group(addr).unwrap_or(DEFAULT_GROUP).to_string()
+    }
+    /// 地址所属的分组，未匹配任何分组时返回 None
+    pub fn find_group(&self, addr: &IpAddr) -> Option<&str> {
+        self.groups
             .iter()
-            .find(|(_, value)| {
-                value.iter().any(|it| match it {
-                    group::IpRange::Single(single) => match_ipaddr(single, addr),
-                    group::IpRange::Range(range) => {
-                        if range.is_empty() {
-                            return false;
-                        };
-                        match addr {
-                            IpAddr::V4(addr) => {
-                                range.contains(&IpAddr::from(addr.to_ipv6_mapped()))
-                            }
-                            _ => range.contains(addr),
-                        }
-                    }
-                })
-            })
+            .find(|(_, value)| value.iter().any(|it| it.contains(addr)))
             .map(|it| it.0.as_str())
-            .unwrap_or(DEFAULT_GROUP);
-        group.to_string()
     }
-    pub fn get_server(&self, group: impl AsRef<str>) -> &Vec<String> {
+    /// 公网地址的反向查询优先使用 reverse-server
+    pub fn get_server(&self, group: impl AsRef<str>, reverse: bool) -> &Vec<String> {
+        if reverse && !self.metadata.reverse_servers.is_empty() {
+            return &self.metadata.reverse_servers;
+        }
         let key = if self.servers.contains_key(group.as_ref()) {
             group.as_ref()
         } else {
@@ -124,58 +221,151 @@ impl Inner {
         };
         self.servers.get(key).as_ref().unwrap()
     }
+    /// 返回第一个包含该域名的 hosts 中的全部地址，顺序与文件中一致
     pub fn get_hosts(&self, group: impl AsRef<str>, domain: &str) -> anyhow::Result<Vec<IpAddr>> {
         let domain = Name::from_str(domain).with_context(||format!("Failed parse '{}' to Name", domain))?;
-        let default = self.hosts.get(DEFAULT_GROUP).into_iter().flatten();
-        let group = self.hosts.get(group.as_ref()).into_iter().flatten();
-        Ok(group
-            .chain(default)
-            .find_map(|it| if it.1 == domain { Some(it.0) } else { None })
-            // .filter_map(|it| if it.1 == domain { Some(it.0) } else { None })
-            // .collect::<HashSet<_>>()
-            .into_iter()
-            .collect::<Vec<_>>())
+        Ok(self
+            .layered_hosts(group.as_ref())
+            .find_map(|it| it.addrs(&domain))
+            .map(<[_]>::to_vec)
+            .unwrap_or_default())
     }
+    /// [ptr] 中的映射优先于 hosts 条目
     pub fn get_hostname(&self, group: impl AsRef<str>, addr: IpAddr) -> Option<String> {
-        let default = self.hosts.get(DEFAULT_GROUP).into_iter().flatten();
-        let group = self.hosts.get(group.as_ref()).into_iter().flatten();
-        group.chain(default).find_map(|it| {
-            if it.0 == addr {
-                Some(it.1.to_utf8())
-            } else {
-                None
-            }
-        })
+        self.ptr_records
+            .get(&addr)
+            .or_else(|| self.layered_hosts(group.as_ref()).find_map(|it| it.name(&addr)))
+            .map(|it| it.to_utf8().trim_end_matches('.').to_string())
     }
-    pub async fn is_allow_ipv6(&self, group: impl AsRef<str>, domain: &Name, addr: IpAddr) -> bool {
-        let default_rules = self
-            .ipv6_resolution
-            .get(DEFAULT_GROUP)
-            .into_iter()
-            .flatten();
-        let group_rules = self
-            .ipv6_resolution
-            .get(group.as_ref())
-            .into_iter()
-            .flatten();
-        let rules = group_rules.chain(default_rules);
-        for rule in rules {
-            if !rule.payload_match(domain) {
-                continue;
-            }
-            if !rule
-                .check_is_allow(resolution::CheckArgs {
-                    addr: &addr,
-                    mmdb: self.metadata.mmdb.as_ref(),
-                })
-                .await
-            {
-                return false;
-            }
-            break;
+    /// [static] 中为该域名固定的应答
+    pub fn get_static(&self, domain: &Name) -> Option<&Vec<StaticAnswer>> {
+        let mut domain = domain.clone();
+        domain.set_fqdn(true);
+        self.static_answers.get(&domain)
+    }
+    /// 域名的 AAAA 合成规则，分组的规则优先
+    pub fn get_synth6(&self, group: impl AsRef<str>, domain: &Name) -> Option<hosts::Synth6Prefix> {
+        self.layered_hosts(group.as_ref()).find_map(|it| it.synth6(domain))
+    }
+    /// 分组的 hosts 优先于默认分组
+    fn layered_hosts(&self, group: &str) -> impl Iterator<Item = &hosts::Hosts> {
+        let group = if group == DEFAULT_GROUP {
+            None
+        } else {
+            self.hosts.get(group)
+        };
+        group.into_iter().chain(self.hosts.get(DEFAULT_GROUP))
+    }
+    /// 包含该域名的静态区域，多个区域匹配时使用最具体的区域
+    pub fn find_zone(&self, domain: &Name) -> Option<&Zone> {
+        self.zones
+            .iter()
+            .filter(|it| it.contains(domain))
+            .max_by_key(|it| it.origin.num_labels())
+            .map(|it| it.as_ref())
+    }
+    /// 查询域名是否命中拦截列表，返回列表名称及应答方式
+    pub fn get_block_action(&self, domain: &Name) -> Option<(&str, BlockAction)> {
+        self.blocklists
+            .iter()
+            .find(|it| it.contains(domain))
+            .map(|it| {
+                (
+                    it.name.as_str(),
+                    it.action.unwrap_or(self.metadata.block_response),
+                )
+            })
+    }
+    /// 分组单独配置的本地应答 TTL，未配置时使用全局 hosts-ttl
+    pub fn hosts_ttl(&self, group: &str) -> u32 {
+        self.metadata
+            .group_hosts_ttl
+            .get(group)
+            .copied()
+            .unwrap_or(self.metadata.hosts_ttl)
+    }
+    /// 分组单独配置的数据库，未配置时使用全局数据库
+    pub fn get_mmdb(&self, group: &str) -> &mmdb::Mmdb {
+        self.metadata
+            .group_mmdb
+            .get(group)
+            .unwrap_or(&self.metadata.mmdb)
+    }
+    /// 第一条匹配域名的规则决定是否保留 AAAA 记录，不保留时返回该规则
+    pub async fn ipv6_denied_by(
+        &self,
+        group: impl AsRef<str>,
+        domain: &Name,
+        addr: IpAddr,
+    ) -> Option<&resolution::Resolution> {
+        let rule = layered(&self.ipv6_resolution, group.as_ref())
+            .find(|rule| rule.payload_match(domain))?;
+        let allowed = rule
+            .check_is_allow(resolution::CheckArgs {
+                addr: &addr,
+                mmdb: self.get_mmdb(group.as_ref()),
+                on_failure: self.metadata.geo_lookup_failure,
+            })
+            .await;
+        (!allowed).then_some(rule)
+    }
+}
+
+/// 按依赖顺序排列的 (分组, 父分组)，父分组总在子分组之前；默认分组本就是所有分组的回退，不会出现在结果中
+fn inheritance_order(
+    groups: &group::Groups,
+    parents: &group::GroupParents,
+) -> anyhow::Result<Vec<(String, String)>> {
+    fn resolve(
+        groups: &group::Groups,
+        parents: &group::GroupParents,
+        group: &str,
+        order: &mut Vec<(String, String)>,
+        resolved: &mut HashSet<String>,
+        visiting: &mut Vec<String>,
+    ) -> anyhow::Result<()> {
+        if resolved.contains(group) {
+            return Ok(());
+        }
+        let Some(parent) = parents.get(group) else {
+            resolved.insert(group.to_string());
+            return Ok(());
+        };
+        if visiting.iter().any(|it| it == group) {
+            anyhow::bail!("Circular group inheritance: {} -> {}", visiting.join(" -> "), group);
+        }
+        if parent != DEFAULT_GROUP && !groups.contains_key(parent) {
+            anyhow::bail!("Group '{}' inherits undefined group '{}'", group, parent);
+        }
+        visiting.push(group.to_string());
+        resolve(groups, parents, parent, order, resolved, visiting)?;
+        visiting.pop();
+        if parent != DEFAULT_GROUP {
+            order.push((group.to_string(), parent.to_string()));
         }
-        true
+        resolved.insert(group.to_string());
+        Ok(())
+    }
+    let mut order = Vec::new();
+    let mut resolved = HashSet::new();
+    for group in parents.keys() {
+        resolve(groups, parents, group, &mut order, &mut resolved, &mut Vec::new())?;
     }
+    Ok(order)
+}
+
+/// 分组规则优先于默认规则：先遍历分组自身的条目，再遍历默认分组的条目，
+/// hosts 与 ipv6_resolution 均取第一个匹配项
+fn layered<'a, T>(map: &'a HashMap<String, Vec<T>>, group: &str) -> impl Iterator<Item = &'a T> {
+    let group = if group == DEFAULT_GROUP {
+        None
+    } else {
+        map.get(group)
+    };
+    group
+        .into_iter()
+        .flatten()
+        .chain(map.get(DEFAULT_GROUP).into_iter().flatten())
 }
 
 enum Section<'input> {
@@ -184,10 +374,22 @@ enum Section<'input> {
     Host(&'input str),
     Metadata,
     IPv6Resolution,
+    Blocklist,
+    Ptr,
+    Zone,
+    Static,
     Unknown(&'input str),
 }
 
-fn parse_section(section: &str) -> Section {
+/// 仅 `[name]` 形式的行视为节标题，含引号、括号或注释的行交由键值解析处理
+fn section_header(line: &str) -> Option<&str> {
+    line.trim()
+        .strip_prefix('[')?
+        .strip_suffix(']')
+        .filter(|name| !name.contains(['[', ']', '"', '#']))
+}
+
+fn parse_section(section: &str) -> Section<'_> {
     let parts = section.split('.').collect::<Vec<_>>();
     match parts[0] {
         "group" => Section::Group,
@@ -195,6 +397,10 @@ fn parse_section(section: &str) -> Section {
         "hosts" => Section::Host(parts.get(1).copied().unwrap_or("default")),
         "metadata" => Section::Metadata,
         "ipv6_resolution" => Section::IPv6Resolution,
+        "blocklist" => Section::Blocklist,
+        "ptr" => Section::Ptr,
+        "zone" => Section::Zone,
+        "static" => Section::Static,
         _ => Section::Unknown(parts[0]),
     }
 }
@@ -259,56 +465,142 @@ fn read_hosts(path: &PathBuf) -> anyhow::Result<Vec<(String, Name)>> {
     }
     let hosts = fs::read_to_string(path)?;
     let mut entries = Vec::new();
-    for line in hosts.lines() {
-        let line = line.trim();
-        if line.starts_with('#') {
+    for (row, line) in hosts.lines().enumerate() {
+        // 去除行内注释，忽略空行及仅含注释的行
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
             continue;
         }
-        let (key, mut value, _) = parse_key_value_pair(line)
-            .map_err(|(err, col)| anyhow::format_err!("{} in line {}:{}", err, line, col))?;
-        // must be fqdn
-        if !value.ends_with('.') {
-            value.push('.')
+        let mut parts = line.split_whitespace();
+        let addr = parts.next().unwrap_or_default();
+        let mut names = parts.peekable();
+        if names.peek().is_none() {
+            anyhow::bail!("Missing host name in line {}:{}", row + 1, addr.len() + 1);
+        }
+        for name in names {
+            let mut name = Name::from_ascii(name)
+                .with_context(|| format!("Invalid host name '{}' in line {}", name, row + 1))?;
+            // must be fqdn
+            name.set_fqdn(true);
+            entries.push((addr.to_string(), name));
         }
-        entries.push((
-            key.parse()
-                .with_context(|| format!("Invalid ip addr '{}'", key))?,
-            Name::from_ascii(value)?,
-        ))
     }
     Ok(entries)
 }
 
-
 pub struct Config {
     ptr: AtomicPtr<Inner>,
-    path: PathBuf
+    path: PathBuf,
+    /// 配置及其引用的文件，记录加载时的修改时间与大小
+    watched: Mutex<HashMap<PathBuf, Option<FileStamp>>>,
+}
+
+type FileStamp = (SystemTime, u64);
+
+fn file_stamp(path: &PathBuf) -> Option<FileStamp> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+fn stamp_all(watch_paths: HashSet<PathBuf>) -> HashMap<PathBuf, Option<FileStamp>> {
+    watch_paths
+        .into_iter()
+        .map(|path| {
+            let stamp = file_stamp(&path);
+            (path, stamp)
+        })
+        .collect()
 }
 
 impl Config {
     pub fn new(path: PathBuf) -> anyhow::Result<Self> {
-        // todo: watch directory
-        let (inner, _watch_paths) =
+        let (inner, watch_paths) =
             Inner::load(&path).with_context(|| "Failed to load config file")?;
         let inner_ptr = Arc::into_raw(Arc::new(inner)) as *mut Inner;
         let ptr = AtomicPtr::new(inner_ptr);
         Ok(Self {
             ptr,
-            path
+            path,
+            watched: Mutex::new(stamp_all(watch_paths)),
         })
     }
     /// 重载配置
     #[allow(unused)]
     pub fn reload(&self) -> anyhow::Result<()> {
-        let (inner, _watch_paths) =
+        let mut watched = self.watched.lock().unwrap_or_else(|err| err.into_inner());
+        self.reload_all(&mut watched)
+    }
+    fn reload_all(&self, watched: &mut HashMap<PathBuf, Option<FileStamp>>) -> anyhow::Result<()> {
+        let (inner, watch_paths) =
             Inner::load(&self.path).with_context(|| "Failed to load config file")?;
+        self.swap(inner);
+        *watched = stamp_all(watch_paths);
+        Ok(())
+    }
+    /// 按修改时间及大小找出变化的文件：仅 hosts 或拦截列表文件变化时只重新读取这些文件，
+    /// 其余配置原样保留；否则完整重载。返回是否为部分重载
+    pub fn reload_changed(&self) -> anyhow::Result<bool> {
+        let mut watched = self.watched.lock().unwrap_or_else(|err| err.into_inner());
+        let changed = watched
+            .iter()
+            .filter(|(path, stamp)| file_stamp(path) != **stamp)
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+        match self.access().reload_files(&changed)? {
+            Some(inner) => {
+                self.swap(inner);
+                for path in changed {
+                    let stamp = file_stamp(&path);
+                    watched.insert(path, stamp);
+                }
+                Ok(true)
+            }
+            None => self.reload_all(&mut watched).map(|_| false),
+        }
+    }
+    /// 仅重新读取指定的 hosts 或拦截列表文件，用于下载的拦截列表写入缓存文件后替换
+    pub fn reload_paths(&self, paths: &[PathBuf]) -> anyhow::Result<()> {
+        let mut watched = self.watched.lock().unwrap_or_else(|err| err.into_inner());
+        let inner = self
+            .access()
+            .reload_files(paths)?
+            .with_context(|| format!("Not hosts or blocklist files: {:?}", paths))?;
+        self.swap(inner);
+        for path in paths {
+            watched.insert(path.clone(), file_stamp(path));
+        }
+        Ok(())
+    }
+    /// 输出合并 include 文件及默认值后的完整配置
+    pub fn dump(&self) -> String {
+        format!("{:#?}", self.access())
+    }
+    /// 仅重新读取 mmdb 文件，其余配置保持不变
+    pub fn reload_mmdb(&self) -> anyhow::Result<()> {
+        let inner = self.access();
+        let metadata = &inner.metadata;
+        let mut reloaded = Vec::new();
+        for database in metadata
+            .mmdb
+            .databases()
+            .chain(metadata.group_mmdb.values().flat_map(|it| it.databases()))
+        {
+            // 分组回退使用的全局数据库只需读取一次
+            if reloaded.iter().any(|it: &&mmdb::Database| it.shares(database)) {
+                continue;
+            }
+            database.reload().with_context(|| "Failed to reload mmdb")?;
+            reloaded.push(database);
+        }
+        Ok(())
+    }
+    fn swap(&self, inner: Inner) {
         let inner_ptr = Arc::into_raw(Arc::new(inner)) as *mut Inner;
         let old_ptr = self.ptr.swap(inner_ptr, Ordering::SeqCst);
         unsafe {
             // 转回 Arc 然后丢弃
             let _ = Arc::from_raw(old_ptr);
         };
-        Ok(())
     }
     pub fn access(&self) -> Arc<Inner> {
         let ptr = self.ptr.load(Ordering::SeqCst);
@@ -324,6 +616,22 @@ impl Config {
     }
 }
 
+#[cfg(test)]
+impl Config {
+    pub fn from_text(text: &str) -> anyhow::Result<Self> {
+        let inner = Inner::parse(text, &mut HashSet::new())?;
+        Ok(Self {
+            ptr: AtomicPtr::new(Arc::into_raw(Arc::new(inner)) as *mut Inner),
+            path: PathBuf::new(),
+            watched: Mutex::new(HashMap::new()),
+        })
+    }
+    pub fn reload_from_text(&self, text: &str) -> anyhow::Result<()> {
+        self.swap(Inner::parse(text, &mut HashSet::new())?);
+        Ok(())
+    }
+}
+
 impl Drop for Config {
     fn drop(&mut self) {
         unsafe {
@@ -344,4 +652,389 @@ mod tests {
         let config = Inner::load(&path.to_path_buf()).unwrap();
         println!("{:#?}", config);
     }
+
+    #[test]
+    fn quoted_values_with_delimiters() {
+        assert_eq!(
+            parse_key_value_pair(r#"10.0.0.1 "a#b.lan" # comment"#).unwrap(),
+            ("10.0.0.1".to_string(), "a#b.lan".to_string(), 10)
+        );
+        let config = Inner::parse(
+            "[server]\ndefault 127.0.0.1:1\n[metadata]\nchaos-version \"pomelo #42 [dev]\" # comment\n",
+            &mut HashSet::new(),
+        )
+        .unwrap();
+        assert_eq!(config.metadata.chaos_version.as_deref(), Some("pomelo #42 [dev]"));
+        // 引号中的括号不会被识别为节标题
+        assert_eq!(section_header("[server]"), Some("server"));
+        assert_eq!(section_header(r#"[metadata] "x]"#), None);
+        let err = Inner::parse(
+            "[server]\ndefault 127.0.0.1:1\n[metadata] \"]\"\n",
+            &mut HashSet::new(),
+        )
+        .unwrap_err();
+        assert!(format!("{:#}", err).contains("Unexpected character '['"), "{err}");
+    }
+
+    #[test]
+    fn parse_error_with_section() {
+        let err = Inner::parse(
+            "[server]\ndefault 127.0.0.1:1\n\n[metadata]\nupstream-strategy random\n",
+            &mut HashSet::new(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "in [metadata] at line 5: 'upstream-strategy random': \
+             Unknown upstream strategy 'random' in line 5"
+        );
+        let err = Inner::parse("[server]\nlan 127.0.0.1:1\n", &mut HashSet::new()).unwrap_err();
+        assert_eq!(err.to_string(), "in [server] at line 2: 'lan 127.0.0.1:1'");
+    }
+
+    #[test]
+    fn reload_changed_hosts_only() {
+        let dir = std::env::temp_dir().join(format!("pomelo-{}-partial-reload", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let hosts = dir.join("hosts");
+        let blocklist = dir.join("blocklist");
+        let path = dir.join("pomelo.conf");
+        fs::write(&hosts, "10.0.0.1 nas.lan\n").unwrap();
+        fs::write(&blocklist, "ads.example\n").unwrap();
+        fs::write(
+            &path,
+            format!(
+                "[server]\ndefault 127.0.0.1:1\n[hosts]\n10.0.0.9 router.lan\n@include {}\n[blocklist]\nads {}\n",
+                hosts.display(),
+                blocklist.display()
+            ),
+        )
+        .unwrap();
+        let config = Config::new(path).unwrap();
+        let before = config.access();
+        fs::write(&hosts, "10.0.0.2 nas.lan\n10.0.0.3 tv.lan\n").unwrap();
+        assert!(config.reload_changed().unwrap());
+        let after = config.access();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(after.get_hosts(DEFAULT_GROUP, "nas.lan").unwrap(), vec![IpAddr::from([10, 0, 0, 2])]);
+        assert_eq!(after.get_hosts(DEFAULT_GROUP, "tv.lan").unwrap(), vec![IpAddr::from([10, 0, 0, 3])]);
+        assert_eq!(after.get_hosts(DEFAULT_GROUP, "router.lan").unwrap(), vec![IpAddr::from([10, 0, 0, 9])]);
+        assert_eq!(before.get_hosts(DEFAULT_GROUP, "nas.lan").unwrap(), vec![IpAddr::from([10, 0, 0, 1])]);
+        // 未变化的拦截列表直接沿用，不重新读取
+        assert!(Arc::ptr_eq(&before.blocklists[0], &after.blocklists[0]));
+    }
+
+    #[test]
+    fn dump_includes_hosts() {
+        let hosts = std::env::temp_dir().join(format!("pomelo-{}-dump-hosts", std::process::id()));
+        fs::write(&hosts, "10.9.8.7 included.lan\n").unwrap();
+        let path = std::env::temp_dir().join(format!("pomelo-{}-dump.conf", std::process::id()));
+        fs::write(
+            &path,
+            format!("[server]\ndefault 127.0.0.1:1\n[hosts]\n@include {}\n", hosts.display()),
+        )
+        .unwrap();
+        let dump = Config::new(path.clone()).unwrap().dump();
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&hosts).unwrap();
+        assert!(dump.contains("10.9.8.7"), "{dump}");
+        assert!(dump.contains("included.lan"), "{dump}");
+    }
+
+    #[test]
+    fn include_hosts_glob() {
+        let dir = std::env::temp_dir().join(format!("pomelo-{}-hosts.d", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("b.conf"), "10.0.0.2 shared.lan\n10.0.0.3 b.lan\n").unwrap();
+        fs::write(dir.join("a.conf"), "10.0.0.1 shared.lan\n").unwrap();
+        fs::write(dir.join("ignored.txt"), "10.0.0.9 ignored.lan\n").unwrap();
+        let mut watch_paths = HashSet::new();
+        let config = Inner::parse(
+            &format!(
+                "[server]\ndefault 127.0.0.1:1\n[hosts]\n@include {}\n",
+                dir.join("*.conf").display()
+            ),
+            &mut watch_paths,
+        )
+        .unwrap();
+        // 按文件名排序依次合并，先出现的条目在前
+        assert_eq!(
+            config.get_hosts(DEFAULT_GROUP, "shared.lan.").unwrap(),
+            vec![IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 2])]
+        );
+        assert_eq!(
+            config.get_hosts(DEFAULT_GROUP, "b.lan.").unwrap(),
+            vec![IpAddr::from([10, 0, 0, 3])]
+        );
+        assert!(config.get_hosts(DEFAULT_GROUP, "ignored.lan.").unwrap().is_empty());
+        assert_eq!(
+            watch_paths,
+            HashSet::from([dir.join("a.conf"), dir.join("b.conf")])
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    const GROUPS: &str = "[group]\nlan 192.168.1.1-192.168.1.255\n[server]\ndefault 127.0.0.1:1\n";
+
+    #[test]
+    fn group_hosts_shadow_default() {
+        let config = Inner::parse(
+            &format!("{GROUPS}[hosts.default]\n10.0.0.1 app.lan\n[hosts.lan]\n10.0.0.2 app.lan\n"),
+            &mut HashSet::new(),
+        )
+        .unwrap();
+        let lan = config.attribute_group(&IpAddr::from([192, 168, 1, 10]));
+        assert_eq!(lan, "lan");
+        assert_eq!(
+            config.get_hosts(&lan, "app.lan.").unwrap(),
+            vec![IpAddr::from([10, 0, 0, 2])]
+        );
+        assert_eq!(
+            config.get_hosts(DEFAULT_GROUP, "app.lan.").unwrap(),
+            vec![IpAddr::from([10, 0, 0, 1])]
+        );
+    }
+
+    #[test]
+    fn hosts_multiple_addrs() {
+        let config = Inner::parse(
+            &format!("{GROUPS}[hosts]\n10.0.0.1 app.lan\n10.0.0.2 app.lan\n[hosts.lan]\n10.0.0.3 app.lan\n"),
+            &mut HashSet::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            config.get_hosts(DEFAULT_GROUP, "app.lan.").unwrap(),
+            vec![IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 2])]
+        );
+        // 分组中存在该域名时不再合并默认分组的地址
+        assert_eq!(
+            config.get_hosts("lan", "app.lan.").unwrap(),
+            vec![IpAddr::from([10, 0, 0, 3])]
+        );
+    }
+
+    #[test]
+    fn group_hostname_shadows_default() {
+        let config = Inner::parse(
+            &format!("{GROUPS}[hosts.default]\n10.0.0.1 default.lan\n[hosts.lan]\n10.0.0.1 group.lan\n"),
+            &mut HashSet::new(),
+        )
+        .unwrap();
+        let addr = IpAddr::from([10, 0, 0, 1]);
+        assert_eq!(config.get_hostname("lan", addr).unwrap(), "group.lan");
+        assert_eq!(config.get_hostname(DEFAULT_GROUP, addr).unwrap(), "default.lan");
+    }
+
+    #[test]
+    fn hosts_case_insensitive() {
+        let config = Inner::parse(
+            &format!("{GROUPS}[hosts]\n10.0.0.1 example.lan\n10.0.0.2 Nas.Home.lan\n"),
+            &mut HashSet::new(),
+        )
+        .unwrap();
+        for domain in ["EXAMPLE.lan", "Example.Lan.", "example.lan"] {
+            assert_eq!(
+                config.get_hosts(DEFAULT_GROUP, domain).unwrap(),
+                vec![IpAddr::from([10, 0, 0, 1])],
+                "{domain}"
+            );
+        }
+        assert_eq!(
+            config.get_hosts(DEFAULT_GROUP, "nas.home.LAN").unwrap(),
+            vec![IpAddr::from([10, 0, 0, 2])]
+        );
+        // 反向查询保留配置中的大小写
+        let addr = IpAddr::from([10, 0, 0, 2]);
+        assert_eq!(config.get_hostname(DEFAULT_GROUP, addr).unwrap(), "Nas.Home.lan");
+    }
+
+    #[tokio::test]
+    async fn group_resolution_shadows_default() {
+        let config = Inner::parse(
+            &format!("{GROUPS}[ipv6_resolution]\ndefault @deny:ALL\nlan @allow:example.com\n"),
+            &mut HashSet::new(),
+        )
+        .unwrap();
+        let addr = IpAddr::from_str("2606:4700::1").unwrap();
+        let domain = Name::from_str("example.com.").unwrap();
+        assert!(config.ipv6_denied_by("lan", &domain, addr).await.is_none());
+        assert!(config.ipv6_denied_by(DEFAULT_GROUP, &domain, addr).await.is_some());
+        // 分组规则未命中时回落到默认规则
+        let other = Name::from_str("example.org.").unwrap();
+        assert!(config.ipv6_denied_by("lan", &other, addr).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn reload_mmdb_in_place() {
+        use mmdb::fixture::{country, Builder};
+        let path = std::env::temp_dir().join(format!("pomelo-{}-country.mmdb", std::process::id()));
+        let write = |iso| {
+            let db = Builder::default()
+                .insert("2606:4700::/32", country(iso))
+                .build("GeoLite2-Country");
+            fs::write(&path, db).unwrap();
+        };
+        write("US");
+        let config = Config::from_text(&format!(
+            "[server]\ndefault 127.0.0.1:1\n[metadata]\nmmdb {}\n[ipv6_resolution]\ndefault @country:US/ALL, @deny:ALL\n",
+            path.display()
+        ))
+        .unwrap();
+        let addr = IpAddr::from_str("2606:4700::1").unwrap();
+        let domain = Name::from_str("example.com.").unwrap();
+        let snapshot = config.access();
+        assert!(snapshot.ipv6_denied_by(DEFAULT_GROUP, &domain, addr).await.is_none());
+
+        write("CN");
+        config.reload_mmdb().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(Arc::ptr_eq(&snapshot, &config.access()));
+        assert!(config.access().ipv6_denied_by(DEFAULT_GROUP, &domain, addr).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn group_mmdb_policies() {
+        use mmdb::fixture::{country, Builder};
+        let dir = std::env::temp_dir();
+        let write = |name: &str, iso| {
+            let path = dir.join(format!("pomelo-{}-{}.mmdb", std::process::id(), name));
+            let db = Builder::default()
+                .insert("2606:4700::/32", country(iso))
+                .build("GeoLite2-Country");
+            fs::write(&path, db).unwrap();
+            path
+        };
+        let (global, gaming) = (write("global", "US"), write("gaming", "JP"));
+        let config = Config::from_text(&format!(
+            "[group]
+gaming 10.0.0.1
+work 10.0.0.2
+             [server]
+default 127.0.0.1:1
+             [metadata]
+mmdb {}
+mmdb.gaming {}
+             [ipv6_resolution]
+gaming @country:JP/ALL, @deny:ALL
+work @country:US/ALL, @deny:ALL
+",
+            global.display(),
+            gaming.display()
+        ))
+        .unwrap();
+        let addr = IpAddr::from_str("2606:4700::1").unwrap();
+        let domain = Name::from_str("example.com.").unwrap();
+        let inner = config.access();
+        // 同一地址在两个分组中按各自的数据库判断国家
+        assert!(inner.ipv6_denied_by("gaming", &domain, addr).await.is_none());
+        assert!(inner.ipv6_denied_by("work", &domain, addr).await.is_none());
+        assert!(!inner.get_mmdb("gaming").country.as_ref().unwrap().shares(
+            inner.metadata.mmdb.country.as_ref().unwrap()
+        ));
+
+        write("global", "CN");
+        config.reload_mmdb().unwrap();
+        fs::remove_file(&global).unwrap();
+        fs::remove_file(&gaming).unwrap();
+        assert!(inner.ipv6_denied_by("gaming", &domain, addr).await.is_none());
+        assert!(inner.ipv6_denied_by("work", &domain, addr).await.is_some());
+    }
+
+    #[test]
+    fn group_inheritance() {
+        let config = Inner::parse(
+            "[group]\noffice 192.168.1.0/24\ntrusted 10.0.0.0/8 @inherit office\nguest 172.16.0.0/12\n\
+             [server]\ndefault 127.0.0.1:1\noffice 127.0.0.1:2\n\
+             [hosts.default]\n10.0.0.1 app.lan\n\
+             [hosts.office]\n10.0.0.2 app.lan\n10.0.0.3 printer.lan\n\
+             [hosts.trusted]\n10.0.0.4 printer.lan\n",
+            &mut HashSet::new(),
+        )
+        .unwrap();
+        let trusted = config.attribute_group(&IpAddr::from([10, 1, 2, 3]));
+        assert_eq!(trusted, "trusted");
+        assert_eq!(config.get_server(&trusted, false), &vec!["127.0.0.1:2".to_string()]);
+        assert_eq!(
+            config.get_hosts(&trusted, "app.lan.").unwrap(),
+            vec![IpAddr::from([10, 0, 0, 2])]
+        );
+        // 自身条目优先于父分组
+        assert_eq!(
+            config.get_hosts(&trusted, "printer.lan.").unwrap(),
+            vec![IpAddr::from([10, 0, 0, 4])]
+        );
+        assert_eq!(config.get_server("guest", false), &vec!["127.0.0.1:1".to_string()]);
+
+        let err = Inner::parse(
+            "[group]\na 10.0.0.1 @inherit b\nb 10.0.0.2 @inherit a\n[server]\ndefault 127.0.0.1:1\n",
+            &mut HashSet::new(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("Circular"), "{err}");
+    }
+
+    #[test]
+    fn read_hosts_with_comments() {
+        let path = std::env::temp_dir().join(format!("pomelo-{}-hosts", std::process::id()));
+        fs::write(
+            &path,
+            "# header\n\n   # indented comment\n\t\n10.0.0.1 app.lan # trailing comment\n  10.0.0.2\tdb.lan  cache.lan\n\n::1 localhost#no space\n",
+        )
+        .unwrap();
+        let hosts = read_hosts(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let hosts = hosts
+            .iter()
+            .map(|(addr, name)| (addr.as_str(), name.to_utf8()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            hosts,
+            vec![
+                ("10.0.0.1", "app.lan.".to_string()),
+                ("10.0.0.2", "db.lan.".to_string()),
+                ("10.0.0.2", "cache.lan.".to_string()),
+                ("::1", "localhost.".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn large_hosts_lookup() {
+        let path = std::env::temp_dir().join(format!("pomelo-{}-hosts-large", std::process::id()));
+        let mut text = String::new();
+        for i in 0..100_000u32 {
+            let [_, a, b, c] = i.to_be_bytes();
+            text.push_str(&format!("10.{a}.{b}.{c} host-{i}.lan\n"));
+        }
+        fs::write(&path, text).unwrap();
+        let config = Inner::parse(
+            &format!("[server]\ndefault 127.0.0.1:1\n[hosts]\n@include {}\n", path.display()),
+            &mut HashSet::new(),
+        )
+        .unwrap();
+        fs::remove_file(&path).unwrap();
+        let lookup = |name: &str| {
+            let start = std::time::Instant::now();
+            for _ in 0..1000 {
+                assert_eq!(config.get_hosts(DEFAULT_GROUP, name).unwrap().len(), 1);
+            }
+            start.elapsed()
+        };
+        let (first, last) = (lookup("host-0.lan."), lookup("host-99999.lan."));
+        // 逐条扫描时末尾条目的查找耗时与条目数成正比，索引后与首个条目相当
+        assert!(last < first * 10 + std::time::Duration::from_millis(20), "{first:?} {last:?}");
+        assert_eq!(
+            config.get_hostname(DEFAULT_GROUP, IpAddr::from([10, 1, 134, 159])).unwrap(),
+            "host-99999.lan"
+        );
+    }
+
+    #[test]
+    fn read_hosts_missing_name() {
+        let path = std::env::temp_dir().join(format!("pomelo-{}-hosts-invalid", std::process::id()));
+        fs::write(&path, "10.0.0.1 app.lan\n10.0.0.2 # no name\n").unwrap();
+        let err = read_hosts(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(err.to_string().contains("line 2"), "{err}");
+    }
 }
@@ -0,0 +1,22 @@
+use crate::config::{parse_key_value_pair, Inner};
+use anyhow::Context;
+use hickory_proto::rr::Name;
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+/// 仅用于反向查询的地址 -> 域名映射，不产生正向记录
+pub type PtrRecords = HashMap<IpAddr, Name>;
+
+/// format: {addr} {name}
+pub fn parse(row: usize, line: &str, inner: &mut Inner) -> anyhow::Result<()> {
+    let (key, value, _) = parse_key_value_pair(line)
+        .map_err(|(err, col)| anyhow::format_err!("{} in line {}:{}", err, row, col))?;
+    let addr = key
+        .parse::<IpAddr>()
+        .with_context(|| format!("Invalid ip addr '{}'", key))?;
+    let mut name = Name::from_ascii(&value)
+        .with_context(|| format!("Invalid host name '{}' in line {}", value, row))?;
+    name.set_fqdn(true);
+    inner.ptr_records.insert(addr, name);
+    Ok(())
+}
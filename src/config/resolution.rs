@@ -2,7 +2,9 @@ use crate::config::{parse_key_value_pair, Inner, DEFAULT_GROUP};
 use crate::ping::ping_with_timeout;
 use hickory_proto::rr::Name;
 use lru::LruCache;
-use maxminddb::{geoip2, Reader};
+use crate::config::metadata::GeoLookupFailure;
+use crate::config::mmdb::{Database, Mmdb};
+use maxminddb::{geoip2, MaxMindDBError, Reader};
 use std::collections::HashMap;
 use std::net::IpAddr;
 use std::num::NonZeroUsize;
@@ -13,15 +15,18 @@ use tokio::sync::OnceCell;
 
 static PING_CACHE: OnceCell<Arc<Mutex<LruCache<IpAddr, bool>>>> = OnceCell::const_new();
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum ResolutionDirective {
     Allow,
     Deny,
     Pingable,
     Country(String),
+    Asn(u32),
+    City(String),
+    Region(String),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum ResolutionPayload {
     Domain(String),
     All,
@@ -38,12 +43,30 @@ impl FromStr for ResolutionPayload {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Resolution {
     directive: ResolutionDirective,
     payload: ResolutionPayload,
 }
 
+/// 按配置中的写法输出规则，例如 @country:CN/ALL
+impl std::fmt::Display for Resolution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "@{}:", self.directive.name())?;
+        match &self.directive {
+            ResolutionDirective::Country(it)
+            | ResolutionDirective::City(it)
+            | ResolutionDirective::Region(it) => write!(f, "{}/", it)?,
+            ResolutionDirective::Asn(asn) => write!(f, "AS{}/", asn)?,
+            _ => {}
+        }
+        match &self.payload {
+            ResolutionPayload::All => f.write_str("ALL"),
+            ResolutionPayload::Domain(domain) => f.write_str(domain),
+        }
+    }
+}
+
 pub type GroupResolutionMappings = HashMap<String, Vec<Resolution>>;
 
 impl Resolution {
@@ -59,15 +82,19 @@ impl Resolution {
                 } else {
                     Name::from_ascii(it)
                 }
-                .map(|it| {
+                .map(|mut it| {
+                    // 规则与查询域名统一为绝对域名，末尾是否带 '.' 不影响匹配
+                    it.set_fqdn(true);
+                    let mut domain = domain.clone();
+                    domain.set_fqdn(true);
                     if is_special_wildcard {
-                        return it.zone_of_case(domain);
+                        return it.zone_of_case(&domain);
                     }
                     if it.is_wildcard() {
                         let basename = it.base_name();
-                        return basename.zone_of_case(domain) && &basename != domain;
+                        return basename.zone_of_case(&domain) && basename != domain;
                     }
-                    it.eq_case(domain)
+                    it.eq_case(&domain)
                 })
                 .unwrap_or_else(|err| {
                     println!("parse failed, reason: {err:?}");
@@ -107,24 +134,113 @@ impl Resolution {
                 r
             }
             ResolutionDirective::Country(country) => {
-                if let Ok(Some(iso_code)) = args
-                    .mmdb
-                    .unwrap()
-                    .lookup::<geoip2::Country>(*args.addr)
-                    .map(|it| it.country.and_then(|it| it.iso_code))
-                {
-                    country == iso_code
-                } else {
-                    false
-                }
+                geo_match(&args, &args.mmdb.country, "country", |mmdb| {
+                    mmdb.lookup::<geoip2::Country>(*args.addr).map(|it| {
+                        it.country
+                            .and_then(|it| it.iso_code)
+                            .map(|it| it == country)
+                    })
+                })
+            }
+            ResolutionDirective::Asn(asn) => geo_match(&args, &args.mmdb.asn, "asn", |mmdb| {
+                mmdb.lookup::<geoip2::Asn>(*args.addr)
+                    .map(|it| it.autonomous_system_number.map(|it| it == *asn))
+            }),
+            ResolutionDirective::City(city) => {
+                geo_match(&args, &args.mmdb.city, "city", |mmdb| {
+                    mmdb.lookup::<geoip2::City>(*args.addr).map(|it| {
+                        it.city
+                            .and_then(|it| it.names)
+                            .map(|names| names.values().any(|it| it.eq_ignore_ascii_case(city)))
+                    })
+                })
+            }
+            ResolutionDirective::Region(region) => {
+                geo_match(&args, &args.mmdb.city, "region", |mmdb| {
+                    mmdb.lookup::<geoip2::City>(*args.addr).map(|it| {
+                        it.subdivisions.map(|subdivisions| {
+                            subdivisions.iter().any(|it| {
+                                it.iso_code.is_some_and(|it| it.eq_ignore_ascii_case(region))
+                            })
+                        })
+                    })
+                })
             }
         }
     }
 }
 
+/// 按 mmdb 中的数据判断是否匹配；数据库未加载、查询出错或地址不在数据库中时按 geo-lookup-failure 处理
+fn geo_match(
+    args: &CheckArgs,
+    database: &Option<Database>,
+    directive: &str,
+    lookup: impl FnOnce(&Reader<Vec<u8>>) -> Result<Option<bool>, MaxMindDBError>,
+) -> bool {
+    let allow = args.on_failure == GeoLookupFailure::Allow;
+    let Some(mmdb) = database.as_ref().map(Database::reader) else {
+        tracing::warn!(
+            "mmdb not loaded, {} directive treated as {}",
+            directive,
+            if allow { "allowed" } else { "denied" }
+        );
+        return allow;
+    };
+    match lookup(&mmdb) {
+        Ok(Some(matched)) => matched,
+        Ok(None) => {
+            tracing::warn!(
+                "No {} data for {} in mmdb, allowed: {}",
+                directive,
+                args.addr,
+                allow
+            );
+            allow
+        }
+        Err(err) => {
+            tracing::warn!(
+                "Failed to lookup {} of {} in mmdb, allowed: {}: {}",
+                directive,
+                args.addr,
+                allow,
+                err
+            );
+            allow
+        }
+    }
+}
+
+impl ResolutionDirective {
+    /// 返回指令依赖的 mmdb 配置项，若该数据库未加载
+    fn missing_mmdb(&self, mmdb: &Mmdb) -> Option<&'static str> {
+        match self {
+            ResolutionDirective::Country(_) if mmdb.country.is_none() => Some("mmdb"),
+            ResolutionDirective::Asn(_) if mmdb.asn.is_none() => Some("mmdb-asn"),
+            ResolutionDirective::City(_) | ResolutionDirective::Region(_)
+                if mmdb.city.is_none() =>
+            {
+                Some("mmdb-city")
+            }
+            _ => None,
+        }
+    }
+    fn name(&self) -> &'static str {
+        match self {
+            ResolutionDirective::Allow => "allow",
+            ResolutionDirective::Deny => "deny",
+            ResolutionDirective::Pingable => "pingable",
+            ResolutionDirective::Country(_) => "country",
+            ResolutionDirective::Asn(_) => "asn",
+            ResolutionDirective::City(_) => "city",
+            ResolutionDirective::Region(_) => "region",
+        }
+    }
+}
+
 pub struct CheckArgs<'input> {
     pub(crate) addr: &'input IpAddr,
-    pub(crate) mmdb: Option<&'input Reader<Vec<u8>>>,
+    pub(crate) mmdb: &'input Mmdb,
+    pub(crate) on_failure: GeoLookupFailure,
 }
 
 impl FromStr for Resolution {
@@ -142,6 +258,28 @@ impl FromStr for Resolution {
                 anyhow::bail!("Country directive invalid: '{}'", s)
             }
             (ResolutionDirective::Country(parts[0].to_string()), parts[1])
+        } else if let Some(end) = s.strip_prefix("@asn:") {
+            let parts = end.split('/').collect::<Vec<_>>();
+            if parts.len() != 2 {
+                anyhow::bail!("ASN directive invalid: '{}'", s)
+            }
+            let asn = parts[0]
+                .trim_start_matches("AS")
+                .parse::<u32>()
+                .map_err(|_| anyhow::format_err!("ASN directive invalid: '{}'", s))?;
+            (ResolutionDirective::Asn(asn), parts[1])
+        } else if let Some(end) = s.strip_prefix("@city:") {
+            let parts = end.split('/').collect::<Vec<_>>();
+            if parts.len() != 2 {
+                anyhow::bail!("City directive invalid: '{}'", s)
+            }
+            (ResolutionDirective::City(parts[0].to_string()), parts[1])
+        } else if let Some(end) = s.strip_prefix("@region:") {
+            let parts = end.split('/').collect::<Vec<_>>();
+            if parts.len() != 2 {
+                anyhow::bail!("Region directive invalid: '{}'", s)
+            }
+            (ResolutionDirective::Region(parts[0].to_string()), parts[1])
         } else {
             anyhow::bail!("Invalid directive: '{}'", s);
         };
@@ -158,29 +296,28 @@ pub fn ipv6_resolution_parse(row: usize, line: &str, inner: &mut Inner) -> anyho
     if key != DEFAULT_GROUP && !inner.groups.contains_key(&key) {
         anyhow::bail!("Can't find group '{}' definition in {line}:1", key);
     }
-    inner.ipv6_resolution.insert(
-        key,
-        value
-            .split(',')
-            .map(|it| match Resolution::from_str(it.trim()) {
-                r @ Ok(Resolution {
-                    directive: ResolutionDirective::Country(_),
-                    ..
-                }) => {
-                    if inner.metadata.mmdb.is_some() {
-                        r
-                    } else {
-                        anyhow::bail!(
-                            "mmdb not found, unable to use 'country' command in line {}:{}",
-                            row,
-                            col
-                        )
-                    }
-                }
-                r => r,
-            })
-            .collect::<Result<Vec<Resolution>, anyhow::Error>>()?,
-    );
+    let rules = value
+        .split(',')
+        .map(|it| {
+            let resolution = Resolution::from_str(it.trim())?;
+            let global = resolution.directive.missing_mmdb(&inner.metadata.mmdb);
+            let missing = match inner.metadata.group_mmdb.get(&key) {
+                Some(mmdb) => resolution.directive.missing_mmdb(mmdb).and(global),
+                None => global,
+            };
+            if let Some(key) = missing {
+                anyhow::bail!(
+                    "{} not found, unable to use '{}' command in line {}:{}",
+                    key,
+                    resolution.directive.name(),
+                    row,
+                    col
+                )
+            }
+            Ok(resolution)
+        })
+        .collect::<Result<Vec<Resolution>, anyhow::Error>>()?;
+    inner.ipv6_resolution.insert(key, rules);
     Ok(())
 }
 
@@ -206,4 +343,145 @@ mod tests {
         assert!(resolution.payload_match(&Name::from_str("abc.example.com").unwrap()));
         assert!(resolution.payload_match(&Name::from_str("www.abc.example.com").unwrap()));
     }
+
+    #[test]
+    fn trailing_dot_optional() {
+        for rule in ["example.com", "example.com.", ".example.com.", "*.example.com."] {
+            let resolution = Resolution {
+                directive: ResolutionDirective::Allow,
+                payload: ResolutionPayload::from_str(rule).unwrap(),
+            };
+            let wildcard = rule.starts_with('*');
+            for query in ["example.com", "example.com."] {
+                let name = Name::from_str(query).unwrap();
+                assert_eq!(resolution.payload_match(&name), !wildcard, "{rule} {query}");
+            }
+            if rule.starts_with(['.', '*']) {
+                for query in ["www.example.com", "www.example.com."] {
+                    let name = Name::from_str(query).unwrap();
+                    assert!(resolution.payload_match(&name), "{rule} {query}");
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn country_without_mmdb() {
+        let resolution = Resolution::from_str("@country:US/ALL").unwrap();
+        let addr = IpAddr::from_str("2606:4700:4700::1111").unwrap();
+        assert!(
+            !resolution
+                .check_is_allow(CheckArgs {
+                    addr: &addr,
+                    mmdb: &Mmdb::default(),
+                    on_failure: GeoLookupFailure::Deny,
+                })
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn country_and_asn_databases() {
+        use crate::config::mmdb::fixture::{asn, country, Builder};
+        let mmdb = Mmdb {
+            country: Some(
+                Builder::default()
+                    .insert("2606:4700::/32", country("US"))
+                    .reader("GeoLite2-Country")
+                    .into(),
+            ),
+            asn: Some(
+                Builder::default()
+                    .insert("2606:4700::/32", asn(13335))
+                    .reader("GeoLite2-ASN")
+                    .into(),
+            ),
+            city: None,
+        };
+        let addr = IpAddr::from_str("2606:4700:4700::1111").unwrap();
+        let args = || CheckArgs {
+            addr: &addr,
+            mmdb: &mmdb,
+            on_failure: GeoLookupFailure::Deny,
+        };
+        let check = |rule: &str| Resolution::from_str(rule).unwrap();
+        assert!(check("@country:US/ALL").check_is_allow(args()).await);
+        assert!(!check("@country:CN/ALL").check_is_allow(args()).await);
+        assert!(check("@asn:13335/ALL").check_is_allow(args()).await);
+        assert!(check("@asn:AS13335/ALL").check_is_allow(args()).await);
+        assert!(!check("@asn:4134/ALL").check_is_allow(args()).await);
+    }
+
+    #[test]
+    fn asn_requires_database() {
+        let err = crate::config::Config::from_text(
+            "[server]\ndefault 127.0.0.1:53\n[ipv6_resolution]\ndefault @asn:13335/ALL\n",
+        )
+        .err()
+        .unwrap();
+        assert!(format!("{err:#}").contains("mmdb-asn"));
+    }
+
+    #[tokio::test]
+    async fn city_and_region_directives() {
+        use crate::config::mmdb::fixture::{city, Builder};
+        let mmdb = Mmdb {
+            city: Some(
+                Builder::default()
+                    .insert("2001:db8::/32", city("Mountain View", "CA"))
+                    .reader("GeoLite2-City")
+                    .into(),
+            ),
+            ..Mmdb::default()
+        };
+        let addr = IpAddr::from_str("2001:db8::1").unwrap();
+        let args = || CheckArgs {
+            addr: &addr,
+            mmdb: &mmdb,
+            on_failure: GeoLookupFailure::Deny,
+        };
+        let check = |rule: &str| Resolution::from_str(rule).unwrap();
+        assert!(check("@city:Mountain View/ALL").check_is_allow(args()).await);
+        assert!(check("@city:mountain view/ALL").check_is_allow(args()).await);
+        assert!(!check("@city:Tokyo/ALL").check_is_allow(args()).await);
+        assert!(check("@region:CA/ALL").check_is_allow(args()).await);
+        assert!(!check("@region:NY/ALL").check_is_allow(args()).await);
+    }
+
+    #[tokio::test]
+    async fn geo_lookup_failure_policy() {
+        use crate::config::mmdb::fixture::{country, Builder};
+        let mmdb = Mmdb {
+            country: Some(
+                Builder::default()
+                    .insert("2606:4700::/32", country("US"))
+                    .reader("GeoLite2-Country")
+                    .into(),
+            ),
+            ..Mmdb::default()
+        };
+        // 地址不在数据库中
+        let addr = IpAddr::from_str("2001:db8::1").unwrap();
+        let resolution = Resolution::from_str("@country:US/ALL").unwrap();
+        for (on_failure, allowed) in [
+            (GeoLookupFailure::Deny, false),
+            (GeoLookupFailure::Allow, true),
+        ] {
+            let args = CheckArgs {
+                addr: &addr,
+                mmdb: &mmdb,
+                on_failure,
+            };
+            assert_eq!(resolution.check_is_allow(args).await, allowed);
+        }
+        // 查询成功时不受策略影响
+        let addr = IpAddr::from_str("2606:4700:4700::1111").unwrap();
+        let resolution = Resolution::from_str("@country:CN/ALL").unwrap();
+        let args = CheckArgs {
+            addr: &addr,
+            mmdb: &mmdb,
+            on_failure: GeoLookupFailure::Allow,
+        };
+        assert!(!resolution.check_is_allow(args).await);
+    }
 }
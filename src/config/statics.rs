@@ -0,0 +1,48 @@
+use crate::config::{parse_key_value_pair, Inner};
+use anyhow::Context;
+use hickory_proto::rr::{rdata, Name, RData};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// 固定应答的记录，或对该域名的所有查询应答 NXDOMAIN
+#[derive(Debug, Clone)]
+pub enum StaticAnswer {
+    NxDomain,
+    Record(RData),
+}
+
+pub type StaticAnswers = HashMap<Name, Vec<StaticAnswer>>;
+
+/// format: {name} {type} [rdata]
+pub fn parse(row: usize, line: &str, inner: &mut Inner) -> anyhow::Result<()> {
+    let (key, value, _) = parse_key_value_pair(line)
+        .map_err(|(err, col)| anyhow::format_err!("{} in line {}:{}", err, row, col))?;
+    let mut name = Name::from_ascii(&key)
+        .with_context(|| format!("Invalid domain name '{}' in line {}", key, row))?;
+    name.set_fqdn(true);
+    let (rtype, data) = value
+        .split_once(char::is_whitespace)
+        .map(|(rtype, data)| (rtype, data.trim()))
+        .unwrap_or((value.as_str(), ""));
+    let answer = match rtype.to_ascii_uppercase().as_str() {
+        "NXDOMAIN" => StaticAnswer::NxDomain,
+        "A" => StaticAnswer::Record(RData::A(rdata::A(
+            data.parse::<Ipv4Addr>()
+                .with_context(|| format!("Invalid ipv4 addr '{}' in line {}", data, row))?,
+        ))),
+        "AAAA" => StaticAnswer::Record(RData::AAAA(rdata::AAAA(
+            data.parse::<Ipv6Addr>()
+                .with_context(|| format!("Invalid ipv6 addr '{}' in line {}", data, row))?,
+        ))),
+        "CNAME" => {
+            let mut target = Name::from_ascii(data)
+                .with_context(|| format!("Invalid CNAME target '{}' in line {}", data, row))?;
+            target.set_fqdn(true);
+            StaticAnswer::Record(RData::CNAME(rdata::CNAME(target)))
+        }
+        "TXT" => StaticAnswer::Record(RData::TXT(rdata::TXT::new(vec![data.to_string()]))),
+        _ => anyhow::bail!("Unsupported static record type '{}' in line {}", rtype, row),
+    };
+    inner.static_answers.entry(name).or_default().push(answer);
+    Ok(())
+}
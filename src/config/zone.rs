@@ -0,0 +1,129 @@
+use crate::config::{parse_key_value_pair, Inner};
+use anyhow::Context;
+use hickory_proto::rr::{rdata, LowerName, Name, RData, Record, RecordSet, RecordType, RrKey};
+use hickory_proto::serialize::txt::Parser;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// 从 RFC 1035 区域文件加载的静态区域
+pub struct Zone {
+    pub origin: Name,
+    pub path: PathBuf,
+    records: BTreeMap<RrKey, RecordSet>,
+}
+
+pub type Zones = Vec<Arc<Zone>>;
+
+/// 区域内查询的应答，没有应答记录时附带区域的 SOA
+#[derive(Debug)]
+pub struct ZoneAnswer {
+    pub nxdomain: bool,
+    pub answers: Vec<Record>,
+    pub soa: Option<Record>,
+}
+
+impl std::fmt::Debug for Zone {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Zone")
+            .field("origin", &self.origin)
+            .field("path", &self.path)
+            .field("records", &self.records.len())
+            .finish()
+    }
+}
+
+impl Zone {
+    pub fn contains(&self, domain: &Name) -> bool {
+        self.origin.zone_of(domain)
+    }
+    /// 查找域名的记录，不存在时跟随区域内的 CNAME
+    pub fn lookup(&self, domain: &Name, rtype: RecordType) -> ZoneAnswer {
+        let mut answers = Vec::new();
+        let mut name = LowerName::from(domain);
+        // 每条 CNAME 至多跟随一次，防止 CNAME 环
+        for _ in 0..=self.records.len() {
+            if let Some(set) = self.records.get(&RrKey::new(name.clone(), rtype)) {
+                answers.extend(set.records_without_rrsigs().cloned());
+                break;
+            }
+            if rtype == RecordType::CNAME {
+                break;
+            }
+            let Some(record) = self
+                .records
+                .get(&RrKey::new(name.clone(), RecordType::CNAME))
+                .and_then(|set| set.records_without_rrsigs().next())
+            else {
+                break;
+            };
+            answers.push(record.clone());
+            match record.data() {
+                Some(RData::CNAME(rdata::CNAME(target))) if self.contains(target) => {
+                    name = LowerName::from(target);
+                }
+                _ => break,
+            }
+        }
+        if !answers.is_empty() {
+            return ZoneAnswer {
+                nxdomain: false,
+                answers,
+                soa: None,
+            };
+        }
+        // 域名本身或其下级域名有记录时应答 NODATA
+        let exists = self.records.keys().any(|key| name.zone_of(key.name()));
+        ZoneAnswer {
+            nxdomain: !exists,
+            answers,
+            soa: self.soa(),
+        }
+    }
+    fn soa(&self) -> Option<Record> {
+        self.records
+            .get(&RrKey::new(LowerName::from(&self.origin), RecordType::SOA))
+            .and_then(|set| set.records_without_rrsigs().next())
+            .cloned()
+    }
+}
+
+/// format: {origin}  {path}
+pub fn parse(
+    row: usize,
+    line: &str,
+    inner: &mut Inner,
+    watch_paths: &mut HashSet<PathBuf>,
+) -> anyhow::Result<()> {
+    let (key, value, _) = parse_key_value_pair(line)
+        .map_err(|(err, col)| anyhow::format_err!("{} in line {}:{}", err, row, col))?;
+    let mut origin =
+        Name::from_ascii(&key).with_context(|| format!("Invalid zone origin '{}'", key))?;
+    origin.set_fqdn(true);
+    let path = PathBuf::from(value.trim());
+    let records = read_zone(&origin, &path)?;
+    watch_paths.insert(path.clone());
+    inner.zones.push(Arc::new(Zone {
+        origin,
+        path,
+        records,
+    }));
+    Ok(())
+}
+
+fn read_zone(origin: &Name, path: &PathBuf) -> anyhow::Result<BTreeMap<RrKey, RecordSet>> {
+    if !path.is_file() {
+        anyhow::bail!("Zone file does not exist, path: '{:?}'", path);
+    }
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Unable to read zone file '{:?}'", path))?;
+    let (_, records) = Parser::new(text, Some(path.clone()), Some(origin.clone()))
+        .parse()
+        .with_context(|| format!("Failed to parse zone file '{:?}'", path))?;
+    let zone = LowerName::from(origin);
+    if let Some(key) = records.keys().find(|key| !zone.zone_of(key.name())) {
+        anyhow::bail!("Record '{}' is outside of zone '{}'", key.name(), origin);
+    }
+    Ok(records)
+}
@@ -1,17 +1,29 @@
 use crate::cache::Cache;
-use crate::config::Config;
-use crate::resolves::{resolve, ResolveOpts};
+use crate::config::{
+    BlockAction, Config, Inner, RdPolicy, RootQueryPolicy, StaticAnswer, UnmatchedPolicy,
+    UpstreamStrategy,
+};
+use crate::resolves::{resolve, ResolveOpts, TlsOpts};
+use crate::stats::{self, STATS};
 use anyhow::Context;
-use hickory_proto::op::{Message, MessageType, Query};
-use hickory_proto::rr::{rdata, Name, RData, Record, RecordType};
+use futures::StreamExt;
+use hickory_proto::op::{Message, MessageType, OpCode, Query, ResponseCode};
+use hickory_proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+use hickory_proto::rr::{rdata, DNSClass, Name, RData, Record, RecordType};
 use hickory_proto::serialize::binary::BinDecodable;
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::collections::{HashMap, HashSet};
 use std::fmt::Write;
 use std::future::Future;
+use std::hash::{BuildHasher, Hash, Hasher};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::time::Instant;
 
+static ROTATION: AtomicUsize = AtomicUsize::new(0);
+
 pub struct Handler {
     pub addr: SocketAddr,
     pub cache: Arc<Cache>,
@@ -20,6 +32,9 @@ pub struct Handler {
     pub group: String,
     pub start: Instant,
     pub protocol: &'static str,
+    pub access_log: bool,
+    /// 设置后仅耗时超过阈值的查询输出完整的访问日志
+    pub slow_query_threshold: Option<Duration>,
 }
 
 impl Handler {
@@ -30,14 +45,24 @@ impl Handler {
         cache: Arc<Cache>,
         config: Arc<Config>,
     ) -> Self {
+        let (access_log, timeout, slow_query_threshold) = {
+            let config = config.access();
+            (
+                config.metadata.is_access_log_enabled(&group),
+                config.metadata.query_timeout,
+                config.metadata.slow_query_threshold,
+            )
+        };
         Self {
             addr,
             cache,
-            timeout: Duration::from_secs(60),
+            timeout,
             group,
             config,
             start: Instant::now(),
             protocol,
+            access_log,
+            slow_query_threshold,
         }
     }
 
@@ -56,90 +81,573 @@ impl Handler {
         F: FnOnce(Vec<u8>, SocketAddr) -> Fut,
         Fut: Future<Output = anyhow::Result<()>>,
     {
+        // 整个查询过程使用同一份配置快照，避免中途重载导致结果不一致
+        let config = self.config.access();
+        if config.metadata.dump_wire {
+            tracing::trace!("[->](W) {}", Hex(&bytes));
+        }
         let req =
             Message::from_bytes(&bytes).with_context(|| "Failed to parse message from bytes")?;
-        tracing::trace!(
-            "----[IP: {protocol}://{addr}]#{id:0>5} [GROUP: {group}]------------------------------------------",
-            protocol = self.protocol,
-            addr = self.addr.ip(),
-            id = req.id(),
-            group = self.group,
-        );
-        tracing::trace!(
-            "[->](Q) Queries: {}",
-            format_queries(req.queries(), req.extensions().is_some())
-        );
-        if let Some(res) = Self::print_err_and_flatten(
-            self.resolve_from_hosts(&req)
+        if self.verbose_log() {
+            self.print_dns_query_header(&req);
+        }
+        let (stage, mut res) = if req.op_code() != OpCode::Query {
+            // 仅支持标准查询，UPDATE、NOTIFY 等操作码不转发给上游
+            ('R', response_to(&req, ResponseCode::NotImp))
+        } else if req.queries().len() > 1 {
+            // 多问题报文无法与上游应答一一对应，按通行做法拒绝
+            ('R', response_to(&req, ResponseCode::FormErr))
+        } else {
+            match self.respond(&config, &req, &bytes).await {
+                Ok(ret) => ret,
+                // 上游超时或不可达等内部错误按配置应答，未配置时不应答
+                Err(err) => match config.metadata.failure_response {
+                    Some(code) => {
+                        tracing::error!("{}", format_err(err, 34));
+                        let mut res = response_to(&req, code);
+                        set_extended_error(&req, &mut res, EDE_NETWORK_ERROR, "upstream failure");
+                        ('E', res)
+                    }
+                    None => return Err(err),
+                },
+            }
+        };
+        if let Some(timeout) = config.metadata.edns_tcp_keepalive {
+            set_tcp_keepalive(self.protocol, &req, &mut res, timeout);
+        }
+        STATS.record_stage(stage);
+        self.print_dns_query_detail(stage, &req, &res);
+        let res = res
+            .to_vec()
+            .with_context(|| "Failed to convert response to vec")?;
+        if config.metadata.dump_wire {
+            tracing::trace!("[<-](W) {}", Hex(&res));
+        }
+        // 本地及缓存应答补齐到最短应答时间，避免通过耗时区分应答来源
+        if let Some(min) = config.metadata.constant_time_response {
+            tokio::time::sleep_until(self.start + min).await;
+        }
+        if let Some((min, max)) = config.metadata.response_delay {
+            tokio::time::sleep(jitter(min, max)).await;
+        }
+        send_ret(res, self.addr)
+            .await
+            .with_context(|| "Failed to send response")?;
+        Ok(())
+    }
+    /// 依次从 hosts、缓存、上游获取应答，返回应答阶段及应答
+    async fn respond(
+        &mut self,
+        config: &Arc<Inner>,
+        req: &Message,
+        bytes: &[u8],
+    ) -> anyhow::Result<(char, Message)> {
+        // 不在 allow-query 范围内的客户端直接拒绝，不做任何转发
+        if !config.metadata.is_query_allowed(&self.addr.ip()) {
+            return Ok(('D', response_to(req, ResponseCode::Refused)));
+        }
+        if config.metadata.unmatched_policy == UnmatchedPolicy::Refuse
+            && config.find_group(&self.addr.ip()).is_none()
+        {
+            return Ok(('D', response_to(req, ResponseCode::Refused)));
+        }
+        if let Some(res) = Self::oversized_name_query(config, req) {
+            return Ok(('R', res));
+        }
+        if let Some(res) = Self::chaos_query(config, req) {
+            return Ok(('V', res));
+        }
+        if let Some(res) = self.self_hostname_query(config, req) {
+            return Ok(('V', res));
+        }
+        if let Some(res) = self.status_query(config, req) {
+            return Ok(('V', res));
+        }
+        if let Some(res) = self.blocked_query(config, req) {
+            return Ok(('B', res));
+        }
+        if let Some(res) = self.static_query(config, req) {
+            return Ok(('L', res));
+        }
+        if let Some(res) = Self::local_zone_soa_query(config, req) {
+            return Ok(('L', res));
+        }
+        if let Some(mut res) = Self::print_err_and_flatten(
+            self.resolve_from_hosts(config, req)
                 .await
                 .with_context(|| "Failed to resolve from hosts"),
         ) {
-            self.print_dns_query_detail('L', &req, &res);
-            send_ret(
-                res.to_vec()
-                    .with_context(|| "Failed to convert response to vec")?,
-                self.addr,
-            )
-            .await
-            .with_context(|| "Failed to send response")?;
-            return Ok(());
+            self.rotate_answers(config, &mut res);
+            return Ok(('L', res));
         }
-        if let Some(res) = Self::print_err_and_flatten(
-            self.lookup_dns_cache(&req)
+        if let Some(res) = Self::zone_query(config, req) {
+            return Ok(('L', res));
+        }
+        if let Some(mut res) = Self::print_err_and_flatten(
+            self.lookup_dns_cache(req)
                 .with_context(|| "Failed to lookup DNS cache"),
         ) {
-            self.print_dns_query_detail('C', &req, &res);
-            send_ret(
-                res.to_vec()
-                    .with_context(|| "Failed to convert cached response to vec")?,
-                self.addr,
-            )
-            .await
-            .with_context(|| "Failed to send cached response")?;
-            return Ok(());
+            self.prefetch(config, req, bytes);
+            self.rotate_answers(config, &mut res);
+            return Ok(('C', res));
         }
-        let res = self
-            .forward_dns_query(&req,&bytes)
-            .await
-            .with_context(|| "Failed to forward DNS query")?;
+        if let Some(res) = self.refuse_any_query(config, req) {
+            return Ok(('R', res));
+        }
+        if let Some(res) = Self::root_query(config, req) {
+            return Ok(('R', res));
+        }
+        // 作为转发器不做迭代查询，RD=0 的查询按策略转发或拒绝
+        if !req.recursion_desired() && config.metadata.rd_policy == RdPolicy::Refuse {
+            return Ok(('R', response_to(req, ResponseCode::Refused)));
+        }
+        let mut res = self.forward_and_cache(config, req, bytes).await?;
+        res.set_recursion_desired(req.recursion_desired())
+            .set_authentic_data(false);
+        self.rotate_answers(config, &mut res);
+        Ok(('F', res))
+    }
+    /// 转发查询并缓存应答，经过 AAAA 过滤及 force-ttl 处理
+    async fn forward_and_cache(
+        &mut self,
+        config: &Arc<Inner>,
+        req: &Message,
+        bytes: &[u8],
+    ) -> anyhow::Result<Message> {
+        let expanded = Self::expand_search_domain(config, req)?;
+        let res = match &expanded {
+            Some(expanded) => {
+                let bytes = expanded
+                    .to_vec()
+                    .with_context(|| "Failed to encode expanded query")?;
+                self.forward_dns_query(config, expanded, &bytes).await
+            }
+            // 重新编码可规整名称压缩并丢弃报文末尾多余的数据
+            None if config.metadata.reencode_requests => {
+                let bytes = req
+                    .to_vec()
+                    .with_context(|| "Failed to re-encode query")?;
+                self.forward_dns_query(config, req, &bytes).await
+            }
+            None => self.forward_dns_query(config, req, bytes).await,
+        }
+        .with_context(|| "Failed to forward DNS query")?;
         let mut res = Message::from_bytes(&res)
             .with_context(|| "Failed to parse forwarded response from bytes")?;
+        if let Some(expanded) = &expanded {
+            Self::restore_search_domain(req, expanded, &mut res);
+        }
+        let synthesized = self.synthesize_aaaa(config, req, &mut res).await?;
         if req
             .queries()
             .iter()
             .any(|it| matches!(it.query_type(), RecordType::AAAA))
         {
-            self.resolution(&mut res)
+            let denied = self
+                .resolution(config, &mut res)
                 .await
                 .with_context(|| "Failed during AAAA record resolution")?;
+            if denied {
+                set_extended_error(req, &mut res, EDE_FILTERED, "AAAA records filtered");
+            }
+            // AAAA 全部被过滤时应答带 SOA 的 NODATA，使客户端尽快回落到 IPv4
+            if let (true, Some(ttl)) = (denied, config.metadata.ipv6_denied_ttl) {
+                let name = req.queries()[0].name().clone();
+                res.take_name_servers();
+                res.add_name_server(local_soa(&name, ttl, ttl));
+            }
+        }
+        if let Some(ttl) = config.metadata.force_ttl {
+            for answer in res.answers_mut() {
+                answer.set_ttl(ttl);
+            }
+        }
+        // 仅保留前 max-answers 条记录，应答的记录数随之调整
+        if let Some(max) = config.metadata.max_answers {
+            res.answers_mut().truncate(max);
+        }
+        // 合成的 AAAA 记录不是上游的应答，不写入缓存
+        if !synthesized {
+            self.cache_dns_record(config, req, &res)
+                .with_context(|| "Failed to cache DNS record")?;
+        }
+        Ok(res)
+    }
+    /// 上游没有 AAAA 记录且域名配置了 synth6 规则时，查询 A 记录并嵌入前缀合成 AAAA，返回是否已合成
+    async fn synthesize_aaaa(
+        &mut self,
+        config: &Inner,
+        req: &Message,
+        res: &mut Message,
+    ) -> anyhow::Result<bool> {
+        let Some(query) = req.queries().first() else {
+            return Ok(false);
+        };
+        if query.query_type() != RecordType::AAAA
+            || res.response_code() != ResponseCode::NoError
+            || res.answers().iter().any(|it| it.record_type() == RecordType::AAAA)
+        {
+            return Ok(false);
+        }
+        let Some(prefix) = config.get_synth6(&self.group, query.name()) else {
+            return Ok(false);
+        };
+        let mut a_req = req.clone();
+        a_req.take_queries();
+        a_req.add_query(Query::query(query.name().clone(), RecordType::A));
+        let bytes = a_req
+            .to_vec()
+            .with_context(|| "Failed to encode A query for AAAA synthesis")?;
+        let a_res = self.forward_dns_query(config, &a_req, &bytes).await?;
+        let a_res = Message::from_bytes(&a_res)
+            .with_context(|| "Failed to parse A response for AAAA synthesis")?;
+        let synthesized = a_res
+            .answers()
+            .iter()
+            .filter_map(|it| {
+                let data = match it.data() {
+                    Some(RData::A(rdata::A(addr))) => RData::AAAA(rdata::AAAA(prefix.embed(*addr))),
+                    Some(RData::CNAME(_)) => it.data()?.clone(),
+                    _ => return None,
+                };
+                Some(Record::from_rdata(it.name().clone(), it.ttl(), data))
+            })
+            .collect::<Vec<_>>();
+        if !synthesized.iter().any(|it| it.record_type() == RecordType::AAAA) {
+            return Ok(false);
         }
-        self.cache_dns_record(&res)
-            .with_context(|| "Failed to cache DNS record")?;
-        res.set_authentic_data(false);
-        self.print_dns_query_detail('F', &req, &res);
-        send_ret(
-            res.to_vec()
-                .with_context(|| "Failed to convert final response to vec")?,
+        res.take_answers();
+        res.take_name_servers();
+        res.add_answers(synthesized);
+        Ok(true)
+    }
+    /// 命中的缓存记录剩余 TTL 低于 prefetch-threshold 时，在后台重新转发查询以提前刷新缓存
+    fn prefetch(&self, config: &Arc<Inner>, req: &Message, bytes: &[u8]) {
+        let (Some(threshold), Some(query)) =
+            (config.metadata.prefetch_threshold, req.queries().first())
+        else {
+            return;
+        };
+        let name = query.name().to_lowercase().to_utf8();
+        let key = self.cache_key(query.name());
+        let claimed = match self.cache.access(&key) {
+            Ok(Some(mut guard)) => {
+                guard.claim_prefetch(&key, query.query_type(), query.query_class(), threshold)
+            }
+            _ => false,
+        };
+        if !claimed {
+            return;
+        }
+        let mut handler = Handler::new(
+            self.protocol,
             self.addr,
-        )
-        .await
-        .with_context(|| "Failed to send final response")?;
-        Ok(())
+            self.group.clone(),
+            self.cache.clone(),
+            self.config.clone(),
+        );
+        let (config, req, bytes) = (config.clone(), req.clone(), bytes.to_vec());
+        tokio::spawn(async move {
+            if let Err(err) = handler.forward_and_cache(&config, &req, &bytes).await {
+                tracing::warn!("Failed to prefetch {}: {}", name, format_err(err, 34));
+            }
+        });
+    }
+    /// 本地应答 CHAOS 类的 version.bind / id.server 查询
+    fn chaos_query(config: &Inner, req: &Message) -> Option<Message> {
+        let query = req.queries().iter().find(|it| {
+            it.query_class() == DNSClass::CH
+                && it.query_type() == RecordType::TXT
+                && Self::CHAOS_NAMES
+                    .iter()
+                    .any(|name| it.name().to_lowercase().to_utf8() == *name)
+        })?;
+        let version = match config.metadata.chaos_version.as_deref() {
+            Some("hidden") => return Some(response_to(req, ResponseCode::Refused)),
+            Some(version) => version.to_string(),
+            None => format!("pomelo {}", env!("CARGO_PKG_VERSION")),
+        };
+        let mut answer = Record::from_rdata(
+            query.name().clone(),
+            0,
+            RData::TXT(rdata::TXT::new(vec![version])),
+        );
+        answer.set_dns_class(DNSClass::CH);
+        let mut res = response_to(req, ResponseCode::NoError);
+        res.set_authoritative(true).add_answer(answer);
+        Some(res)
+    }
+    /// 本地应答 pomelo 自身域名的 A/AAAA 查询，无对应地址时应答 NODATA
+    fn self_hostname_query(&self, config: &Inner, req: &Message) -> Option<Message> {
+        let (name, addrs) = config.metadata.self_hostname.as_ref()?;
+        let query = req.queries().iter().find(|it| {
+            matches!(it.query_type(), RecordType::A | RecordType::AAAA)
+                && it.name().to_lowercase() == name.to_lowercase()
+        })?;
+        let mut res = response_to(req, ResponseCode::NoError);
+        res.set_authoritative(true);
+        for addr in addrs {
+            let data = match (query.query_type(), addr) {
+                (RecordType::A, IpAddr::V4(addr)) => RData::A(rdata::A(*addr)),
+                (RecordType::AAAA, IpAddr::V6(addr)) => RData::AAAA(rdata::AAAA(*addr)),
+                _ => continue,
+            };
+            res.add_answer(Record::from_rdata(
+                query.name().clone(),
+                config.hosts_ttl(&self.group),
+                data,
+            ));
+        }
+        Some(res)
+    }
+    /// 本地应答 status-name 的 TXT 查询，每条记录为一项 key=value；其它类型应答 NODATA
+    fn status_query(&self, config: &Inner, req: &Message) -> Option<Message> {
+        let name = config.metadata.status_name.as_ref()?;
+        let query = req
+            .queries()
+            .iter()
+            .find(|it| it.name().to_lowercase() == name.to_lowercase())?;
+        if query.query_type() != RecordType::TXT {
+            let mut res = negative_response(config, req, ResponseCode::NoError);
+            res.set_authoritative(true);
+            return Some(res);
+        }
+        let fields = [
+            ("version", env!("CARGO_PKG_VERSION").to_string()),
+            ("commit", env!("COMMIT_ID").to_string()),
+            ("uptime", stats::uptime().as_secs().to_string()),
+            ("queries", STATS.queries().to_string()),
+            ("cache-hit", format!("{:.1}%", STATS.hit_ratio())),
+            ("cache-entries", self.cache.entries().to_string()),
+        ];
+        let mut res = response_to(req, ResponseCode::NoError);
+        res.set_authoritative(true);
+        for (key, value) in fields {
+            res.add_answer(Record::from_rdata(
+                query.name().clone(),
+                0,
+                RData::TXT(rdata::TXT::new(vec![format!("{}={}", key, value)])),
+            ));
+        }
+        Some(res)
+    }
+    const CHAOS_NAMES: [&'static str; 3] = ["version.bind.", "version.server.", "id.server."];
+    /// 本地区域的 SOA 查询直接应答，区域内其它域名的 SOA 查询应答 NODATA
+    fn local_zone_soa_query(config: &Inner, req: &Message) -> Option<Message> {
+        let (query, zone) = req
+            .queries()
+            .iter()
+            .filter(|it| it.query_type() == RecordType::SOA)
+            .find_map(|it| config.metadata.local_zone(it.name()).map(|zone| (it, zone)))?;
+        let mut res = response_to(req, ResponseCode::NoError);
+        res.set_authoritative(true);
+        // 与 negative_response 一致，SOA 的 TTL 及 minimum 决定客户端缓存否定应答的时长
+        let ttl = config.metadata.local_negative_ttl;
+        if query.name() == zone {
+            res.add_answer(local_soa(zone, ttl, ttl));
+        } else {
+            res.add_name_server(local_soa(zone, ttl, ttl));
+        }
+        Some(res)
+    }
+    /// [static] 中固定的应答；查询类型没有对应记录时使用 CNAME，均没有时交由后续阶段处理
+    fn static_query(&self, config: &Inner, req: &Message) -> Option<Message> {
+        let query = req.queries().first()?;
+        let answers = config.get_static(query.name())?;
+        if answers.iter().any(|it| matches!(it, StaticAnswer::NxDomain)) {
+            let mut res = negative_response(config, req, ResponseCode::NXDomain);
+            res.set_authoritative(true);
+            return Some(res);
+        }
+        let ttl = config.hosts_ttl(&self.group);
+        let records_of = |rtype: RecordType| {
+            answers
+                .iter()
+                .filter_map(|it| match it {
+                    StaticAnswer::Record(data) if data.record_type() == rtype => Some(data),
+                    _ => None,
+                })
+                .map(|data| Record::from_rdata(query.name().clone(), ttl, data.clone()))
+                .collect::<Vec<_>>()
+        };
+        let mut records = records_of(query.query_type());
+        if records.is_empty() {
+            records = records_of(RecordType::CNAME);
+        }
+        if records.is_empty() {
+            return None;
+        }
+        let mut res = response_to(req, ResponseCode::NoError);
+        res.set_authoritative(true).add_answers(records);
+        Some(res)
+    }
+    /// 静态区域内的域名由区域文件权威应答，不转发
+    fn zone_query(config: &Inner, req: &Message) -> Option<Message> {
+        let query = req.queries().first()?;
+        let zone = config.find_zone(query.name())?;
+        let answer = zone.lookup(query.name(), query.query_type());
+        let code = if answer.nxdomain {
+            ResponseCode::NXDomain
+        } else {
+            ResponseCode::NoError
+        };
+        let mut res = response_to(req, code);
+        res.set_authoritative(true).add_answers(answer.answers);
+        if let Some(soa) = answer.soa {
+            res.add_name_server(soa);
+        }
+        Some(res)
+    }
+    /// 为单标签域名追加搜索域，无需改写时返回 None
+    fn expand_search_domain(config: &Inner, req: &Message) -> anyhow::Result<Option<Message>> {
+        let domain = match &config.metadata.search_domain {
+            Some(domain) => domain,
+            None => return Ok(None),
+        };
+        if !req.queries().iter().any(|it| it.name().num_labels() == 1) {
+            return Ok(None);
+        }
+        let mut expanded = req.clone();
+        let queries = expanded
+            .take_queries()
+            .into_iter()
+            .map(|mut query| {
+                if query.name().num_labels() == 1 {
+                    let name = query.name().clone().append_domain(domain)?;
+                    query.set_name(name);
+                }
+                Ok(query)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        expanded.add_queries(queries);
+        Ok(Some(expanded))
+    }
+    /// 将应答中的域名还原为客户端查询的原始域名
+    fn restore_search_domain(req: &Message, expanded: &Message, res: &mut Message) {
+        let names = expanded
+            .queries()
+            .iter()
+            .zip(req.queries())
+            .filter(|(expanded, query)| expanded.name() != query.name())
+            .map(|(expanded, query)| (expanded.name().clone(), query.name().clone()))
+            .collect::<Vec<_>>();
+        res.take_queries();
+        res.add_queries(req.queries().to_vec());
+        let restore = |records: &mut [Record]| {
+            for record in records {
+                if let Some((_, name)) = names.iter().find(|(it, _)| it == record.name()) {
+                    record.set_name(name.clone());
+                }
+            }
+        };
+        restore(res.answers_mut());
+        restore(res.name_servers_mut());
+        restore(res.additionals_mut());
+    }
+    /// 命中拦截列表时按配置的方式直接应答
+    fn blocked_query(&self, config: &Inner, req: &Message) -> Option<Message> {
+        let (query, (list, action)) = req
+            .queries()
+            .iter()
+            .find_map(|it| config.get_block_action(it.name()).map(|r| (it, r)))?;
+        if self.verbose_log() {
+            tracing::trace!("[--](B) Blocked by '{}', action {:?}", list, action);
+        }
+        let mut res = match action {
+            BlockAction::NxDomain => negative_response(config, req, ResponseCode::NXDomain),
+            BlockAction::NoData => negative_response(config, req, ResponseCode::NoError),
+            BlockAction::Refused => response_to(req, ResponseCode::Refused),
+            BlockAction::Sinkhole => {
+                let mut res = response_to(req, ResponseCode::NoError);
+                let data = match query.query_type() {
+                    RecordType::A => Some(RData::A(rdata::A(Ipv4Addr::UNSPECIFIED))),
+                    RecordType::AAAA => Some(RData::AAAA(rdata::AAAA(Ipv6Addr::UNSPECIFIED))),
+                    _ => None,
+                };
+                if let Some(data) = data {
+                    res.add_answer(Record::from_rdata(query.name().clone(), 60, data));
+                }
+                res
+            }
+        };
+        set_extended_error(req, &mut res, EDE_BLOCKED, &format!("blocked by {}", list));
+        Some(res)
+    }
+    /// 标签数或长度超出限制的域名直接拒绝
+    fn oversized_name_query(config: &Inner, req: &Message) -> Option<Message> {
+        let metadata = &config.metadata;
+        if metadata.max_name_labels.is_none() && metadata.max_name_length.is_none() {
+            return None;
+        }
+        req.queries()
+            .iter()
+            .any(|it| {
+                metadata
+                    .max_name_labels
+                    .is_some_and(|max| it.name().num_labels() as usize > max)
+                    || metadata
+                        .max_name_length
+                        .is_some_and(|max| it.name().len() > max)
+            })
+            .then(|| response_to(req, ResponseCode::Refused))
+    }
+    /// RFC 8482: 对 ANY 查询返回最小化的 HINFO 应答，避免被用于放大攻击
+    fn refuse_any_query(&self, config: &Inner, req: &Message) -> Option<Message> {
+        if !config.metadata.refuse_any {
+            return None;
+        }
+        let query = req
+            .queries()
+            .iter()
+            .find(|it| it.query_type() == RecordType::ANY)?;
+        let answer = Record::from_rdata(
+            query.name().clone(),
+            3600,
+            RData::HINFO(rdata::HINFO::new("RFC8482".to_string(), String::new())),
+        );
+        let mut res = response_to(req, ResponseCode::NoError);
+        res.add_answer(answer);
+        Some(res)
+    }
+    /// 根域及顶级域查询按 root-query-policy 应答；配置搜索域时单标签域名会被补全，不视为顶级域
+    fn root_query(config: &Inner, req: &Message) -> Option<Message> {
+        let code = match config.metadata.root_query_policy {
+            RootQueryPolicy::Forward => return None,
+            RootQueryPolicy::Refuse => ResponseCode::Refused,
+            RootQueryPolicy::NoData => ResponseCode::NoError,
+        };
+        let max_labels = if config.metadata.search_domain.is_some() { 0 } else { 1 };
+        req.queries()
+            .iter()
+            .any(|it| it.name().num_labels() <= max_labels)
+            .then(|| response_to(req, code))
     }
     const PTR_IPV4_SUFFIX: &'static str = ".in-addr.arpa.";
     const PTR_IPV6_SUFFIX: &'static str = ".ip6.arpa.";
-    async fn resolve_from_hosts(&self, req: &Message) -> anyhow::Result<Option<Message>> {
+    async fn resolve_from_hosts(
+        &self,
+        config: &Inner,
+        req: &Message,
+    ) -> anyhow::Result<Option<Message>> {
         let mut answers = Vec::<Record>::new();
+        let mut nxdomain = false;
+        let mut nodata = false;
         for query in req.queries() {
             let name = query.name().to_utf8();
             match query.query_type() {
                 RecordType::PTR => {
-                    self.local_reverse_dns_query(&name, query, &mut answers)?;
+                    let addr = match Self::parse_ptr_name(&name)? {
+                        Some(addr) => addr,
+                        None => continue,
+                    };
+                    if !self.local_reverse_dns_query(config, addr, query, &mut answers)?
+                        && config.metadata.is_local_ptr(&addr)
+                    {
+                        nxdomain = true;
+                    }
                 }
                 RecordType::A => {
-                    let addrs = self
-                        .config
-                        .access()
+                    let addrs = config
                         .get_hosts(&self.group, &name)?
                         .into_iter()
                         .filter_map(|it| match it {
@@ -155,41 +663,65 @@ impl Handler {
                         Record::new()
                             .set_name(name.clone())
                             .set_record_type(RecordType::A)
-                            .set_ttl(1)
+                            .set_ttl(config.hosts_ttl(&self.group))
                             .set_data(Some(RData::A(rdata::A(it))))
                             .to_owned()
                     }))
                 }
                 RecordType::AAAA => {
-                    let addrs = self
-                        .config
-                        .access()
-                        .get_hosts(&self.group, &name)?
-                        .into_iter()
+                    let hosts = config.get_hosts(&self.group, &name)?;
+                    let mut addrs = hosts
+                        .iter()
                         .filter_map(|it| match it {
-                            IpAddr::V6(addr) => Some(addr),
+                            IpAddr::V6(addr) => Some(*addr),
                             IpAddr::V4(_) => None,
                         })
                         .collect::<Vec<_>>();
+                    let name = Name::from_ascii(name)?;
+                    // 没有 AAAA 时按 synth6 规则由 A 记录合成
+                    if let (true, Some(prefix)) =
+                        (addrs.is_empty(), config.get_synth6(&self.group, &name))
+                    {
+                        addrs.extend(hosts.iter().filter_map(|it| match it {
+                            IpAddr::V4(addr) => Some(prefix.embed(*addr)),
+                            IpAddr::V6(_) => None,
+                        }));
+                    }
                     if addrs.is_empty() {
                         continue;
                     };
-                    let name = Name::from_ascii(name)?;
                     answers.extend(addrs.into_iter().map(|it| {
                         Record::new()
                             .set_name(name.clone())
                             .set_record_type(RecordType::AAAA)
-                            .set_ttl(1)
+                            .set_ttl(config.hosts_ttl(&self.group))
                             .set_data(Some(RData::AAAA(rdata::AAAA(it))))
                             .to_owned()
                     }))
                 }
-                _ => continue,
+                _ => {
+                    // 仅本地可见的域名不转发其它类型的查询，避免泄露内部域名
+                    if config.metadata.local_only_names
+                        && !config.get_hosts(&self.group, &name)?.is_empty()
+                    {
+                        nodata = true;
+                    }
+                }
             }
         }
-        if answers.is_empty() {
+        if answers.is_empty() && nxdomain {
+            // 私有地址的反向查询不转发到上游
+            Ok(Some(negative_response(config, req, ResponseCode::NXDomain)))
+        } else if answers.is_empty() && nodata {
+            Ok(Some(
+                negative_response(config, req, ResponseCode::NoError)
+                    .set_authoritative(true)
+                    .to_owned(),
+            ))
+        } else if answers.is_empty() {
             Ok(None)
         } else {
+            dedup_answers(&mut answers);
             Ok(Some(
                 req.to_owned()
                     .set_message_type(MessageType::Response)
@@ -198,13 +730,8 @@ impl Handler {
             ))
         }
     }
-    fn local_reverse_dns_query(
-        &self,
-        name: &str,
-        query: &Query,
-        answers: &mut Vec<Record>,
-    ) -> anyhow::Result<()> {
-        // 反查 Ip addr
+    /// 解析反向查询的域名，返回对应的 IP 地址
+    fn parse_ptr_name(name: &str) -> anyhow::Result<Option<IpAddr>> {
         let addr = if let Some(addr) = name.strip_suffix(Self::PTR_IPV4_SUFFIX) {
             let parts = addr
                 .split('.')
@@ -215,12 +742,18 @@ impl Handler {
                 })
                 .rev()
                 .collect::<Result<Vec<_>, _>>()?;
+            if parts.len() != 4 {
+                tracing::warn!("Ignore malformed IPv4 PTR name: {}", name);
+                return Ok(None);
+            }
             IpAddr::from(Ipv4Addr::new(parts[0], parts[1], parts[2], parts[3]))
         } else if let Some(addr) = name.strip_suffix(Self::PTR_IPV6_SUFFIX) {
-            let parts = addr
-                .split('.')
-                .rev()
-                .collect::<Vec<_>>()
+            let nibbles = addr.split('.').rev().collect::<Vec<_>>();
+            if nibbles.len() != 32 || nibbles.iter().any(|it| it.len() != 1) {
+                tracing::warn!("Ignore malformed IPv6 PTR name: {}", name);
+                return Ok(None);
+            }
+            let parts = nibbles
                 .chunks(4)
                 .map(|it| {
                     u16::from_str_radix(&it.join(""), 16).with_context(|| {
@@ -232,40 +765,82 @@ impl Handler {
                 parts[0], parts[1], parts[2], parts[3], parts[4], parts[5], parts[6], parts[7],
             ))
         } else {
-            return Ok(());
+            return Ok(None);
         };
-        if let Some(hostname) = self.config.access().get_hostname(&self.group, addr) {
+        Ok(Some(addr))
+    }
+    /// 反查 Ip addr，返回是否已在本地应答
+    fn local_reverse_dns_query(
+        &self,
+        config: &Inner,
+        addr: IpAddr,
+        query: &Query,
+        answers: &mut Vec<Record>,
+    ) -> anyhow::Result<bool> {
+        if let Some(hostname) = config.get_hostname(&self.group, addr) {
             answers.push(
                 Record::new()
                     .set_name(query.name().to_owned())
                     .set_record_type(RecordType::PTR)
+                    .set_ttl(config.hosts_ttl(&self.group))
                     .set_data(Some(RData::PTR(rdata::PTR(Name::from_ascii(hostname)?))))
                     .to_owned(),
             );
+            return Ok(true);
         }
-        Ok(())
+        Ok(false)
     }
+    const MAX_CACHED_CNAME_HOPS: usize = 8;
     fn lookup_dns_cache(&mut self, req: &Message) -> anyhow::Result<Option<Message>> {
-        let mut guard = match self.cache.access()? {
-            Some(guard) => guard,
-            None => return Ok(None),
-        };
+        if !self.cache.enabled() {
+            return Ok(None);
+        }
         let mut answers = Vec::new();
         for query in req.queries() {
-            let name = query.name().to_utf8();
-            let qtype = query.query_type();
-            match qtype {
-                RecordType::A | RecordType::AAAA => {
-                    if let Some(records) = guard.get(&name, qtype) {
-                        answers.extend(records)
-                    };
+            let (qtype, class) = (query.query_type(), query.query_class());
+            let mut name = query.name().to_lowercase();
+            let key = self.cache_key(&name);
+            if let Some(mut guard) = self.cache.access(&key)? {
+                if let Some((code, soa)) = guard.get_negative(&key, qtype, class) {
+                    let mut res = response_to(req, code);
+                    res.add_name_server(soa);
+                    return Ok(Some(res));
                 }
-                _ => continue,
+            }
+            // 沿缓存中的 CNAME 链查找目标记录，链不完整时不使用缓存
+            let mut chain = Vec::new();
+            for _ in 0..Self::MAX_CACHED_CNAME_HOPS {
+                let key = self.cache_key(&name);
+                let mut guard = match self.cache.access(&key)? {
+                    Some(guard) => guard,
+                    None => break,
+                };
+                let records = guard.get(&key, qtype, class).unwrap_or_default();
+                if !records.is_empty() {
+                    chain.extend(records);
+                    answers.append(&mut chain);
+                    break;
+                }
+                let target = match guard
+                    .get(&key, RecordType::CNAME, class)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .next()
+                {
+                    Some(record) if qtype != RecordType::CNAME => record,
+                    _ => break,
+                };
+                name = match target.data() {
+                    Some(RData::CNAME(rdata::CNAME(target))) => target.to_lowercase(),
+                    _ => break,
+                };
+                chain.push(target);
             }
         }
         if answers.is_empty() {
             Ok(None)
         } else {
+            dedup_answers(&mut answers);
             Ok(Some(
                 req.to_owned()
                     .set_message_type(MessageType::Response)
@@ -274,73 +849,264 @@ impl Handler {
             ))
         }
     }
-    async fn forward_dns_query(&mut self, req: &Message, bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
-        let config = self.config.access();
-        let server = config.get_server(&self.group);
-        let now = Instant::now();
-        let mut opts = ResolveOpts{
-            max_payload_size: 4096
+    async fn forward_dns_query(
+        &mut self,
+        config: &Inner,
+        req: &Message,
+        bytes: &[u8],
+    ) -> anyhow::Result<Vec<u8>> {
+        let reverse = req.queries().iter().any(|it| {
+            it.query_type() == RecordType::PTR
+                && matches!(
+                    Self::parse_ptr_name(&it.name().to_utf8()),
+                    Ok(Some(addr)) if is_public_addr(&addr)
+                )
+        });
+        let servers = config.get_server(&self.group, reverse);
+        let mut opts = ResolveOpts {
+            udp_retries: config.metadata.udp_retries,
+            udp_retry_timeout: config.metadata.udp_retry_timeout,
+            tcp_fallback: config.metadata.tcp_fallback,
+            // 经 TCP 或 unix socket 收到的查询可能超出 UDP 应答大小，直接以 TCP 转发
+            prefer_tcp: self.protocol != "udp",
+            edns_cookies: config.metadata.edns_cookies,
+            connect_timeout: config.metadata.connect_timeout,
+            tcp_fastopen: config.metadata.tcp_fastopen,
+            dot_max_inflight: config.metadata.dot_max_inflight,
+            tls: TlsOpts {
+                keylog: config.metadata.tls_keylog,
+                min_version: config.metadata.tls_min_version,
+                cipher_suites: config.metadata.tls_cipher_suites.clone(),
+                ca_certs: config.metadata.ca_certs.clone(),
+                ..TlsOpts::default()
+            },
+            ..ResolveOpts::default()
         };
         if let Some(ext) = req.extensions(){
             opts.max_payload_size = ext.max_payload() as usize
         }
-        tokio::select! {
-            res = resolve(&server[0], bytes, opts) =>  {
-                Ok(res?)
-            },
-            _ = tokio::time::sleep(self.timeout) => {
-                anyhow::bail!("query upstream server timeout, elapsed {}ms", now.elapsed().as_millis())
+        let first = match config.metadata.upstream_strategy {
+            UpstreamStrategy::Sequential => 0,
+            UpstreamStrategy::Sticky => sticky_upstream_index(&self.addr.ip(), servers.len()),
+        };
+        let mut last_err = None;
+        // 开启 retry-on-servfail 时最后一个 SERVFAIL/REFUSED 应答，所有上游均失败时返回
+        let mut soft_failure = None;
+        // 所有上游共享同一个截止时间，总耗时不超过 timeout
+        let now = Instant::now();
+        let deadline = now + self.timeout;
+        // 按顺序尝试上游服务器，失败时切换到下一个
+        for offset in 0..servers.len() {
+            let server = &servers[(first + offset) % servers.len()];
+            let attempt = Instant::now();
+            let res = tokio::select! {
+                res = resolve(server, bytes, &opts) => res,
+                _ = tokio::time::sleep_until(deadline) => {
+                    if let Some(res) = soft_failure {
+                        return Ok(res);
+                    }
+                    return Err(anyhow::format_err!("query upstream server timeout, elapsed {}ms", now.elapsed().as_millis())
+                        .context(format!("Upstream server '{}' failed", server)));
+                }
+            };
+            match res {
+                Ok(res) => {
+                    STATS.record_upstream(attempt.elapsed());
+                    if config.metadata.retry_on_servfail {
+                        let code = Message::from_vec(&res).map(|it| it.response_code());
+                        if let Ok(code @ (ResponseCode::ServFail | ResponseCode::Refused)) = code {
+                            tracing::warn!("Upstream server '{}' responded {}", server, code);
+                            soft_failure = Some(res);
+                            continue;
+                        }
+                    }
+                    return Ok(res);
+                }
+                Err(err) => {
+                    tracing::warn!("Upstream server '{}' failed: {}", server, err);
+                    last_err = Some(err.context(format!("Upstream server '{}' failed", server)));
+                }
             }
         }
+        if let Some(res) = soft_failure {
+            return Ok(res);
+        }
+        Err(last_err.unwrap_or_else(|| anyhow::format_err!("No upstream server available")))
     }
-    async fn resolution(&self, message: &mut Message) -> anyhow::Result<()> {
+    /// 按 ipv6_resolution 过滤 AAAA 记录，返回是否所有 AAAA 记录均被过滤
+    async fn resolution(&self, config: &Arc<Inner>, message: &mut Message) -> anyhow::Result<bool> {
         let answers = message.answers_mut();
-        let mut tasks = Vec::new();
-        let config = self.config.access();
-        for answer in answers.iter() {
-            if let Some(RData::AAAA(rdata::AAAA(addr))) = answer.data() {
-                let domain = answer.name().clone();
-                let group = self.group.clone();
-                let addr = IpAddr::from(addr.to_owned());
-                let config = config.clone();
-                tasks.push(tokio::spawn(async move {
-                    config.is_allow_ipv6(&group, &domain, addr).await
-                }));
-            } else {
-                tasks.push(tokio::spawn(async move { true }))
-            }
-        }
-        let allows = futures::future::join_all(tasks)
-            .await
-            .into_iter()
-            .map(|it| it.unwrap_or(false))
+        // CNAME 目标 -> 别名，用于将地址记录回溯到查询的域名
+        let aliases = answers
+            .iter()
+            .filter_map(|it| match it.data() {
+                Some(RData::CNAME(rdata::CNAME(target))) => Some((target.clone(), it.name().clone())),
+                _ => None,
+            })
+            .collect::<HashMap<_, _>>();
+        // 仅 AAAA 记录需要检查，其余记录直接保留
+        let checks = answers
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, answer)| match answer.data() {
+                Some(RData::AAAA(rdata::AAAA(addr))) => {
+                    let mut domain = answer.name().clone();
+                    let mut hops = 0;
+                    while let Some(alias) = aliases.get(&domain) {
+                        // 防止 CNAME 环
+                        hops += 1;
+                        if hops > aliases.len() {
+                            break;
+                        }
+                        domain = alias.clone();
+                    }
+                    Some((idx, domain, IpAddr::from(addr.to_owned())))
+                }
+                _ => None,
+            })
             .collect::<Vec<_>>();
+        let has_ipv6 = !checks.is_empty();
+        let denied = run_bounded(
+            checks,
+            config.metadata.resolution_concurrency,
+            |(idx, domain, addr)| async move {
+                let rule = config.ipv6_denied_by(&self.group, &domain, addr).await?;
+                // 丢弃记录会改变应答，即使只输出慢查询摘要也记录原因
+                if self.access_log {
+                    tracing::trace!("[--](F) Dropped AAAA {} {} by {}", domain, addr, rule);
+                }
+                Some(idx)
+            },
+        )
+        .await
+        .into_iter()
+        .flatten()
+        .collect::<HashSet<_>>();
         *answers = answers
             .drain(..)
             .enumerate()
-            .filter_map(
-                |(idx, record)| {
-                    if allows[idx] {
-                        Some(record)
-                    } else {
-                        None
-                    }
-                },
-            )
+            .filter_map(|(idx, record)| (!denied.contains(&idx)).then_some(record))
             .collect();
-        Ok(())
+        Ok(has_ipv6 && !answers.iter().any(|it| it.record_type() == RecordType::AAAA))
     }
-    fn cache_dns_record(&self, _message: &Message) -> anyhow::Result<()> {
+    /// 缓存中的应答已经过分组的上游选择、ipv6_resolution 过滤及改写，按分组区分缓存键
+    fn cache_key(&self, name: &Name) -> String {
+        format!("{} {}", self.group, name.to_lowercase().to_utf8())
+    }
+    fn cache_dns_record(&self, config: &Inner, req: &Message, res: &Message) -> anyhow::Result<()> {
         if !self.cache.enabled() {
             return Ok(());
         }
-        todo!();
+        let query = match req.queries().first() {
+            Some(query) => query,
+            None => return Ok(()),
+        };
+        let excluded = &config.metadata.cache_exclude_types;
+        if excluded.contains(&query.query_type()) {
+            return Ok(());
+        }
+        let name = self.cache_key(query.name());
+        let soa = res.name_servers().iter().find_map(|it| match it.data() {
+            Some(RData::SOA(soa)) => Some((it, soa.minimum())),
+            _ => None,
+        });
+        match (res.response_code(), soa) {
+            (ResponseCode::NoError, _) if !res.answers().is_empty() => {
+                // 仅缓存查询名称及由其出发的 CNAME 链上的记录，丢弃应答中夹带的无关记录
+                let owners = cname_chain(query.name(), res.answers());
+                let mut records = HashMap::<String, Vec<Record>>::new();
+                for answer in res.answers() {
+                    if excluded.contains(&answer.record_type())
+                        || !owners.contains(&answer.name().to_lowercase())
+                    {
+                        continue;
+                    }
+                    records
+                        .entry(self.cache_key(answer.name()))
+                        .or_default()
+                        .push(answer.clone());
+                }
+                for (name, records) in records {
+                    if let Some(mut guard) = self.cache.access(&name)? {
+                        guard.put(name, &records);
+                    }
+                }
+            }
+            (code @ (ResponseCode::NoError | ResponseCode::NXDomain), Some((soa, minimum))) => {
+                // RFC 2308: 否定应答的 TTL 取 SOA 记录 TTL 与 MINIMUM 中较小者
+                let ttl = soa.ttl().min(minimum).clamp(
+                    config.metadata.negative_cache_min_ttl,
+                    config.metadata.negative_cache_max_ttl,
+                );
+                let rtype = match code {
+                    ResponseCode::NXDomain => None,
+                    _ => Some(query.query_type()),
+                };
+                if let Some(mut guard) = self.cache.access(&name)? {
+                    guard.put_negative(name, query.query_class(), rtype, soa.clone(), ttl);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+    /// 轮转 A/AAAA 记录的顺序，使客户端的连接分散到各个地址
+    fn rotate_answers(&self, config: &Inner, res: &mut Message) {
+        if !config.metadata.rotate_answers {
+            return;
+        }
+        let answers = res.answers_mut();
+        let (mut addrs, others): (Vec<_>, Vec<_>) = answers
+            .drain(..)
+            .partition(|it| matches!(it.record_type(), RecordType::A | RecordType::AAAA));
+        if !addrs.is_empty() {
+            let offset = ROTATION.fetch_add(1, Ordering::Relaxed) % addrs.len();
+            addrs.rotate_left(offset);
+        }
+        answers.extend(others);
+        answers.extend(addrs);
+    }
+    /// 未设置 slow-query-threshold 时在处理过程中即时输出访问日志
+    fn verbose_log(&self) -> bool {
+        self.access_log && self.slow_query_threshold.is_none()
+    }
+    fn print_dns_query_header(&self, req: &Message) {
+        tracing::trace!(
+            "----[IP: {protocol}://{addr}]#{id:0>5} [GROUP: {group}]------------------------------------------",
+            protocol = self.protocol,
+            addr = self.addr.ip(),
+            id = req.id(),
+            group = self.group,
+        );
+        tracing::trace!(
+            "[->](Q) Queries: {}",
+            format_queries(req.queries(), req.extensions().is_some())
+        );
     }
-    fn print_dns_query_detail(&self, stage: char, _req: &Message, res: &Message) {
+    fn print_dns_query_detail(&self, stage: char, req: &Message, res: &Message) {
+        if !self.access_log {
+            return;
+        }
+        let elapsed = self.start.elapsed();
+        match self.slow_query_threshold {
+            // 未超过阈值的查询仅输出一行摘要
+            Some(threshold) if elapsed <= threshold => {
+                tracing::trace!(
+                    "[<-:{}ms]({stage}) {}://{} {}",
+                    elapsed.as_millis(),
+                    self.protocol,
+                    self.addr.ip(),
+                    format_queries(req.queries(), req.extensions().is_some())
+                );
+                return;
+            }
+            Some(_) => self.print_dns_query_header(req),
+            None => {}
+        }
         let indent = " ".repeat(41);
         tracing::trace!(
             "[<-:{}ms]({stage}) Answers: {}",
-            self.start.elapsed().as_millis(),
+            elapsed.as_millis(),
             format_answers(&indent, res.answers())
         );
     }
@@ -352,14 +1118,184 @@ impl Handler {
     }
 }
 
-pub(crate) fn format_err(err: anyhow::Error, indent: usize) -> String {
-    let ind = " ".repeat(indent);
-    format!(
-        "{}\n{}",
-        err,
-        err.chain()
-            .skip(1)
-            .fold(String::new(), |mut output, it| {
+/// 构造一个不含记录的本地应答
+fn response_to(req: &Message, code: ResponseCode) -> Message {
+    let mut res = Message::new();
+    res.set_id(req.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(req.op_code())
+        .set_recursion_desired(req.recursion_desired())
+        .set_recursion_available(true)
+        .set_response_code(code)
+        .add_queries(req.queries().to_vec());
+    if let Some(edns) = req.extensions() {
+        res.set_edns(edns.clone());
+    }
+    res
+}
+
+/// 以不超过 limit 的并发数执行任务，结果保持输入顺序
+async fn run_bounded<I, F, Fut>(items: I, limit: usize, f: F) -> Vec<Fut::Output>
+where
+    I: IntoIterator,
+    F: FnMut(I::Item) -> Fut,
+    Fut: Future,
+{
+    futures::stream::iter(items)
+        .map(f)
+        .buffered(limit.max(1))
+        .collect()
+        .await
+}
+
+/// 为本地区域生成的 SOA 记录
+fn local_soa(zone: &Name, ttl: u32, minimum: u32) -> Record {
+    let mname = Name::from_ascii("localhost.").unwrap();
+    let rname = Name::from_ascii("hostmaster")
+        .unwrap()
+        .append_domain(zone)
+        .unwrap_or_else(|_| zone.clone());
+    Record::from_rdata(
+        zone.clone(),
+        ttl,
+        RData::SOA(rdata::SOA::new(mname, rname, 1, 3600, 600, 86400, minimum)),
+    )
+}
+
+/// 本地生成的 NXDOMAIN/NODATA 应答，权威部分附带 SOA 以便客户端缓存否定应答
+fn negative_response(config: &Inner, req: &Message, code: ResponseCode) -> Message {
+    let mut res = response_to(req, code);
+    if let Some(query) = req.queries().first() {
+        let zone = config
+            .metadata
+            .local_zone(query.name())
+            .cloned()
+            .unwrap_or_else(|| query.name().base_name());
+        let ttl = config.metadata.local_negative_ttl;
+        res.add_name_server(local_soa(&zone, ttl, ttl));
+    }
+    res
+}
+
+/// RFC 8914 Extended DNS Errors 选项及使用的错误码
+const EDE_OPTION: u16 = 15;
+const EDE_BLOCKED: u16 = 15;
+const EDE_FILTERED: u16 = 17;
+const EDE_NETWORK_ERROR: u16 = 23;
+
+/// 客户端使用 EDNS 时在应答中附带扩展错误码及说明
+fn set_extended_error(req: &Message, res: &mut Message, code: u16, text: &str) {
+    let Some(edns) = req.extensions() else {
+        return;
+    };
+    let mut data = code.to_be_bytes().to_vec();
+    data.extend_from_slice(text.as_bytes());
+    res.extensions_mut()
+        .get_or_insert_with(|| edns.clone())
+        .options_mut()
+        .insert(EdnsOption::Unknown(EDE_OPTION, data));
+}
+
+/// RFC 7828 edns-tcp-keepalive 选项
+pub const EDNS_TCP_KEEPALIVE: u16 = 11;
+
+/// 客户端通过流式连接查询并携带 edns-tcp-keepalive 时，在应答中告知连接的空闲超时
+fn set_tcp_keepalive(protocol: &str, req: &Message, res: &mut Message, timeout: Duration) {
+    if protocol == "udp" {
+        return;
+    }
+    let requested = req
+        .extensions()
+        .as_ref()
+        .is_some_and(|it| it.option(EdnsCode::from(EDNS_TCP_KEEPALIVE)).is_some());
+    if !requested {
+        return;
+    }
+    let timeout = (timeout.as_millis() / 100) as u16;
+    if let Some(edns) = res.extensions_mut() {
+        let options = edns.options_mut();
+        options.remove(EdnsCode::from(EDNS_TCP_KEEPALIVE));
+        options.insert(EdnsOption::Unknown(EDNS_TCP_KEEPALIVE, timeout.to_be_bytes().to_vec()));
+    }
+}
+
+/// 查询名称及应答中由其出发的 CNAME 链经过的名称（小写）
+fn cname_chain(qname: &Name, answers: &[Record]) -> HashSet<Name> {
+    let mut owners = HashSet::from([qname.to_lowercase()]);
+    // 应答中 CNAME 的顺序不一定与链一致，重复扫描直到不再增加
+    loop {
+        let len = owners.len();
+        for answer in answers {
+            if let Some(RData::CNAME(rdata::CNAME(target))) = answer.data() {
+                if owners.contains(&answer.name().to_lowercase()) {
+                    owners.insert(target.to_lowercase());
+                }
+            }
+        }
+        if owners.len() == len {
+            return owners;
+        }
+    }
+}
+
+/// 去除名称、类型及数据均相同的重复记录，保留首次出现的记录
+fn dedup_answers(answers: &mut Vec<Record>) {
+    let mut seen = Vec::with_capacity(answers.len());
+    answers.retain(|it| {
+        let key = (it.name().to_lowercase(), it.record_type(), it.data().cloned());
+        if seen.contains(&key) {
+            return false;
+        }
+        seen.push(key);
+        true
+    });
+}
+
+/// 在 [min, max] 范围内随机选取时长
+fn jitter(min: Duration, max: Duration) -> Duration {
+    if max <= min {
+        return min;
+    }
+    let span = (max - min).as_millis() as u64 + 1;
+    min + Duration::from_millis(RandomState::new().build_hasher().finish() % span)
+}
+
+/// 判断是否为公网地址
+fn is_public_addr(addr: &IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(addr) => {
+            !(addr.is_private()
+                || addr.is_loopback()
+                || addr.is_link_local()
+                || addr.is_unspecified())
+        }
+        IpAddr::V6(addr) => {
+            !(addr.is_loopback()
+                || addr.is_unique_local()
+                || addr.is_unicast_link_local()
+                || addr.is_unspecified())
+        }
+    }
+}
+
+/// 根据客户端 IP 选择固定的上游服务器下标
+fn sticky_upstream_index(addr: &IpAddr, len: usize) -> usize {
+    if len == 0 {
+        return 0;
+    }
+    let mut hasher = DefaultHasher::new();
+    addr.hash(&mut hasher);
+    (hasher.finish() % len as u64) as usize
+}
+
+pub(crate) fn format_err(err: anyhow::Error, indent: usize) -> String {
+    let ind = " ".repeat(indent);
+    format!(
+        "{}\n{}",
+        err,
+        err.chain()
+            .skip(1)
+            .fold(String::new(), |mut output, it| {
                 let _ = writeln!(output, "{ind}Caused by: {it}");
                 output
             })
@@ -383,6 +1319,21 @@ fn format_queries(queries: &[Query], edns: bool) -> String {
         .join("; ")
 }
 
+/// 以十六进制输出报文，仅在日志实际写出时格式化
+struct Hex<'a>(&'a [u8]);
+
+impl std::fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_char(' ')?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
 fn format_answers(indent: &str, answers: &[Record]) -> String {
     let items = answers
         .iter()
@@ -419,3 +1370,1917 @@ fn format_answers(indent: &str, answers: &[Record]) -> String {
             .to_string()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logs::capture::capture_logs;
+    use hickory_proto::op::Query;
+    use std::str::FromStr;
+    use std::sync::Mutex;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UdpSocket;
+
+    struct MockUpstream {
+        addr: String,
+        hits: Arc<AtomicUsize>,
+    }
+
+    impl std::fmt::Display for MockUpstream {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str(&self.addr)
+        }
+    }
+
+    /// 启动一个模拟上游，由 respond 根据请求生成应答
+    async fn spawn_responder<F>(respond: F) -> MockUpstream
+    where
+        F: Fn(&Message) -> Message + Send + 'static,
+    {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        tokio::spawn(async move {
+            let mut buf = [0; 4096];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let req = Message::from_bytes(&buf[..len]).unwrap();
+                let res = respond(&req);
+                let _ = socket.send_to(&res.to_vec().unwrap(), peer).await;
+            }
+        });
+        MockUpstream { addr, hits }
+    }
+
+    /// 启动一个模拟上游，对所有 A 查询返回指定的地址
+    async fn spawn_upstream(answers: &[Ipv4Addr]) -> MockUpstream {
+        let answers = answers.to_vec();
+        spawn_responder(move |req| {
+            let mut res = req.clone();
+            res.set_message_type(MessageType::Response);
+            for query in req.queries() {
+                if query.query_type() == RecordType::A {
+                    res.add_answers(answers.iter().map(|it| {
+                        Record::from_rdata(query.name().clone(), 300, RData::A(rdata::A(*it)))
+                    }));
+                }
+            }
+            res
+        })
+        .await
+    }
+
+    /// 在临时目录写入测试文件
+    fn temp_file(name: &str, content: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("pomelo-{}-{}", std::process::id(), name));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn build_query(name: &str, rtype: RecordType) -> Message {
+        let mut req = Message::new();
+        req.set_id(0x1234)
+            .set_recursion_desired(true)
+            .add_query(Query::query(Name::from_ascii(name).unwrap(), rtype));
+        req
+    }
+
+    async fn exchange(config: &Arc<Config>, client: &str, req: &Message) -> Option<Message> {
+        let addr = client.parse::<SocketAddr>().unwrap();
+        let group = config.access().attribute_group(&addr.ip());
+        let mut handler = Handler::new(
+            "udp",
+            addr,
+            group,
+            Arc::new(Cache::with_capacity(0)),
+            config.clone(),
+        );
+        let output = Arc::new(Mutex::new(None));
+        let ret = {
+            let output = output.clone();
+            |bytes: Vec<u8>, _addr| async move {
+                *output.lock().unwrap() = Some(bytes);
+                Ok(())
+            }
+        };
+        handler.run(req.to_vec().unwrap(), ret).await;
+        let bytes = output.lock().unwrap().take()?;
+        Some(Message::from_bytes(&bytes).unwrap())
+    }
+
+    fn answer_addrs(res: &Message) -> Vec<IpAddr> {
+        res.answers()
+            .iter()
+            .filter_map(|it| match it.data() {
+                Some(RData::A(rdata::A(addr))) => Some(IpAddr::from(*addr)),
+                Some(RData::AAAA(rdata::AAAA(addr))) => Some(IpAddr::from(*addr)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn sticky_upstream_strategy() {
+        let first = spawn_upstream(&[Ipv4Addr::new(10, 0, 0, 1)]).await;
+        let second = spawn_upstream(&[Ipv4Addr::new(10, 0, 0, 2)]).await;
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {first}, {second}\n[metadata]\nupstream-strategy sticky\n"
+            ))
+            .unwrap(),
+        );
+        let req = build_query("example.com.", RecordType::A);
+        let a = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        let b = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(answer_addrs(&a), answer_addrs(&b));
+
+        let index = sticky_upstream_index(&"127.0.0.1".parse().unwrap(), 2);
+        let other = (2..=255)
+            .map(|it| format!("127.0.0.{it}"))
+            .find(|it| sticky_upstream_index(&it.parse().unwrap(), 2) != index)
+            .unwrap();
+        let c = exchange(&config, &format!("{other}:5353"), &req)
+            .await
+            .unwrap();
+        assert_ne!(answer_addrs(&a), answer_addrs(&c));
+    }
+
+    #[tokio::test]
+    async fn retry_on_servfail() {
+        let failing = spawn_responder(|req| response_to(req, ResponseCode::ServFail)).await;
+        let good = spawn_upstream(&[Ipv4Addr::new(10, 0, 0, 2)]).await;
+        let req = build_query("example.com.", RecordType::A);
+        let config = Arc::new(
+            Config::from_text(&format!("[server]\ndefault {failing}, {good}\n")).unwrap(),
+        );
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(res.response_code(), ResponseCode::ServFail);
+        assert_eq!(good.hits.load(Ordering::SeqCst), 0);
+
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {failing}, {good}\n[metadata]\nretry-on-servfail on\n"
+            ))
+            .unwrap(),
+        );
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(res.response_code(), ResponseCode::NoError);
+        assert_eq!(answer_addrs(&res), vec![IpAddr::from([10, 0, 0, 2])]);
+        assert_eq!(failing.hits.load(Ordering::SeqCst), 2);
+
+        // 所有上游均失败时返回最后一个应答
+        let refused = spawn_responder(|req| response_to(req, ResponseCode::Refused)).await;
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {failing}, {refused}\n[metadata]\nretry-on-servfail on\n"
+            ))
+            .unwrap(),
+        );
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(res.response_code(), ResponseCode::Refused);
+    }
+
+    #[tokio::test]
+    async fn force_ttl_rewrites_answers() {
+        let upstream = spawn_upstream(&[Ipv4Addr::new(10, 0, 0, 1)]).await;
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {upstream}\n[metadata]\nforce-ttl 5\n"
+            ))
+            .unwrap(),
+        );
+        let req = build_query("example.com.", RecordType::A);
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert!(!res.answers().is_empty());
+        assert!(res.answers().iter().all(|it| it.ttl() == 5));
+    }
+
+    #[tokio::test]
+    async fn access_log_groups() {
+        let upstream = spawn_upstream(&[Ipv4Addr::new(10, 0, 0, 1)]).await;
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[group]\nloud 127.0.0.2\nquiet 127.0.0.3\n[server]\ndefault {upstream}\n[metadata]\naccess-log-groups loud\n"
+            ))
+            .unwrap(),
+        );
+        let req = build_query("example.com.", RecordType::A);
+        let (_guard, logs) = capture_logs();
+        exchange(&config, "127.0.0.3:5353", &req).await.unwrap();
+        assert!(logs.lines().iter().all(|it| !it.contains("Queries")));
+        exchange(&config, "127.0.0.2:5353", &req).await.unwrap();
+        assert!(logs.lines().iter().any(|it| it.contains("Queries")));
+    }
+
+    #[tokio::test]
+    async fn dump_wire_hexdump() {
+        let config = Arc::new(
+            Config::from_text("[server]\ndefault 127.0.0.1:1\n[hosts]\n10.0.0.1 app.lan\n")
+                .unwrap(),
+        );
+        let req = build_query("app.lan.", RecordType::A);
+        let (_guard, logs) = capture_logs();
+        exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert!(logs.lines().iter().all(|it| !it.contains("(W)")));
+
+        let config = Arc::new(
+            Config::from_text(
+                "[server]\ndefault 127.0.0.1:1\n[hosts]\n10.0.0.1 app.lan\n[metadata]\ndump-wire on\n",
+            )
+            .unwrap(),
+        );
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        let hex = |bytes: Vec<u8>| Hex(&bytes).to_string();
+        let lines = logs.lines();
+        let line = lines.iter().find(|it| it.contains("[->](W)")).unwrap();
+        assert!(line.ends_with(&hex(req.to_vec().unwrap())), "{line}");
+        let line = lines.iter().find(|it| it.contains("[<-](W)")).unwrap();
+        assert!(line.ends_with(&hex(res.to_vec().unwrap())), "{line}");
+    }
+
+    #[tokio::test]
+    async fn response_delay() {
+        let config = |delay: &str| {
+            Config::from_text(&format!(
+                "[server]\ndefault 127.0.0.1:1\n[hosts]\n10.0.0.1 app.lan\n[metadata]\nresponse-delay-ms {delay}\n"
+            ))
+        };
+        assert!(config("80-50").is_err());
+        let req = build_query("app.lan.", RecordType::A);
+        for (delay, min) in [("120", 120), ("60-90", 60)] {
+            let config = Arc::new(config(delay).unwrap());
+            let start = Instant::now();
+            exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+            assert!(start.elapsed() >= Duration::from_millis(min), "{delay}");
+        }
+        for _ in 0..20 {
+            let delay = jitter(Duration::from_millis(60), Duration::from_millis(90));
+            assert!((60..=90).contains(&delay.as_millis()));
+        }
+    }
+
+    #[tokio::test]
+    async fn local_ptr_ranges_nxdomain() {
+        let upstream = spawn_upstream(&[Ipv4Addr::new(10, 0, 0, 1)]).await;
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {upstream}\n[metadata]\nlocal-ptr-ranges 10.0.0.0/8\n"
+            ))
+            .unwrap(),
+        );
+        let req = build_query("4.3.2.10.in-addr.arpa.", RecordType::PTR);
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(res.response_code(), ResponseCode::NXDomain);
+        assert!(res.answers().is_empty());
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn local_only_names_nodata() {
+        let upstream = spawn_upstream(&[Ipv4Addr::new(10, 0, 0, 1)]).await;
+        let text = format!("[server]\ndefault {upstream}\n[hosts]\n10.0.0.2 app.lan\n");
+        let req = build_query("app.lan.", RecordType::SRV);
+
+        let config = Arc::new(Config::from_text(&text).unwrap());
+        exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 1);
+
+        let config =
+            Arc::new(Config::from_text(&format!("{text}[metadata]\nlocal-only-names on\n")).unwrap());
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(res.response_code(), ResponseCode::NoError);
+        assert!(res.authoritative());
+        assert!(res.answers().is_empty());
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn search_domain_expansion() {
+        let forwarded = Arc::new(Mutex::new(Vec::new()));
+        let upstream = {
+            let forwarded = forwarded.clone();
+            spawn_responder(move |req| {
+                let name = req.queries()[0].name().clone();
+                forwarded.lock().unwrap().push(name.to_utf8());
+                let mut res = req.clone();
+                res.set_message_type(MessageType::Response).add_answer(Record::from_rdata(
+                    name,
+                    300,
+                    RData::A(rdata::A(Ipv4Addr::new(10, 0, 0, 1))),
+                ));
+                res
+            })
+            .await
+        };
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {upstream}\n[metadata]\nsearch-domain corp.example\n"
+            ))
+            .unwrap(),
+        );
+        let req = build_query("intranet.", RecordType::A);
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(res.queries()[0].name().to_utf8(), "intranet.");
+        assert_eq!(res.answers()[0].name().to_utf8(), "intranet.");
+        assert_eq!(answer_addrs(&res), vec![IpAddr::from([10, 0, 0, 1])]);
+
+        let req = build_query("www.example.com.", RecordType::A);
+        exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(
+            *forwarded.lock().unwrap(),
+            vec!["intranet.corp.example.", "www.example.com."]
+        );
+    }
+
+    #[tokio::test]
+    async fn chaos_version_query() {
+        let upstream = spawn_upstream(&[Ipv4Addr::new(10, 0, 0, 1)]).await;
+        let mut req = Message::new();
+        req.set_id(0x1234).add_query(
+            Query::query(Name::from_ascii("version.bind.").unwrap(), RecordType::TXT)
+                .set_query_class(DNSClass::CH)
+                .to_owned(),
+        );
+        let config =
+            Arc::new(Config::from_text(&format!("[server]\ndefault {upstream}\n")).unwrap());
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(res.answers().len(), 1);
+        assert_eq!(res.answers()[0].dns_class(), DNSClass::CH);
+        match res.answers()[0].data() {
+            Some(RData::TXT(txt)) => assert_eq!(
+                txt.to_string(),
+                format!("pomelo {}", env!("CARGO_PKG_VERSION"))
+            ),
+            _ => panic!("expected TXT answer"),
+        }
+
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {upstream}\n[metadata]\nchaos-version hidden\n"
+            ))
+            .unwrap(),
+        );
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(res.response_code(), ResponseCode::Refused);
+        assert!(res.answers().is_empty());
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn self_hostname_query() {
+        let upstream = spawn_upstream(&[Ipv4Addr::new(10, 0, 0, 1)]).await;
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {upstream}\n[metadata]\nself-hostname pomelo.lan 192.168.1.2, fd00::2\n"
+            ))
+            .unwrap(),
+        );
+        let req = build_query("Pomelo.LAN.", RecordType::A);
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert!(res.authoritative());
+        assert_eq!(answer_addrs(&res), vec![IpAddr::from([192, 168, 1, 2])]);
+        let req = build_query("pomelo.lan.", RecordType::AAAA);
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(answer_addrs(&res), vec!["fd00::2".parse::<IpAddr>().unwrap()]);
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn status_name_txt() {
+        let upstream = spawn_upstream(&[Ipv4Addr::new(10, 0, 0, 1)]).await;
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {upstream}\n[metadata]\ncache-size 64\nstatus-name _pomelo.status\n"
+            ))
+            .unwrap(),
+        );
+        let mut handler = Handler::new(
+            "udp",
+            "127.0.0.1:5353".parse().unwrap(),
+            "default".to_string(),
+            Arc::new(Cache::with_capacity(64)),
+            config.clone(),
+        );
+        let req = build_query("example.com.", RecordType::A);
+        let bytes = req.to_vec().unwrap();
+        handler.respond(&config.access(), &req, &bytes).await.unwrap();
+
+        let req = build_query("_Pomelo.Status.", RecordType::TXT);
+        let (stage, res) = handler.respond(&config.access(), &req, &[]).await.unwrap();
+        assert_eq!(stage, 'V');
+        assert!(res.authoritative());
+        let fields = res
+            .answers()
+            .iter()
+            .filter_map(|it| match it.data() {
+                Some(RData::TXT(txt)) => Some(txt.to_string()),
+                _ => None,
+            })
+            .map(|it| {
+                let (key, value) = it.split_once('=').unwrap();
+                (key.to_string(), value.to_string())
+            })
+            .collect::<std::collections::HashMap<_, _>>();
+        assert_eq!(fields["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(fields["commit"], env!("COMMIT_ID"));
+        assert!(fields["uptime"].parse::<u64>().is_ok());
+        assert!(fields["queries"].parse::<u64>().is_ok());
+        assert!(fields["cache-hit"].ends_with('%'));
+        assert_eq!(fields["cache-entries"], "1");
+
+        let req = build_query("_pomelo.status.", RecordType::A);
+        let (stage, res) = handler.respond(&config.access(), &req, &[]).await.unwrap();
+        assert_eq!(stage, 'V');
+        assert!(res.answers().is_empty());
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn allow_query_ranges() {
+        let upstream = spawn_upstream(&[Ipv4Addr::new(10, 0, 0, 1)]).await;
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {upstream}\n[metadata]\nallow-query 127.0.0.1, 192.168.0.0/16\n"
+            ))
+            .unwrap(),
+        );
+        let req = build_query("example.com.", RecordType::A);
+        let res = exchange(&config, "10.1.1.1:5353", &req).await.unwrap();
+        assert_eq!(res.response_code(), ResponseCode::Refused);
+        assert!(res.answers().is_empty());
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 0);
+        let res = exchange(&config, "192.168.1.10:5353", &req).await.unwrap();
+        assert_eq!(res.response_code(), ResponseCode::NoError);
+        assert_eq!(answer_addrs(&res), vec![IpAddr::from([10, 0, 0, 1])]);
+    }
+
+    #[tokio::test]
+    async fn unmatched_policy_refuse() {
+        let upstream = spawn_upstream(&[Ipv4Addr::new(10, 0, 0, 1)]).await;
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[group]\nlan 192.168.0.0/16\n[server]\ndefault {upstream}\n[metadata]\nunmatched-policy refuse\n"
+            ))
+            .unwrap(),
+        );
+        let req = build_query("example.com.", RecordType::A);
+        let res = exchange(&config, "10.1.1.1:5353", &req).await.unwrap();
+        assert_eq!(res.response_code(), ResponseCode::Refused);
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 0);
+        let res = exchange(&config, "192.168.1.10:5353", &req).await.unwrap();
+        assert_eq!(res.response_code(), ResponseCode::NoError);
+        assert_eq!(answer_addrs(&res), vec![IpAddr::from([10, 0, 0, 1])]);
+    }
+
+    #[tokio::test]
+    async fn local_zone_soa() {
+        let upstream = spawn_upstream(&[Ipv4Addr::new(10, 0, 0, 1)]).await;
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {upstream}\n[metadata]\nlocal-zones lan home.arpa\nlocal-negative-ttl 120\n"
+            ))
+            .unwrap(),
+        );
+        let req = build_query("LAN.", RecordType::SOA);
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(res.response_code(), ResponseCode::NoError);
+        assert!(res.authoritative());
+        assert_eq!(res.answers().len(), 1);
+        match res.answers()[0].data() {
+            Some(RData::SOA(soa)) => {
+                assert_eq!(soa.mname().to_utf8(), "localhost.");
+                assert_eq!(soa.rname().to_utf8(), "hostmaster.lan.");
+                assert_eq!(soa.minimum(), 120);
+            }
+            _ => panic!("expected SOA answer"),
+        }
+
+        let req = build_query("app.home.arpa.", RecordType::SOA);
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert!(res.answers().is_empty());
+        assert_eq!(res.name_servers()[0].name().to_utf8(), "home.arpa.");
+        assert_eq!(res.name_servers()[0].ttl(), 120);
+
+        let req = build_query("example.com.", RecordType::SOA);
+        exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn reverse_server_for_public_ptr() {
+        let forward = spawn_upstream(&[Ipv4Addr::new(10, 0, 0, 1)]).await;
+        let reverse = spawn_upstream(&[]).await;
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {forward}\n[metadata]\nreverse-server {reverse}\n"
+            ))
+            .unwrap(),
+        );
+        let req = build_query("8.8.8.8.in-addr.arpa.", RecordType::PTR);
+        exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(reverse.hits.load(Ordering::SeqCst), 1);
+        assert_eq!(forward.hits.load(Ordering::SeqCst), 0);
+
+        let req = build_query("1.1.168.192.in-addr.arpa.", RecordType::PTR);
+        exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        let req = build_query("example.com.", RecordType::A);
+        exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(reverse.hits.load(Ordering::SeqCst), 1);
+        assert_eq!(forward.hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn failover_shares_deadline() {
+        let mut dead = Vec::new();
+        for _ in 0..3 {
+            // 只接收不应答的上游
+            dead.push(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        }
+        let servers = dead
+            .iter()
+            .map(|it| it.local_addr().unwrap().to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {servers}\n[metadata]\nquery-timeout 300\nudp-retries 0\nudp-retry-timeout 250\n"
+            ))
+            .unwrap(),
+        );
+        let req = build_query("example.com.", RecordType::A);
+        let start = Instant::now();
+        exchange(&config, "127.0.0.1:5353", &req).await;
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(300), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(600), "{elapsed:?}");
+    }
+
+    #[tokio::test]
+    async fn negative_cache_ttl_bounds() {
+        let upstream = spawn_responder(|req| {
+            let mut res = response_to(req, ResponseCode::NXDomain);
+            let zone = Name::from_ascii("example.com.").unwrap();
+            res.add_name_server(Record::from_rdata(
+                zone.clone(),
+                86400,
+                RData::SOA(rdata::SOA::new(zone.clone(), zone, 1, 3600, 600, 86400, 86400)),
+            ));
+            res
+        })
+        .await;
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {upstream}\n[metadata]\ncache-size 64\nnegative-cache-max-ttl 30\n"
+            ))
+            .unwrap(),
+        );
+        let mut handler = Handler::new(
+            "udp",
+            "127.0.0.1:5353".parse().unwrap(),
+            "default".to_string(),
+            Arc::new(Cache::with_capacity(64)),
+            config.clone(),
+        );
+        let req = build_query("missing.example.com.", RecordType::A);
+        let bytes = req.to_vec().unwrap();
+        let (stage, res) = handler.respond(&config.access(), &req, &bytes).await.unwrap();
+        assert_eq!(stage, 'F');
+        assert_eq!(res.response_code(), ResponseCode::NXDomain);
+
+        let req = build_query("MISSING.example.com.", RecordType::AAAA);
+        let (stage, res) = handler.respond(&config.access(), &req, &[]).await.unwrap();
+        assert_eq!(stage, 'C');
+        assert_eq!(res.response_code(), ResponseCode::NXDomain);
+        assert!(res.name_servers()[0].ttl() <= 30);
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn cache_scoped_to_chain_and_group() {
+        let upstream = spawn_responder(|req| {
+            let mut res = response_to(req, ResponseCode::NoError);
+            let query = &req.queries()[0];
+            let target = Name::from_ascii("cdn.example.com.").unwrap();
+            res.add_answer(Record::from_rdata(
+                query.name().clone(),
+                300,
+                RData::CNAME(rdata::CNAME(target.clone())),
+            ));
+            let data = match query.query_type() {
+                RecordType::AAAA => RData::AAAA(rdata::AAAA("2001:db8::1".parse().unwrap())),
+                _ => RData::A(rdata::A(Ipv4Addr::new(10, 0, 0, 1))),
+            };
+            res.add_answer(Record::from_rdata(target, 300, data));
+            // 夹带的无关记录不应进入缓存
+            res.add_answer(Record::from_rdata(
+                Name::from_ascii("bank.example.").unwrap(),
+                300,
+                RData::A(rdata::A(Ipv4Addr::new(6, 6, 6, 6))),
+            ));
+            res
+        })
+        .await;
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[group]\nv4only 127.0.0.2\n[server]\ndefault {upstream}\n[metadata]\ncache-size 64\n[ipv6_resolution]\ndefault @allow:ALL\nv4only @deny:ALL\n"
+            ))
+            .unwrap(),
+        );
+        let cache = Arc::new(Cache::with_capacity(64));
+        let handler = |group: &str| {
+            Handler::new(
+                "udp",
+                "127.0.0.1:5353".parse().unwrap(),
+                group.to_string(),
+                cache.clone(),
+                config.clone(),
+            )
+        };
+        let (mut default, mut v4only) = (handler("default"), handler("v4only"));
+        let aaaa = |res: &Message| {
+            answer_addrs(res)
+                .into_iter()
+                .filter(IpAddr::is_ipv6)
+                .collect::<Vec<_>>()
+        };
+
+        let req = build_query("www.example.com.", RecordType::AAAA);
+        let bytes = req.to_vec().unwrap();
+        let (stage, res) = v4only.respond(&config.access(), &req, &bytes).await.unwrap();
+        assert_eq!(stage, 'F');
+        assert!(aaaa(&res).is_empty());
+        // 其它分组不会命中被过滤后的应答
+        let (stage, res) = default.respond(&config.access(), &req, &bytes).await.unwrap();
+        assert_eq!(stage, 'F');
+        assert_eq!(aaaa(&res), vec!["2001:db8::1".parse::<IpAddr>().unwrap()]);
+        let (stage, res) = default.respond(&config.access(), &req, &bytes).await.unwrap();
+        assert_eq!(stage, 'C');
+        assert_eq!(aaaa(&res), vec!["2001:db8::1".parse::<IpAddr>().unwrap()]);
+        let (_, res) = v4only.respond(&config.access(), &req, &bytes).await.unwrap();
+        assert!(aaaa(&res).is_empty());
+
+        let req = build_query("bank.example.", RecordType::A);
+        let bytes = req.to_vec().unwrap();
+        let (stage, _) = default.respond(&config.access(), &req, &bytes).await.unwrap();
+        assert_eq!(stage, 'F');
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn hosts_and_cache_ttl() {
+        let upstream = spawn_upstream(&[Ipv4Addr::new(10, 0, 0, 1)]).await;
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {upstream}\n[metadata]\ncache-size 64\nhosts-ttl 42\nforce-ttl 120\n[hosts]\n10.0.0.2 app.lan\n"
+            ))
+            .unwrap(),
+        );
+        let mut handler = Handler::new(
+            "udp",
+            "127.0.0.1:5353".parse().unwrap(),
+            "default".to_string(),
+            Arc::new(Cache::with_capacity(64)),
+            config.clone(),
+        );
+        let ttls = |res: &Message| res.answers().iter().map(|it| it.ttl()).collect::<Vec<_>>();
+
+        let req = build_query("app.lan.", RecordType::A);
+        let (stage, res) = handler.respond(&config.access(), &req, &[]).await.unwrap();
+        assert_eq!(stage, 'L');
+        assert_eq!(ttls(&res), vec![42]);
+
+        let req = build_query("2.0.0.10.in-addr.arpa.", RecordType::PTR);
+        let (stage, res) = handler.respond(&config.access(), &req, &[]).await.unwrap();
+        assert_eq!(stage, 'L');
+        assert_eq!(ttls(&res), vec![42]);
+
+        let req = build_query("example.com.", RecordType::A);
+        let bytes = req.to_vec().unwrap();
+        handler.respond(&config.access(), &req, &bytes).await.unwrap();
+        let (stage, res) = handler.respond(&config.access(), &req, &bytes).await.unwrap();
+        assert_eq!(stage, 'C');
+        assert!(matches!(ttls(&res)[..], [119 | 120]), "{:?}", ttls(&res));
+    }
+
+    #[tokio::test]
+    async fn group_hosts_ttl() {
+        let config = Arc::new(
+            Config::from_text(
+                "[group]\ndynamic 127.0.0.2\nstable 127.0.0.3\n[server]\ndefault 127.0.0.1:1\n[metadata]\nhosts-ttl 42\nhosts-ttl.dynamic 5\nhosts-ttl.stable 3600\n[hosts]\n10.0.0.2 app.lan\n",
+            )
+            .unwrap(),
+        );
+        let req = build_query("app.lan.", RecordType::A);
+        for (group, ttl) in [("dynamic", 5), ("stable", 3600), ("default", 42)] {
+            let mut handler = Handler::new(
+                "udp",
+                "127.0.0.1:5353".parse().unwrap(),
+                group.to_string(),
+                Arc::new(Cache::with_capacity(64)),
+                config.clone(),
+            );
+            let (stage, res) = handler.respond(&config.access(), &req, &[]).await.unwrap();
+            assert_eq!(stage, 'L');
+            assert_eq!(res.answers()[0].ttl(), ttl, "{group}");
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_all_record_types() {
+        let upstream = spawn_responder(|req| {
+            let mut res = response_to(req, ResponseCode::NoError);
+            let query = &req.queries()[0];
+            let name = query.name().clone();
+            let target = Name::from_ascii("mail.example.com.").unwrap();
+            let data = match query.query_type() {
+                RecordType::TXT => RData::TXT(rdata::TXT::new(vec!["v=spf1 -all".to_string()])),
+                RecordType::MX => RData::MX(rdata::MX::new(10, target)),
+                _ => {
+                    // www 为 mail 的别名
+                    res.add_answer(Record::from_rdata(
+                        name,
+                        300,
+                        RData::CNAME(rdata::CNAME(target.clone())),
+                    ));
+                    res.add_answer(Record::from_rdata(
+                        target,
+                        300,
+                        RData::A(rdata::A(Ipv4Addr::new(10, 0, 0, 1))),
+                    ));
+                    return res;
+                }
+            };
+            res.add_answer(Record::from_rdata(name, 300, data));
+            res
+        })
+        .await;
+        let config = Arc::new(
+            Config::from_text(&format!("[server]\ndefault {upstream}\n[metadata]\ncache-size 64\n"))
+                .unwrap(),
+        );
+        let mut handler = Handler::new(
+            "udp",
+            "127.0.0.1:5353".parse().unwrap(),
+            "default".to_string(),
+            Arc::new(Cache::with_capacity(64)),
+            config.clone(),
+        );
+        for (name, rtype) in [
+            ("example.com.", RecordType::TXT),
+            ("example.com.", RecordType::MX),
+            ("www.example.com.", RecordType::A),
+        ] {
+            let req = build_query(name, rtype);
+            let bytes = req.to_vec().unwrap();
+            let (stage, first) = handler.respond(&config.access(), &req, &bytes).await.unwrap();
+            assert_eq!(stage, 'F');
+            let (stage, cached) = handler.respond(&config.access(), &req, &bytes).await.unwrap();
+            assert_eq!(stage, 'C', "{name} {rtype}");
+            let data = |res: &Message| {
+                res.answers()
+                    .iter()
+                    .map(|it| (it.name().clone(), it.data().cloned()))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(data(&cached), data(&first));
+        }
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn all_ipv6_denied_nodata() {
+        let upstream = spawn_responder(|req| {
+            let mut res = req.clone();
+            res.set_message_type(MessageType::Response);
+            let name = req.queries()[0].name().clone();
+            for addr in ["2606:4700::1", "2606:4700::2"] {
+                res.add_answer(Record::from_rdata(
+                    name.clone(),
+                    300,
+                    RData::AAAA(rdata::AAAA(addr.parse().unwrap())),
+                ));
+            }
+            res
+        })
+        .await;
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {upstream}\n[metadata]\nipv6-denied-ttl 5\n[ipv6_resolution]\ndefault @deny:ALL\n"
+            ))
+            .unwrap(),
+        );
+        let req = build_query("example.com.", RecordType::AAAA);
+        let start = Instant::now();
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert_eq!(res.response_code(), ResponseCode::NoError);
+        assert!(res.answers().is_empty());
+        assert_eq!(res.name_servers().len(), 1);
+        assert_eq!(res.name_servers()[0].record_type(), RecordType::SOA);
+        assert_eq!(res.name_servers()[0].ttl(), 5);
+    }
+
+    #[tokio::test]
+    async fn failure_vs_policy_rcode() {
+        let dead = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let blocked = temp_file("failure-blocked.txt", "blocked.example.com\n");
+        let text = format!(
+            "[server]\ndefault {}\n[metadata]\nquery-timeout 200\nblock-response refused\n[blocklist]\nads {}\n",
+            dead.local_addr().unwrap(),
+            blocked.display()
+        );
+        let config = Arc::new(Config::from_text(&text).unwrap());
+        let req = build_query("example.com.", RecordType::A);
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(res.response_code(), ResponseCode::ServFail);
+        assert_eq!(res.id(), req.id());
+        let req = build_query("blocked.example.com.", RecordType::A);
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(res.response_code(), ResponseCode::Refused);
+
+        let config =
+            Arc::new(Config::from_text(&format!("{text}[metadata]\nfailure-response drop\n")).unwrap());
+        let req = build_query("example.com.", RecordType::A);
+        assert!(exchange(&config, "127.0.0.1:5353", &req).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn stats_report() {
+        let upstream = spawn_upstream(&[Ipv4Addr::new(10, 0, 0, 1)]).await;
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {upstream}\n[hosts]\n10.0.0.2 app.lan\n"
+            ))
+            .unwrap(),
+        );
+        exchange(&config, "127.0.0.1:5353", &build_query("app.lan.", RecordType::A)).await;
+        exchange(&config, "127.0.0.1:5353", &build_query("example.com.", RecordType::A)).await;
+        let (_guard, logs) = capture_logs();
+        let task = tokio::spawn(crate::stats::report(Duration::from_millis(20)));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        task.abort();
+        let line = logs
+            .lines()
+            .into_iter()
+            .find(|it| it.contains("Stats:"))
+            .unwrap();
+        assert!(!line.contains("queries=0 "), "{line}");
+        assert!(!line.contains(" L=0"), "{line}");
+        assert!(!line.contains(" F=0"), "{line}");
+    }
+
+    #[tokio::test]
+    async fn resolution_follows_cname() {
+        let upstream = spawn_responder(|req| {
+            let mut res = req.clone();
+            res.set_message_type(MessageType::Response);
+            let name = req.queries()[0].name().clone();
+            let cdn = Name::from_ascii("edge.cdn.net.").unwrap();
+            res.add_answer(Record::from_rdata(name, 300, RData::CNAME(rdata::CNAME(cdn.clone()))));
+            res.add_answer(Record::from_rdata(
+                cdn,
+                300,
+                RData::AAAA(rdata::AAAA("2606:4700::1".parse().unwrap())),
+            ));
+            res
+        })
+        .await;
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {upstream}\n[ipv6_resolution]\ndefault @deny:.example.com, @allow:ALL\n"
+            ))
+            .unwrap(),
+        );
+        let req = build_query("www.example.com.", RecordType::AAAA);
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(res.answers().len(), 1);
+        assert_eq!(res.answers()[0].record_type(), RecordType::CNAME);
+
+        let req = build_query("www.example.org.", RecordType::AAAA);
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(res.answers().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn local_negative_ttl_soa() {
+        let ads = temp_file("negative-ads.txt", "ads.example.com\n");
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault 127.0.0.1:1\n[metadata]\nlocal-negative-ttl 300\nlocal-ptr-ranges 192.168.0.0/16\n[blocklist]\nads {}\n",
+                ads.display()
+            ))
+            .unwrap(),
+        );
+        let req = build_query("www.ads.example.com.", RecordType::A);
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(res.response_code(), ResponseCode::NXDomain);
+        let soa = &res.name_servers()[0];
+        assert_eq!(soa.ttl(), 300);
+        assert_eq!(soa.name(), &Name::from_ascii("ads.example.com.").unwrap());
+        match soa.data() {
+            Some(RData::SOA(soa)) => assert_eq!(soa.minimum(), 300),
+            _ => panic!("expected SOA record"),
+        }
+        // 私有地址的反向查询同样附带 SOA
+        let req = build_query("1.1.168.192.in-addr.arpa.", RecordType::PTR);
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(res.response_code(), ResponseCode::NXDomain);
+        assert_eq!(res.name_servers()[0].ttl(), 300);
+    }
+
+    #[tokio::test]
+    async fn bounded_resolution_checks() {
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let results = run_bounded(0..50, 4, |idx| {
+            let (running, peak) = (running.clone(), peak.clone());
+            async move {
+                let current = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(current, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                idx
+            }
+        })
+        .await;
+        assert_eq!(results, (0..50).collect::<Vec<_>>());
+        assert_eq!(peak.load(Ordering::SeqCst), 4);
+
+        // 大量 AAAA 记录经过规则过滤后保持原有顺序
+        let addrs = (1..=40u16)
+            .map(|it| Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, it))
+            .collect::<Vec<_>>();
+        let upstream = spawn_responder({
+            let addrs = addrs.clone();
+            move |req: &Message| {
+                let mut res = response_to(req, ResponseCode::NoError);
+                let name = req.queries()[0].name().clone();
+                for addr in &addrs {
+                    let data = RData::AAAA(rdata::AAAA(*addr));
+                    res.add_answer(Record::from_rdata(name.clone(), 60, data));
+                }
+                res
+            }
+        })
+        .await;
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {upstream}\n[metadata]\nresolution-concurrency 2\n[ipv6_resolution]\ndefault @deny:deny.example.com, @allow:ALL\n"
+            ))
+            .unwrap(),
+        );
+        let req = build_query("many.example.com.", RecordType::AAAA);
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(
+            answer_addrs(&res),
+            addrs.into_iter().map(IpAddr::from).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn resolution_keeps_non_aaaa_records() {
+        let upstream = spawn_responder(|req: &Message| {
+            let mut res = response_to(req, ResponseCode::NoError);
+            let name = req.queries()[0].name().clone();
+            let records = [
+                RData::A(rdata::A(Ipv4Addr::new(10, 0, 0, 1))),
+                RData::AAAA(rdata::AAAA("2001:db8::1".parse().unwrap())),
+                RData::A(rdata::A(Ipv4Addr::new(10, 0, 0, 2))),
+                RData::AAAA(rdata::AAAA("2001:db8::2".parse().unwrap())),
+            ];
+            for data in records {
+                res.add_answer(Record::from_rdata(name.clone(), 60, data));
+            }
+            res
+        })
+        .await;
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {upstream}\n[ipv6_resolution]\ndefault @deny:ALL\n"
+            ))
+            .unwrap(),
+        );
+        let req = build_query("mixed.example.com.", RecordType::AAAA);
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(
+            answer_addrs(&res),
+            vec![IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 2])]
+        );
+    }
+
+    #[test]
+    fn parse_ipv6_ptr_name() {
+        let name = "b.a.9.8.7.6.5.0.4.0.0.0.3.0.0.0.2.0.0.0.1.0.0.0.0.0.0.0.1.2.3.4.ip6.arpa.";
+        assert_eq!(
+            Handler::parse_ptr_name(name).unwrap(),
+            Some(IpAddr::from_str("4321:0:1:2:3:4:567:89ab").unwrap())
+        );
+        let truncated = "b.a.9.8.7.6.5.0.4.0.0.0.3.ip6.arpa.";
+        assert_eq!(Handler::parse_ptr_name(truncated).unwrap(), None);
+        let malformed = "ba.9.8.7.6.5.0.4.0.0.0.3.0.0.0.2.0.0.0.1.0.0.0.0.0.0.0.1.2.3.4.ip6.arpa.";
+        assert_eq!(Handler::parse_ptr_name(malformed).unwrap(), None);
+    }
+
+    #[test]
+    fn parse_ipv4_ptr_name() {
+        assert_eq!(
+            Handler::parse_ptr_name("4.3.2.1.in-addr.arpa.").unwrap(),
+            Some(IpAddr::from_str("1.2.3.4").unwrap())
+        );
+        assert_eq!(Handler::parse_ptr_name("3.2.1.in-addr.arpa.").unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn malformed_ipv4_ptr_has_no_answer() {
+        let config = Arc::new(
+            Config::from_text("[server]\ndefault 127.0.0.1:1\n[hosts]\n1.2.3.4 router.lan\n")
+                .unwrap(),
+        );
+        let handler = Handler::new(
+            "udp",
+            "127.0.0.1:5353".parse().unwrap(),
+            "default".to_string(),
+            Arc::new(Cache::with_capacity(0)),
+            config,
+        );
+        let req = build_query("3.2.1.in-addr.arpa.", RecordType::PTR);
+        let config = handler.config.access();
+        assert!(handler
+            .resolve_from_hosts(&config, &req)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn rotate_answers() {
+        let addrs = [
+            Ipv4Addr::new(10, 0, 0, 1),
+            Ipv4Addr::new(10, 0, 0, 2),
+            Ipv4Addr::new(10, 0, 0, 3),
+        ];
+        let upstream = spawn_upstream(&addrs).await;
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {upstream}\n[metadata]\nrotate-answers true\n"
+            ))
+            .unwrap(),
+        );
+        let req = build_query("example.com.", RecordType::A);
+        let first = answer_addrs(&exchange(&config, "127.0.0.1:5353", &req).await.unwrap());
+        let second = answer_addrs(&exchange(&config, "127.0.0.1:5353", &req).await.unwrap());
+        assert_eq!(first.len(), 3);
+        assert_ne!(first, second);
+        let mut rotated = first.clone();
+        rotated.rotate_left(1);
+        assert_eq!(rotated, second);
+    }
+
+    #[tokio::test]
+    async fn rotate_hosts_answers() {
+        let config = Arc::new(
+            Config::from_text(
+                "[server]\ndefault 127.0.0.1:1\n[hosts]\n10.0.0.1 app.lan\n10.0.0.2 app.lan\n10.0.0.3 app.lan\n[metadata]\nrotate-answers true\n",
+            )
+            .unwrap(),
+        );
+        let req = build_query("app.lan.", RecordType::A);
+        let hosts = [[10, 0, 0, 1], [10, 0, 0, 2], [10, 0, 0, 3]].map(IpAddr::from);
+        let mut orders = HashSet::new();
+        for _ in 0..4 {
+            let addrs = answer_addrs(&exchange(&config, "127.0.0.1:5353", &req).await.unwrap());
+            // 其它测试可能同时推进轮转计数，只校验结果是 hosts 顺序的某个轮转
+            assert!(
+                (0..hosts.len()).any(|n| {
+                    let mut rotated = hosts.to_vec();
+                    rotated.rotate_left(n);
+                    rotated == addrs
+                }),
+                "{addrs:?}"
+            );
+            orders.insert(addrs);
+        }
+        assert!(orders.len() > 1, "{orders:?}");
+    }
+
+    #[tokio::test]
+    async fn refuse_any_query() {
+        let upstream = spawn_upstream(&[Ipv4Addr::new(10, 0, 0, 1)]).await;
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {upstream}\n[metadata]\nrefuse-any true\n"
+            ))
+            .unwrap(),
+        );
+        let req = build_query("example.com.", RecordType::ANY);
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert!(res.recursion_available());
+        assert_eq!(res.answers().len(), 1);
+        assert_eq!(res.answers()[0].record_type(), RecordType::HINFO);
+        match res.answers()[0].data() {
+            Some(RData::HINFO(hinfo)) => assert_eq!(hinfo.cpu(), b"RFC8482"),
+            _ => panic!("expected HINFO answer"),
+        }
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn root_query_policy() {
+        let upstream = spawn_upstream(&[Ipv4Addr::new(10, 0, 0, 1)]).await;
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {upstream}\n[metadata]\nroot-query-policy refuse\n[hosts]\n10.0.0.2 nas\n"
+            ))
+            .unwrap(),
+        );
+        let res = exchange(&config, "127.0.0.1:5353", &build_query(".", RecordType::NS))
+            .await
+            .unwrap();
+        assert_eq!(res.response_code(), ResponseCode::Refused);
+        let res = exchange(&config, "127.0.0.1:5353", &build_query("com.", RecordType::NS))
+            .await
+            .unwrap();
+        assert_eq!(res.response_code(), ResponseCode::Refused);
+        // hosts 中的单标签域名仍在本地应答
+        let res = exchange(&config, "127.0.0.1:5353", &build_query("nas.", RecordType::A))
+            .await
+            .unwrap();
+        assert_eq!(answer_addrs(&res), vec![IpAddr::from([10, 0, 0, 2])]);
+        let res = exchange(&config, "127.0.0.1:5353", &build_query("example.com.", RecordType::A))
+            .await
+            .unwrap();
+        assert_eq!(res.answers().len(), 1);
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 1);
+
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {upstream}\n[metadata]\nroot-query-policy nodata\n"
+            ))
+            .unwrap(),
+        );
+        let res = exchange(&config, "127.0.0.1:5353", &build_query(".", RecordType::NS))
+            .await
+            .unwrap();
+        assert_eq!(res.response_code(), ResponseCode::NoError);
+        assert!(res.answers().is_empty());
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn oversized_name_refused() {
+        let upstream = spawn_upstream(&[Ipv4Addr::new(10, 0, 0, 1)]).await;
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {upstream}\n[metadata]\nmax-name-labels 4\nmax-name-length 32\n"
+            ))
+            .unwrap(),
+        );
+        for name in [
+            "a.b.c.d.example.com.",
+            "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaa.example.com.",
+        ] {
+            let res = exchange(&config, "127.0.0.1:5353", &build_query(name, RecordType::A))
+                .await
+                .unwrap();
+            assert_eq!(res.response_code(), ResponseCode::Refused, "{name}");
+        }
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 0);
+        let res = exchange(&config, "127.0.0.1:5353", &build_query("www.example.com.", RecordType::A))
+            .await
+            .unwrap();
+        assert_eq!(res.response_code(), ResponseCode::NoError);
+        assert_eq!(answer_addrs(&res), vec![Ipv4Addr::new(10, 0, 0, 1)]);
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn unsupported_opcode_notimp() {
+        let upstream = spawn_upstream(&[Ipv4Addr::new(10, 0, 0, 1)]).await;
+        let config = Arc::new(Config::from_text(&format!("[server]\ndefault {upstream}\n")).unwrap());
+        let mut req = build_query("example.com.", RecordType::SOA);
+        req.set_op_code(OpCode::Update);
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(res.response_code(), ResponseCode::NotImp);
+        assert_eq!(res.op_code(), OpCode::Update);
+        assert_eq!(res.id(), req.id());
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn multiple_questions_formerr() {
+        let upstream = spawn_upstream(&[Ipv4Addr::new(10, 0, 0, 1)]).await;
+        let config = Arc::new(
+            Config::from_text(&format!("[server]\ndefault {upstream}\n[hosts]\n10.0.0.2 app.lan\n"))
+                .unwrap(),
+        );
+        let mut req = build_query("app.lan.", RecordType::A);
+        req.add_query(Query::query(Name::from_ascii("example.com.").unwrap(), RecordType::A));
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(res.response_code(), ResponseCode::FormErr);
+        assert!(res.answers().is_empty());
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn config_snapshot_per_query() {
+        let config = Arc::new(
+            Config::from_text("[server]\ndefault 127.0.0.1:1\n[hosts]\n10.0.0.1 app.lan\n")
+                .unwrap(),
+        );
+        let mut handler = Handler::new(
+            "udp",
+            "127.0.0.1:5353".parse().unwrap(),
+            "default".to_string(),
+            Arc::new(Cache::with_capacity(0)),
+            config.clone(),
+        );
+        let snapshot = config.access();
+        config
+            .reload_from_text("[server]\ndefault 127.0.0.1:1\n[hosts]\n10.0.0.2 app.lan\n")
+            .unwrap();
+        let req = build_query("app.lan.", RecordType::A);
+        let (_, res) = handler.respond(&snapshot, &req, &[]).await.unwrap();
+        assert_eq!(answer_addrs(&res), vec![IpAddr::from([10, 0, 0, 1])]);
+        let (_, res) = handler.respond(&config.access(), &req, &[]).await.unwrap();
+        assert_eq!(answer_addrs(&res), vec![IpAddr::from([10, 0, 0, 2])]);
+    }
+
+    #[tokio::test]
+    async fn blocklist_responses() {
+        let upstream = spawn_upstream(&[Ipv4Addr::new(10, 0, 0, 1)]).await;
+        let ads = temp_file("ads.txt", "# ads\nads.example.com\n");
+        let sink = temp_file("sink.txt", "tracker.example.com\n");
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {upstream}\n[metadata]\nblock-response nxdomain\n[blocklist]\nads {}\nsink {} sinkhole\n",
+                ads.display(),
+                sink.display()
+            ))
+            .unwrap(),
+        );
+        let req = build_query("www.ads.example.com.", RecordType::A);
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(res.response_code(), ResponseCode::NXDomain);
+        assert_eq!(res.id(), req.id());
+        assert_eq!(res.queries().len(), 1);
+        assert!(res.answers().is_empty());
+
+        let req = build_query("tracker.example.com.", RecordType::AAAA);
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(res.response_code(), ResponseCode::NoError);
+        assert_eq!(answer_addrs(&res), vec![IpAddr::from(Ipv6Addr::UNSPECIFIED)]);
+
+        let req = build_query("tracker.example.com.", RecordType::A);
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(answer_addrs(&res), vec![IpAddr::from(Ipv4Addr::UNSPECIFIED)]);
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 0);
+
+        let req = build_query("example.com.", RecordType::A);
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(answer_addrs(&res), vec![IpAddr::from([10, 0, 0, 1])]);
+    }
+
+    #[tokio::test]
+    async fn dedup_host_and_cache_answers() {
+        let config = Arc::new(
+            Config::from_text(
+                "[group]\nlan 127.0.0.2\n[server]\ndefault 127.0.0.1:1\n[hosts]\n10.0.0.1 nas.lan\n[hosts.lan]\n10.0.0.1 nas.lan\n10.0.0.3 nas.lan\n10.0.0.1 nas.lan\n",
+            )
+            .unwrap(),
+        );
+        let req = build_query("nas.lan.", RecordType::A);
+        let res = exchange(&config, "127.0.0.2:5353", &req).await.unwrap();
+        assert_eq!(
+            answer_addrs(&res),
+            vec![IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 3])]
+        );
+
+        let upstream = spawn_upstream(&[Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 2)]).await;
+        let config = Arc::new(
+            Config::from_text(&format!("[server]\ndefault {upstream}\n[metadata]\ncache-size 64\n"))
+                .unwrap(),
+        );
+        let mut handler = Handler::new(
+            "udp",
+            "127.0.0.1:5353".parse().unwrap(),
+            "default".to_string(),
+            Arc::new(Cache::with_capacity(64)),
+            config.clone(),
+        );
+        let req = build_query("example.com.", RecordType::A);
+        let bytes = req.to_vec().unwrap();
+        handler.respond(&config.access(), &req, &bytes).await.unwrap();
+        let (stage, res) = handler.respond(&config.access(), &req, &bytes).await.unwrap();
+        assert_eq!(stage, 'C');
+        assert_eq!(answer_addrs(&res), vec![IpAddr::from([10, 0, 0, 2])]);
+    }
+
+    #[tokio::test]
+    async fn prefetch_near_expiry() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let upstream = spawn_responder(move |req| {
+            let mut res = response_to(req, ResponseCode::NoError);
+            let hit = counter.fetch_add(1, Ordering::SeqCst) as u8;
+            res.add_answer(Record::from_rdata(
+                req.queries()[0].name().clone(),
+                2,
+                RData::A(rdata::A::new(10, 0, 0, hit + 1)),
+            ));
+            res
+        })
+        .await;
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {upstream}\n[metadata]\ncache-size 64\nprefetch-threshold 0.5\n"
+            ))
+            .unwrap(),
+        );
+        let mut handler = Handler::new(
+            "udp",
+            "127.0.0.1:5353".parse().unwrap(),
+            "default".to_string(),
+            Arc::new(Cache::with_capacity(64)),
+            config.clone(),
+        );
+        let req = build_query("example.com.", RecordType::A);
+        let bytes = req.to_vec().unwrap();
+        let (stage, _) = handler.respond(&config.access(), &req, &bytes).await.unwrap();
+        assert_eq!(stage, 'F');
+        // 未到阈值时不预取
+        let (stage, _) = handler.respond(&config.access(), &req, &bytes).await.unwrap();
+        assert_eq!(stage, 'C');
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let (stage, res) = handler.respond(&config.access(), &req, &bytes).await.unwrap();
+        assert_eq!(stage, 'C');
+        assert_eq!(answer_addrs(&res), vec![IpAddr::from([10, 0, 0, 1])]);
+        for _ in 0..50 {
+            if upstream.hits.load(Ordering::SeqCst) == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let (stage, res) = handler.respond(&config.access(), &req, &bytes).await.unwrap();
+        assert_eq!(stage, 'C');
+        assert_eq!(answer_addrs(&res), vec![IpAddr::from([10, 0, 0, 2])]);
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn rd_policy() {
+        let upstream = spawn_upstream(&[Ipv4Addr::new(10, 0, 0, 1)]).await;
+        let mut req = build_query("example.com.", RecordType::A);
+        req.set_recursion_desired(false);
+        let config = Arc::new(Config::from_text(&format!("[server]\ndefault {upstream}\n")).unwrap());
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(answer_addrs(&res), vec![IpAddr::from([10, 0, 0, 1])]);
+        assert!(!res.recursion_desired());
+
+        config
+            .reload_from_text(&format!(
+                "[server]\ndefault {upstream}\n[hosts]\n10.0.0.9 nas.lan\n[metadata]\nrd-policy refuse\n"
+            ))
+            .unwrap();
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(res.response_code(), ResponseCode::Refused);
+        assert!(!res.recursion_desired());
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 1);
+        // hosts 中的域名仍在本地应答
+        let mut req = build_query("nas.lan.", RecordType::A);
+        req.set_recursion_desired(false);
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(answer_addrs(&res), vec![IpAddr::from([10, 0, 0, 9])]);
+        assert!(!res.recursion_desired());
+    }
+
+    #[tokio::test]
+    async fn tcp_client_forwards_over_tcp() {
+        // 上游 UDP 端口静默丢弃查询，仅 TCP 应答
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        let udp = UdpSocket::bind(upstream).await.unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            while udp.recv_from(&mut buf).await.is_ok() {}
+        });
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let len = stream.read_u16().await.unwrap() as usize;
+                let mut buf = vec![0; len];
+                stream.read_exact(&mut buf).await.unwrap();
+                let req = Message::from_bytes(&buf).unwrap();
+                let mut res = response_to(&req, ResponseCode::NoError);
+                res.add_answer(Record::from_rdata(
+                    req.queries()[0].name().clone(),
+                    300,
+                    RData::A(rdata::A::new(10, 0, 0, 1)),
+                ));
+                let res = res.to_vec().unwrap();
+                stream.write_u16(res.len() as u16).await.unwrap();
+                stream.write_all(&res).await.unwrap();
+            }
+        });
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {upstream}\n[metadata]\nudp-retries 0\nudp-retry-timeout 100\n"
+            ))
+            .unwrap(),
+        );
+        let req = build_query("example.com.", RecordType::A);
+        let bytes = req.to_vec().unwrap();
+        let mut handler = Handler::new(
+            "udp",
+            "127.0.0.1:5353".parse().unwrap(),
+            "default".to_string(),
+            Arc::new(Cache::with_capacity(0)),
+            config.clone(),
+        );
+        assert!(handler.respond(&config.access(), &req, &bytes).await.is_err());
+        // unix socket 同样是流式连接，应答不受 UDP 大小限制
+        for protocol in ["tcp", "unix"] {
+            let mut handler = Handler::new(
+                protocol,
+                "127.0.0.1:5353".parse().unwrap(),
+                "default".to_string(),
+                Arc::new(Cache::with_capacity(0)),
+                config.clone(),
+            );
+            let (stage, res) = handler.respond(&config.access(), &req, &bytes).await.unwrap();
+            assert_eq!(stage, 'F', "{protocol}");
+            assert_eq!(answer_addrs(&res), vec![IpAddr::from([10, 0, 0, 1])]);
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_query_log() {
+        let fast = spawn_upstream(&[Ipv4Addr::new(10, 0, 0, 1)]).await;
+        let slow = spawn_responder(|req| {
+            std::thread::sleep(Duration::from_millis(150));
+            let mut res = response_to(req, ResponseCode::NoError);
+            res.add_answer(Record::from_rdata(
+                req.queries()[0].name().clone(),
+                300,
+                RData::A(rdata::A::new(10, 0, 0, 2)),
+            ));
+            res
+        })
+        .await;
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[group]\nslow 127.0.0.2\n[server]\ndefault {fast}\nslow {slow}\n[metadata]\nslow-query-threshold-ms 100\n"
+            ))
+            .unwrap(),
+        );
+        let req = build_query("example.com.", RecordType::A);
+        let (_guard, logs) = capture_logs();
+        exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        let lines = logs.lines();
+        assert!(lines.iter().all(|it| !it.contains("Queries") && !it.contains("Answers")), "{lines:?}");
+        assert!(lines.iter().any(|it| it.contains("(F) udp://127.0.0.1 example.com.")), "{lines:?}");
+
+        exchange(&config, "127.0.0.2:5353", &req).await.unwrap();
+        let lines = logs.lines();
+        assert!(lines.iter().any(|it| it.contains("[GROUP: slow]")), "{lines:?}");
+        assert!(lines.iter().any(|it| it.contains("Queries")), "{lines:?}");
+        assert!(lines.iter().any(|it| it.contains("Answers") && it.contains("10.0.0.2")), "{lines:?}");
+    }
+
+    #[tokio::test]
+    async fn blocked_extended_error() {
+        let blocklist = temp_file("ede-blocklist", "ads.example\n");
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault 127.0.0.1:1\n[blocklist]\nads {}\n",
+                blocklist.display()
+            ))
+            .unwrap(),
+        );
+        std::fs::remove_file(&blocklist).unwrap();
+        let mut req = build_query("ads.example.", RecordType::A);
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(res.response_code(), ResponseCode::NXDomain);
+        assert!(res.extensions().is_none());
+
+        req.set_edns(hickory_proto::op::Edns::new());
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        let ede = res
+            .extensions()
+            .as_ref()
+            .and_then(|it| it.option(EdnsCode::from(EDE_OPTION)))
+            .cloned();
+        match ede {
+            Some(EdnsOption::Unknown(_, data)) => {
+                assert_eq!(u16::from_be_bytes([data[0], data[1]]), EDE_BLOCKED);
+                assert_eq!(&data[2..], b"blocked by ads");
+            }
+            other => panic!("unexpected EDE option {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_reload_keeps_config() {
+        let hosts = temp_file("reload-hosts", "10.0.0.1 nas.lan\n");
+        let path = temp_file(
+            "reload.conf",
+            &format!("[server]\ndefault 127.0.0.1:1\n[hosts]\n@include {}\n", hosts.display()),
+        );
+        let config = Arc::new(Config::new(path.clone()).unwrap());
+        let req = build_query("nas.lan.", RecordType::A);
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(answer_addrs(&res), vec![IpAddr::from([10, 0, 0, 1])]);
+
+        let (_guard, logs) = capture_logs();
+        // 仅 hosts 文件变化及主配置变化两种重载方式均失败
+        std::fs::write(&hosts, "10.0.0.256 nas.lan\n").unwrap();
+        crate::server::reload_config(&config);
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(answer_addrs(&res), vec![IpAddr::from([10, 0, 0, 1])]);
+        std::fs::write(&path, "[server]\n[hosts]\n10.0.0.2 nas.lan\n").unwrap();
+        crate::server::reload_config(&config);
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&hosts).unwrap();
+        assert_eq!(answer_addrs(&res), vec![IpAddr::from([10, 0, 0, 1])]);
+        let lines = logs.lines();
+        assert_eq!(
+            lines.iter().filter(|it| it.contains("Failed to reload config")).count(),
+            2,
+            "{lines:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn reencode_requests() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let forwarded = received.clone();
+        tokio::spawn(async move {
+            let mut buf = [0; 4096];
+            while let Ok((len, peer)) = upstream.recv_from(&mut buf).await {
+                forwarded.lock().unwrap().push(buf[..len].to_vec());
+                let mut res = Message::from_bytes(&buf[..len]).unwrap();
+                res.set_message_type(MessageType::Response);
+                let _ = upstream.send_to(&res.to_vec().unwrap(), peer).await;
+            }
+        });
+        // 附加记录的名称是指向指针的指针，报文末尾带有多余的数据
+        let mut bytes = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 2];
+        bytes.extend_from_slice(b"\x07example\x03com\x00\x00\x01\x00\x01");
+        bytes.extend_from_slice(b"\x03sub\xc0\x0c\x00\x01\x00\x01\x00\x00\x00\x3c\x00\x04\x0a\x00\x00\x01");
+        bytes.extend_from_slice(b"\xc0\x21\x00\x01\x00\x01\x00\x00\x00\x3c\x00\x04\x0a\x00\x00\x02");
+        bytes.extend_from_slice(b"junk");
+        let req = Message::from_bytes(&bytes).unwrap();
+        assert_eq!(req.additionals()[1].name().to_utf8(), "example.com.");
+
+        for (reencode, expected) in [(false, bytes.clone()), (true, req.to_vec().unwrap())] {
+            let config = Arc::new(
+                Config::from_text(&format!(
+                    "[server]\ndefault {upstream_addr}\n[metadata]\nreencode-requests {reencode}\n"
+                ))
+                .unwrap(),
+            );
+            let mut handler = Handler::new(
+                "udp",
+                "127.0.0.1:5353".parse().unwrap(),
+                "default".to_string(),
+                Arc::new(Cache::with_capacity(0)),
+                config,
+            );
+            let output = Arc::new(Mutex::new(None));
+            let ret = {
+                let output = output.clone();
+                |bytes: Vec<u8>, _addr| async move {
+                    *output.lock().unwrap() = Some(bytes);
+                    Ok(())
+                }
+            };
+            handler.run(bytes.clone(), ret).await;
+            assert!(output.lock().unwrap().is_some());
+            assert_eq!(received.lock().unwrap().pop().unwrap(), expected);
+        }
+        assert_ne!(req.to_vec().unwrap(), bytes);
+    }
+
+    #[tokio::test]
+    async fn cache_exclude_types() {
+        let upstream = spawn_responder(|req| {
+            let mut res = response_to(req, ResponseCode::NoError);
+            let query = &req.queries()[0];
+            let data = match query.query_type() {
+                RecordType::TXT => RData::TXT(rdata::TXT::new(vec!["dynamic".to_string()])),
+                _ => RData::A(rdata::A::new(10, 0, 0, 1)),
+            };
+            res.add_answer(Record::from_rdata(query.name().clone(), 300, data));
+            res
+        })
+        .await;
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {upstream}\n[metadata]\ncache-size 64\ncache-exclude-types txt\n"
+            ))
+            .unwrap(),
+        );
+        let mut handler = Handler::new(
+            "udp",
+            "127.0.0.1:5353".parse().unwrap(),
+            "default".to_string(),
+            Arc::new(Cache::with_capacity(64)),
+            config.clone(),
+        );
+        for (rtype, stages) in [(RecordType::TXT, ['F', 'F']), (RecordType::A, ['F', 'C'])] {
+            let req = build_query("example.com.", rtype);
+            let bytes = req.to_vec().unwrap();
+            for expected in stages {
+                let (stage, _) = handler.respond(&config.access(), &req, &bytes).await.unwrap();
+                assert_eq!(stage, expected, "{rtype}");
+            }
+        }
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn synth6_aaaa() {
+        let upstream = spawn_responder(|req| {
+            let mut res = response_to(req, ResponseCode::NoError);
+            let query = &req.queries()[0];
+            if query.query_type() == RecordType::A {
+                res.add_answer(Record::from_rdata(
+                    query.name().clone(),
+                    300,
+                    RData::A(rdata::A::new(192, 0, 2, 33)),
+                ));
+            }
+            res
+        })
+        .await;
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {upstream}\n[hosts]\n10.0.0.1 legacy.lan\n\
+                 synth6 legacy.lan 64:ff9b::/96\nsynth6 v4only.example 2001:db8:100::/40\n"
+            ))
+            .unwrap(),
+        );
+        let req = build_query("legacy.lan.", RecordType::AAAA);
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(answer_addrs(&res), vec![IpAddr::from_str("64:ff9b::a00:1").unwrap()]);
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 0);
+
+        let req = build_query("v4only.example.", RecordType::AAAA);
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        // RFC 6052: /40 前缀中 IPv4 地址跨过保留的第 64~71 位
+        assert_eq!(answer_addrs(&res), vec![IpAddr::from_str("2001:db8:1c0:2:21::").unwrap()]);
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 2);
+
+        // 合成的记录不写入缓存，再次查询仍转发
+        let mut handler = Handler::new(
+            "udp",
+            "127.0.0.1:5353".parse().unwrap(),
+            "default".to_string(),
+            Arc::new(Cache::with_capacity(64)),
+            config.clone(),
+        );
+        let bytes = req.to_vec().unwrap();
+        for _ in 0..2 {
+            let (stage, res) = handler.respond(&config.access(), &req, &bytes).await.unwrap();
+            assert_eq!(stage, 'F');
+            assert_eq!(answer_addrs(&res), vec![IpAddr::from_str("2001:db8:1c0:2:21::").unwrap()]);
+        }
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 6);
+
+        // 未配置规则的域名不合成
+        let req = build_query("other.example.", RecordType::AAAA);
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert!(res.answers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn max_answers() {
+        let addrs = (1..=8).map(|it| Ipv4Addr::new(10, 0, 0, it)).collect::<Vec<_>>();
+        let upstream = spawn_upstream(&addrs).await;
+        let config = Arc::new(
+            Config::from_text(&format!("[server]\ndefault {upstream}\n[metadata]\nmax-answers 3\n"))
+                .unwrap(),
+        );
+        let req = build_query("example.com.", RecordType::A);
+        let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+        assert_eq!(
+            answer_addrs(&res),
+            addrs[..3].iter().map(|it| IpAddr::from(*it)).collect::<Vec<_>>()
+        );
+        assert_eq!(res.answer_count(), 3);
+    }
+
+    #[tokio::test]
+    async fn log_dropping_rule() {
+        let upstream = spawn_responder(|req| {
+            let mut res = response_to(req, ResponseCode::NoError);
+            res.add_answer(Record::from_rdata(
+                req.queries()[0].name().clone(),
+                300,
+                RData::AAAA(rdata::AAAA("2001:db8::1".parse().unwrap())),
+            ));
+            res
+        })
+        .await;
+        // 设置 slow-query-threshold 时同样记录丢弃原因
+        for metadata in ["", "[metadata]\nslow-query-threshold-ms 60000\n"] {
+            let config = Arc::new(
+                Config::from_text(&format!(
+                    "[server]\ndefault {upstream}\n[ipv6_resolution]\ndefault @allow:keep.example, @deny:.example.com, @allow:ALL\n{metadata}"
+                ))
+                .unwrap(),
+            );
+            let (_guard, logs) = capture_logs();
+            let req = build_query("www.example.com.", RecordType::AAAA);
+            let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+            assert!(res.answers().is_empty());
+            let lines = logs.lines();
+            assert!(
+                lines
+                    .iter()
+                    .any(|it| it.contains("Dropped AAAA www.example.com. 2001:db8::1 by @deny:.example.com")),
+                "{lines:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn zone_file_answers() {
+        let upstream = spawn_upstream(&[Ipv4Addr::new(10, 0, 0, 9)]).await;
+        let zone = temp_file(
+            "example.lan.zone",
+            "$TTL 300\n\
+             @       IN SOA ns.example.lan. admin.example.lan. 1 3600 600 86400 60\n\
+             @       IN NS  ns\n\
+             @       IN MX  10 mail\n\
+             @       IN TXT \"v=spf1 -all\"\n\
+             ns      IN A   10.0.0.1\n\
+             mail    IN A   10.0.0.2\n\
+             www     IN CNAME mail\n\
+             v6      IN AAAA fd00::1\n\
+             a.b     IN A   10.0.0.3\n",
+        );
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {upstream}\n[zone]\nexample.lan {}\n",
+                zone.display()
+            ))
+            .unwrap(),
+        );
+        let query = |name: &str, rtype| {
+            let config = config.clone();
+            let req = build_query(name, rtype);
+            async move { exchange(&config, "127.0.0.1:5353", &req).await.unwrap() }
+        };
+
+        let res = query("mail.example.lan.", RecordType::A).await;
+        assert!(res.authoritative());
+        assert_eq!(answer_addrs(&res), vec![IpAddr::from([10, 0, 0, 2])]);
+        let res = query("V6.Example.Lan.", RecordType::AAAA).await;
+        assert_eq!(answer_addrs(&res), vec!["fd00::1".parse::<IpAddr>().unwrap()]);
+        // 跟随区域内的 CNAME
+        let res = query("www.example.lan.", RecordType::A).await;
+        assert_eq!(res.answers()[0].record_type(), RecordType::CNAME);
+        assert_eq!(answer_addrs(&res), vec![IpAddr::from([10, 0, 0, 2])]);
+        let res = query("example.lan.", RecordType::MX).await;
+        match res.answers()[0].data() {
+            Some(RData::MX(mx)) => assert_eq!(mx.exchange().to_utf8(), "mail.example.lan."),
+            _ => panic!("expected MX answer"),
+        }
+        let res = query("example.lan.", RecordType::TXT).await;
+        assert_eq!(res.answers()[0].record_type(), RecordType::TXT);
+        let res = query("example.lan.", RecordType::NS).await;
+        assert_eq!(res.answers()[0].record_type(), RecordType::NS);
+        let res = query("example.lan.", RecordType::SOA).await;
+        assert_eq!(res.answers()[0].record_type(), RecordType::SOA);
+
+        // 不存在的类型应答 NODATA，不存在的域名应答 NXDOMAIN，均附带 SOA
+        let res = query("mail.example.lan.", RecordType::AAAA).await;
+        assert_eq!(res.response_code(), ResponseCode::NoError);
+        assert!(res.answers().is_empty());
+        assert_eq!(res.name_servers()[0].record_type(), RecordType::SOA);
+        let res = query("b.example.lan.", RecordType::A).await;
+        assert_eq!(res.response_code(), ResponseCode::NoError);
+        let res = query("missing.example.lan.", RecordType::A).await;
+        assert_eq!(res.response_code(), ResponseCode::NXDomain);
+        assert_eq!(res.name_servers()[0].record_type(), RecordType::SOA);
+
+        let res = query("example.com.", RecordType::A).await;
+        assert_eq!(answer_addrs(&res), vec![IpAddr::from([10, 0, 0, 9])]);
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 1);
+        std::fs::remove_file(zone).unwrap();
+    }
+
+    #[tokio::test]
+    async fn static_answers() {
+        let upstream = spawn_upstream(&[Ipv4Addr::new(10, 0, 0, 9)]).await;
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {upstream}\n[static]\n\
+                 captive.apple.com A 10.0.0.1\n\
+                 www.app.lan CNAME app.lan\n\
+                 tracker.example NXDOMAIN\n\
+                 note.lan TXT \"hello # world\"\n"
+            ))
+            .unwrap(),
+        );
+        let query = |name: &str, rtype| {
+            let config = config.clone();
+            let req = build_query(name, rtype);
+            async move { exchange(&config, "127.0.0.1:5353", &req).await.unwrap() }
+        };
+        let res = query("captive.apple.com.", RecordType::A).await;
+        assert_eq!(answer_addrs(&res), vec![IpAddr::from([10, 0, 0, 1])]);
+        // 其它类型的查询不受影响
+        let res = query("captive.apple.com.", RecordType::AAAA).await;
+        assert!(!res.authoritative());
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 1);
+
+        for rtype in [RecordType::A, RecordType::AAAA, RecordType::CNAME] {
+            let res = query("WWW.app.lan.", rtype).await;
+            match res.answers()[0].data() {
+                Some(RData::CNAME(cname)) => assert_eq!(cname.0.to_utf8(), "app.lan."),
+                _ => panic!("expected CNAME answer"),
+            }
+        }
+        for rtype in [RecordType::A, RecordType::MX] {
+            let res = query("tracker.example.", rtype).await;
+            assert_eq!(res.response_code(), ResponseCode::NXDomain);
+        }
+        let res = query("note.lan.", RecordType::TXT).await;
+        match res.answers()[0].data() {
+            Some(RData::TXT(txt)) => assert_eq!(txt.to_string(), "hello # world"),
+            _ => panic!("expected TXT answer"),
+        }
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn ptr_only_mapping() {
+        let config = Arc::new(
+            Config::from_text(
+                "[server]\ndefault 127.0.0.1:1\n[hosts]\n192.168.1.1 nas.lan\n[ptr]\n192.168.1.1 router.lan\nfd00::1 router.lan\n",
+            )
+            .unwrap(),
+        );
+        for name in [
+            "1.1.168.192.in-addr.arpa.",
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.d.f.ip6.arpa.",
+        ] {
+            let req = build_query(name, RecordType::PTR);
+            let res = exchange(&config, "127.0.0.1:5353", &req).await.unwrap();
+            match res.answers()[0].data() {
+                Some(RData::PTR(rdata::PTR(target))) => assert_eq!(target.to_utf8(), "router.lan."),
+                other => panic!("unexpected answer {other:?}"),
+            }
+        }
+        // 没有对应的正向记录
+        let handler = Handler::new(
+            "udp",
+            "127.0.0.1:5353".parse().unwrap(),
+            "default".to_string(),
+            Arc::new(Cache::with_capacity(0)),
+            config.clone(),
+        );
+        let req = build_query("router.lan.", RecordType::A);
+        assert!(handler.resolve_from_hosts(&config.access(), &req).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn constant_time_response() {
+        let upstream = spawn_upstream(&[Ipv4Addr::new(10, 0, 0, 1)]).await;
+        let config = Arc::new(
+            Config::from_text(&format!(
+                "[server]\ndefault {upstream}\n[metadata]\ncache-size 64\nconstant-time-response 200\n"
+            ))
+            .unwrap(),
+        );
+        let cache = Arc::new(Cache::with_capacity(64));
+        let req = build_query("example.com.", RecordType::A).to_vec().unwrap();
+        for _ in 0..2 {
+            let mut handler = Handler::new(
+                "udp",
+                "127.0.0.1:5353".parse().unwrap(),
+                "default".to_string(),
+                cache.clone(),
+                config.clone(),
+            );
+            let start = Instant::now();
+            let sent = Arc::new(Mutex::new(None));
+            let ret = {
+                let sent = sent.clone();
+                |bytes: Vec<u8>, _addr| async move {
+                    *sent.lock().unwrap() = Some(bytes);
+                    Ok(())
+                }
+            };
+            handler.run(req.clone(), ret).await;
+            assert!(sent.lock().unwrap().is_some());
+            assert!(start.elapsed() >= Duration::from_millis(200), "{:?}", start.elapsed());
+        }
+        // 第二次查询由缓存应答
+        assert_eq!(upstream.hits.load(Ordering::SeqCst), 1);
+    }
+}
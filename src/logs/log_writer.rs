@@ -21,14 +21,14 @@ impl Write for Writer<'_> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         self.sender
             .send(LogTask::Write(self.id, buf.to_vec()))
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Failed to send log task"))?;
+            .map_err(|_| io::Error::other("Failed to send log task"))?;
         Ok(buf.len())
     }
 
     fn flush(&mut self) -> io::Result<()> {
         self.sender
             .send(LogTask::Flush(self.id))
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Failed to send flush task"))?;
+            .map_err(|_| io::Error::other("Failed to send flush task"))?;
         Ok(())
     }
 }
@@ -122,7 +122,7 @@ impl LogWriter {
         self.id_acc += 1;
         self.sender
             .send(LogTask::AddFile(self.id_acc, path, file))
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Failed to send log task"))?;
+            .map_err(|_| io::Error::other("Failed to send log task"))?;
         Ok(FileWriter {
             id: self.id_acc,
             sender: self.sender.clone(),
@@ -132,7 +132,7 @@ impl LogWriter {
     pub fn reopen(&self) -> anyhow::Result<()> {
         self.sender
             .send(LogTask::Reopen)
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Failed to send log task"))?;
+            .map_err(|_| io::Error::other("Failed to send log task"))?;
         Ok(())
     }
     pub fn terminal(&self) {
@@ -8,6 +8,8 @@ use tracing_subscriber::{
     {filter, Layer},
 };
 
+#[cfg(test)]
+pub mod capture;
 mod log_writer;
 mod seq_layer;
 
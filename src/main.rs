@@ -4,8 +4,10 @@ mod handler;
 mod logs;
 mod pidfile;
 mod ping;
+mod proxy_protocol;
 mod resolves;
 mod server;
+mod stats;
 
 use crate::config::Config;
 use crate::logs::registry_logs;
@@ -13,9 +15,9 @@ use crate::pidfile::Pidfile;
 use crate::server::ServerArgs;
 use anyhow::Context;
 use std::env;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::net::{TcpListener, UdpSocket};
 
 pub const MAX_CONNECTIONS: usize = 1024;
 
@@ -30,26 +32,19 @@ fn print_banner() {
     tracing::info!("");
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let _pid = Pidfile::new()?;
-    let path = env::args()
-        .nth(1)
-        .unwrap_or("/etc/pomelo/pomelo.conf".to_string());
-    let config =
-        Arc::new(Config::new(PathBuf::from(path)).with_context(|| "Failed to load config file")?);
-    let (mut log_writer, log_handle) = logs::LogWriter::new()?;
-    let (udp, tcp) = {
-        let config = config.access();
-        registry_logs(&mut log_writer, config.metadata.access_log)?;
-        let udp = UdpSocket::bind(&config.metadata.bind)
-            .await
-            .with_context(|| format!("could not bind to udp: {}", &config.metadata.bind))?;
-        let tcp = TcpListener::bind(&config.metadata.bind)
-            .await
-            .with_context(|| format!("could not bind to tcp: {}", &config.metadata.bind))?;
-        (udp, tcp)
-    };
+/// 输出版本及监听地址，关闭 banner 时合并为一行
+fn print_startup(banner: bool, udp: SocketAddr, udp_workers: usize, tcp: SocketAddr) {
+    if !banner {
+        tracing::info!(
+            "Pomelo {} ({}) listening on udp://{} ({} workers), tcp://{}",
+            env!("CARGO_PKG_VERSION"),
+            env!("COMMIT_ID"),
+            udp,
+            udp_workers,
+            tcp
+        );
+        return;
+    }
     print_banner();
     tracing::info!(
         "Pomelo {version} ({commit_id} {build_date}) built with docker{docker_version}, {system_version}, rustc{rustc_version}",
@@ -61,17 +56,67 @@ async fn main() -> anyhow::Result<()> {
         system_version = env!("SYSTEM_VERSION"),
     );
     tracing::info!("The DNS Server running: ");
-    tracing::info!(
-        "udp://{}",
-        udp.local_addr()
+    tracing::info!("udp://{} ({} workers)", udp, udp_workers);
+    tracing::info!("tcp://{}", tcp);
+    tracing::info!("awaiting connections...");
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let mut args = env::args().skip(1).collect::<Vec<_>>();
+    let print_config = args.iter().any(|it| it == "--print-config");
+    args.retain(|it| it != "--print-config");
+    let path = args
+        .into_iter()
+        .next()
+        .unwrap_or("/etc/pomelo/pomelo.conf".to_string());
+    // 输出合并后的配置后退出，用于检查 include 及默认值
+    if print_config {
+        let config = Config::new(PathBuf::from(path)).with_context(|| "Failed to load config file")?;
+        println!("{}", config.dump());
+        return Ok(());
+    }
+    let _pid = Pidfile::new()?;
+    stats::mark_started();
+    let config =
+        Arc::new(Config::new(PathBuf::from(path)).with_context(|| "Failed to load config file")?);
+    let (mut log_writer, log_handle) = logs::LogWriter::new()?;
+    let (udp, tcp) = {
+        let config = config.access();
+        registry_logs(&mut log_writer, config.metadata.access_log)?;
+        let udp = server::bind_udp(
+            &config.metadata.bind,
+            config.metadata.udp_workers,
+            config.metadata.ipv6_v6only,
+        )
+        .await?;
+        if config.metadata.socket_rcvbuf.is_some() || config.metadata.socket_sndbuf.is_some() {
+            for socket in &udp {
+                let (rcvbuf, sndbuf) = server::apply_socket_buffers(
+                    socket,
+                    config.metadata.socket_rcvbuf,
+                    config.metadata.socket_sndbuf,
+                )?;
+                tracing::info!("UDP socket buffers: rcvbuf={} sndbuf={}", rcvbuf, sndbuf);
+            }
+        }
+        let tcp = server::bind_tcp(
+            &config.metadata.bind,
+            config.metadata.tcp_backlog,
+            config.metadata.ipv6_v6only,
+        )
+        .await?;
+        (udp, tcp)
+    };
+    print_startup(
+        config.access().metadata.banner,
+        udp[0]
+            .local_addr()
             .with_context(|| "could not lookup local address")?,
-    );
-    tracing::info!(
-        "tcp://{}",
+        udp.len(),
         tcp.local_addr()
-            .with_context(|| "could not lookup local address")?
+            .with_context(|| "could not lookup local address")?,
     );
-    tracing::info!("awaiting connections...");
     match server::run_until_done(
         ServerArgs {
             config,
@@ -98,3 +143,24 @@ async fn main() -> anyhow::Result<()> {
     };
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logs::capture::capture_logs;
+
+    #[test]
+    fn startup_without_banner() {
+        let (udp, tcp) = ("127.0.0.1:53".parse().unwrap(), "127.0.0.1:53".parse().unwrap());
+        let (_guard, logs) = capture_logs();
+        print_startup(true, udp, 2, tcp);
+        assert!(logs.lines().len() > 4);
+        assert!(logs.lines().iter().any(|it| it.contains("|_|")));
+
+        let (_guard, logs) = capture_logs();
+        print_startup(false, udp, 2, tcp);
+        let lines = logs.lines();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains("udp://127.0.0.1:53 (2 workers), tcp://127.0.0.1:53"));
+    }
+}
@@ -0,0 +1,123 @@
+use anyhow::Context;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// v1 头部的最大长度，包括结尾的 CRLF
+const V1_MAX_LEN: usize = 107;
+
+/// 读取连接开头的 PROXY 协议头（v1/v2），返回真实的客户端地址；
+/// LOCAL 命令或 UNKNOWN 协议族返回 None，此时使用连接本身的地址
+pub async fn read_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    timeout: Duration,
+) -> anyhow::Result<Option<SocketAddr>> {
+    tokio::time::timeout(timeout, async {
+        // v1 头部至少为 "PROXY UNKNOWN\r\n"，读取 12 字节不会越过头部
+        let mut prefix = [0; 12];
+        stream.read_exact(&mut prefix).await?;
+        if prefix == V2_SIGNATURE {
+            read_v2(stream).await
+        } else if prefix.starts_with(b"PROXY ") {
+            read_v1(stream, prefix).await
+        } else {
+            anyhow::bail!("Missing PROXY protocol header")
+        }
+    })
+    .await
+    .with_context(|| format!("Read timeout after {}ms", timeout.as_millis()))?
+}
+
+async fn read_v1<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    prefix: [u8; 12],
+) -> anyhow::Result<Option<SocketAddr>> {
+    let mut line = prefix.to_vec();
+    // 逐字节读取到 CRLF，避免读走头部之后的 DNS 消息
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            anyhow::bail!("PROXY v1 header exceeds {} bytes", V1_MAX_LEN);
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2]).context("Invalid PROXY v1 header")?;
+    let parts = line.split(' ').collect::<Vec<_>>();
+    match parts[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, port, _] => {
+            let ip = source
+                .parse::<IpAddr>()
+                .with_context(|| format!("Invalid PROXY v1 source address '{}'", source))?;
+            let port = port
+                .parse::<u16>()
+                .with_context(|| format!("Invalid PROXY v1 source port '{}'", port))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => anyhow::bail!("Invalid PROXY v1 header '{}'", line),
+    }
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> anyhow::Result<Option<SocketAddr>> {
+    let mut header = [0; 4];
+    stream.read_exact(&mut header).await?;
+    let [ver_cmd, family, ..] = header;
+    if ver_cmd >> 4 != 2 {
+        anyhow::bail!("Unsupported PROXY protocol version {}", ver_cmd >> 4);
+    }
+    let mut payload = vec![0; u16::from_be_bytes([header[2], header[3]]) as usize];
+    stream.read_exact(&mut payload).await?;
+    match ver_cmd & 0x0F {
+        0 => return Ok(None),
+        1 => {}
+        cmd => anyhow::bail!("Unsupported PROXY v2 command {}", cmd),
+    }
+    // 高 4 位为地址族：1 AF_INET、2 AF_INET6，其余（UNSPEC、UNIX）不携带 IP 地址
+    let addr = match family >> 4 {
+        1 if payload.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&payload[..4])?);
+            SocketAddr::new(ip.into(), u16::from_be_bytes([payload[8], payload[9]]))
+        }
+        2 if payload.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&payload[..16])?);
+            SocketAddr::new(ip.into(), u16::from_be_bytes([payload[32], payload[33]]))
+        }
+        1 | 2 => anyhow::bail!("Truncated PROXY v2 address block"),
+        _ => return Ok(None),
+    };
+    Ok(Some(addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn parse(bytes: &[u8]) -> anyhow::Result<Option<SocketAddr>> {
+        let mut reader = bytes;
+        let addr = read_header(&mut reader, Duration::from_secs(1)).await?;
+        // 头部之后的数据保留给 DNS 消息
+        assert_eq!(reader, b"rest");
+        Ok(addr)
+    }
+
+    #[tokio::test]
+    async fn header_versions() {
+        let addr = parse(b"PROXY TCP4 203.0.113.7 192.0.2.1 5555 53\r\nrest").await;
+        assert_eq!(addr.unwrap(), Some("203.0.113.7:5555".parse().unwrap()));
+        let addr = parse(b"PROXY TCP6 2001:db8::7 2001:db8::1 5555 53\r\nrest").await;
+        assert_eq!(addr.unwrap(), Some("[2001:db8::7]:5555".parse().unwrap()));
+        assert_eq!(parse(b"PROXY UNKNOWN\r\nrest").await.unwrap(), None);
+
+        let mut v2 = V2_SIGNATURE.to_vec();
+        v2.extend_from_slice(&[0x21, 0x11, 0, 12, 203, 0, 113, 7, 192, 0, 2, 1, 0x15, 0xB3, 0, 53]);
+        v2.extend_from_slice(b"rest");
+        assert_eq!(parse(&v2).await.unwrap(), Some("203.0.113.7:5555".parse().unwrap()));
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        local.extend_from_slice(b"rest");
+        assert_eq!(parse(&local).await.unwrap(), None);
+
+        let mut reader = &b"\x00\x1d\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00"[..];
+        assert!(read_header(&mut reader, Duration::from_secs(1)).await.is_err());
+    }
+}
@@ -1,8 +1,9 @@
-use crate::resolves::dot::{build_tcp_stream, make_tls_config, wrap_tls_stream};
+use crate::resolves::dot::{build_tcp_stream, connect_target, make_tls_config, wrap_tls_stream};
 use crate::resolves::http;
-use crate::resolves::DNSResolver;
+use crate::resolves::{DNSResolver, ResolveOpts};
 use anyhow::Context;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
 use tokio_rustls::client::TlsStream;
@@ -12,21 +13,30 @@ use url::Url;
 
 pub struct DoH {
     target: Url,
+    connect_target: Url,
     tls_config: Arc<ClientConfig>,
+    connect_timeout: Duration,
+    tcp_fastopen: bool,
 }
 
 impl DoH {
-    pub fn new(target: &str) -> anyhow::Result<Self> {
+    pub fn new(target: &str, opts: &ResolveOpts) -> anyhow::Result<Self> {
+        let target = Url::parse(target)?;
         Ok(Self {
-            target: Url::parse(target)?,
-            tls_config: make_tls_config(),
+            connect_target: connect_target(&target, opts.connect_addr),
+            target,
+            tls_config: make_tls_config(&opts.tls)?,
+            connect_timeout: opts.connect_timeout,
+            tcp_fastopen: opts.tcp_fastopen,
         })
     }
     pub async fn build_connect(&self) -> anyhow::Result<TlsStream<TcpStream>> {
         wrap_tls_stream(
-            build_tcp_stream(&self.target).await?,
+            build_tcp_stream(&self.connect_target, self.connect_timeout, self.tcp_fastopen)
+                .await?,
             &self.target,
             &TlsConnector::from(self.tls_config.clone()),
+            self.connect_timeout,
         )
         .await
     }
@@ -34,13 +44,16 @@ impl DoH {
 impl DNSResolver for DoH {
     async fn resolve(&mut self, bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
         let mut stream = self.build_connect().await?;
+        let host = self.target.host_str().with_context(|| "Missing host")?;
+        // 非默认端口时 host 需要携带端口
+        let host = match self.target.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
         let req = http::h1::Request::new()
             .path(self.target.path())
             .header("content-type", "application/dns-message")
-            .header(
-                "host",
-                self.target.host_str().with_context(|| "Missing host")?,
-            )
+            .header("host", &host)
             .header("content-length", &bytes.len().to_string())
             .body(bytes)
             .as_bytes();
@@ -63,7 +76,7 @@ mod tests {
     use hickory_proto::serialize::binary::BinDecodable;
     #[tokio::test]
     async fn it_works() {
-        let mut dns = DoH::new("https://1.1.1.1/dns-query").unwrap();
+        let mut dns = DoH::new("https://1.1.1.1/dns-query", &ResolveOpts::default()).unwrap();
         // query example.com
         let bytes = [
             0x00, 0x02, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x65,
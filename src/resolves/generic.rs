@@ -1,25 +1,189 @@
 use crate::resolves::{DNSResolver, ResolveOpts};
-use tokio::net::UdpSocket;
+use anyhow::Context;
+use hickory_proto::op::{Edns, Message};
+use hickory_proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+use hickory_proto::serialize::binary::{BinDecodable, BinEncodable};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::Instant;
+
+/// RFC 7873: 每个上游的客户端 Cookie 及最近一次收到的服务端 Cookie
+type Cookies = HashMap<String, ([u8; 8], Vec<u8>)>;
+
+static COOKIES: OnceLock<Mutex<Cookies>> = OnceLock::new();
+
+fn cookies<'a>() -> MutexGuard<'a, Cookies> {
+    let cookies = COOKIES.get_or_init(|| Mutex::new(HashMap::new()));
+    match cookies.lock() {
+        Ok(guard) => guard,
+        Err(err) => err.into_inner(),
+    }
+}
+
+/// 发出的查询及用于校验应答的信息
+struct CookieQuery {
+    bytes: Vec<u8>,
+    id: u16,
+    client_cookie: [u8; 8],
+    /// 原始查询不带 EDNS 时，应答中的 OPT 记录需要移除
+    has_edns: bool,
+}
 
 pub struct Generic<'input> {
     target: &'input str,
     udp_payload_size: usize,
+    retries: usize,
+    retry_timeout: Duration,
+    tcp_fallback: bool,
+    prefer_tcp: bool,
+    connect_timeout: Duration,
+    edns_cookies: bool,
 }
 
 impl<'input> Generic<'input> {
-    pub fn new(target: &'input str, opts: ResolveOpts) -> Self {
-        Generic { target, udp_payload_size: opts.max_payload_size }
+    pub fn new(target: &'input str, opts: &ResolveOpts) -> Self {
+        Generic {
+            target,
+            udp_payload_size: opts.max_payload_size,
+            retries: opts.udp_retries,
+            retry_timeout: opts.udp_retry_timeout,
+            tcp_fallback: opts.tcp_fallback,
+            prefer_tcp: opts.prefer_tcp,
+            connect_timeout: opts.connect_timeout,
+            edns_cookies: opts.edns_cookies,
+        }
+    }
+    /// 在查询的 OPT 记录中附加 Cookie，已知服务端 Cookie 时一并发送
+    fn attach_cookie(&self, bytes: &[u8]) -> anyhow::Result<CookieQuery> {
+        let mut message = Message::from_bytes(bytes).with_context(|| "Failed to parse query")?;
+        let (client_cookie, server_cookie) = cookies()
+            .entry(self.target.to_string())
+            .or_insert_with(|| {
+                let mut hasher = RandomState::new().build_hasher();
+                hasher.write(self.target.as_bytes());
+                (hasher.finish().to_be_bytes(), Vec::new())
+            })
+            .clone();
+        let has_edns = message.extensions().is_some();
+        let mut edns = message.extensions().clone().unwrap_or_else(|| {
+            let mut edns = Edns::new();
+            edns.set_max_payload(self.udp_payload_size as u16);
+            edns
+        });
+        let mut cookie = client_cookie.to_vec();
+        cookie.extend_from_slice(&server_cookie);
+        edns.options_mut()
+            .insert(EdnsOption::Unknown(u16::from(EdnsCode::Cookie), cookie));
+        message.set_edns(edns);
+        Ok(CookieQuery {
+            bytes: message.to_bytes()?,
+            id: message.id(),
+            client_cookie,
+            has_edns,
+        })
+    }
+    /// 校验应答中的客户端 Cookie，保存服务端 Cookie 并从应答中移除 Cookie 选项
+    fn verify_cookie(&self, query: &CookieQuery, response: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut message = Message::from_bytes(response).with_context(|| "Malformed response")?;
+        if message.id() != query.id {
+            anyhow::bail!("Mismatched message id {}", message.id());
+        }
+        let cookie = match message
+            .extensions()
+            .as_ref()
+            .and_then(|it| it.option(EdnsCode::Cookie))
+        {
+            Some(EdnsOption::Unknown(_, cookie)) => cookie.clone(),
+            _ => anyhow::bail!("Missing DNS cookie"),
+        };
+        if cookie.len() < 8 || cookie[..8] != query.client_cookie {
+            anyhow::bail!("Mismatched client cookie");
+        }
+        if let Some(entry) = cookies().get_mut(self.target) {
+            entry.1 = cookie[8..].to_vec();
+        }
+        match message.extensions_mut() {
+            Some(edns) if query.has_edns => edns.options_mut().remove(EdnsCode::Cookie),
+            edns => *edns = None,
+        }
+        Ok(message.to_bytes()?)
+    }
+    /// 通过 TCP 向同一上游发送查询，报文带两字节长度前缀
+    async fn resolve_tcp(&self, bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut stream = tokio::time::timeout(self.connect_timeout, TcpStream::connect(self.target))
+            .await
+            .map_err(|_| anyhow::format_err!("connect to '{}' timeout", self.target))?
+            .with_context(|| format!("Unable to connect to '{}' over TCP", self.target))?;
+        let mut buf = Vec::with_capacity(bytes.len() + 2);
+        buf.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+        buf.extend_from_slice(bytes);
+        stream.write_all(&buf).await?;
+        let len = stream.read_u16().await? as usize;
+        let mut response = vec![0; len];
+        stream.read_exact(&mut response).await?;
+        Ok(response)
     }
 }
 
 impl<'input> DNSResolver for Generic<'input> {
     async fn resolve(&mut self, bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+        // 客户端因截断改用 TCP 时，上游同样使用 TCP，避免应答再次被截断
+        if self.prefer_tcp {
+            return self.resolve_tcp(bytes).await;
+        }
         let socket = UdpSocket::bind("0.0.0.0:0").await?;
-        socket.send_to(bytes, self.target).await?;
         let mut response = vec![0; self.udp_payload_size];
-        let (len, _) = socket.recv_from(&mut response).await?;
-        response.truncate(len);
-        Ok(response)
+        let query = if self.edns_cookies {
+            Some(self.attach_cookie(bytes)?)
+        } else {
+            None
+        };
+        let payload = query.as_ref().map(|it| it.bytes.as_slice()).unwrap_or(bytes);
+        // 数据包可能丢失，超时后重新发送相同的查询
+        for attempt in 0..=self.retries {
+            socket.send_to(payload, self.target).await?;
+            let deadline = Instant::now() + self.retry_timeout;
+            loop {
+                let len = match tokio::time::timeout_at(deadline, socket.recv_from(&mut response)).await {
+                    Ok(r) => r?.0,
+                    Err(_) => break,
+                };
+                let query = match &query {
+                    Some(query) => query,
+                    None => {
+                        response.truncate(len);
+                        return Ok(response);
+                    }
+                };
+                // Cookie 不匹配的应答可能是伪造的，丢弃后继续等待
+                match self.verify_cookie(query, &response[..len]) {
+                    Ok(response) => return Ok(response),
+                    Err(err) => tracing::warn!("Rejected response from '{}': {}", self.target, err),
+                }
+            }
+            tracing::debug!(
+                "No response from '{}' after attempt {}",
+                self.target,
+                attempt + 1
+            );
+        }
+        // UDP 可能被拦截，改用 TCP 重试一次
+        if self.tcp_fallback {
+            tracing::debug!("Falling back to TCP for '{}'", self.target);
+            return tokio::time::timeout(self.retry_timeout, self.resolve_tcp(bytes))
+                .await
+                .map_err(|_| anyhow::format_err!("No response from '{}' over TCP", self.target))?;
+        }
+        anyhow::bail!(
+            "No response from '{}' after {} attempts",
+            self.target,
+            self.retries + 1
+        )
     }
 }
 
@@ -32,7 +196,7 @@ mod tests {
 
     #[tokio::test]
     async fn it_works() {
-        let mut dns = Generic::new("1.1.1.1:53", ResolveOpts{max_payload_size: 4096});
+        let mut dns = Generic::new("1.1.1.1:53", &ResolveOpts::default());
         // query example.com
         let bytes = [
             0x00, 0x02, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x65,
@@ -45,4 +209,114 @@ mod tests {
         assert_eq!(message.answers()[0].name().to_utf8(), "example.com.");
         assert_eq!(message.answers()[0].record_type(), RecordType::A);
     }
+
+    #[tokio::test]
+    async fn retry_after_dropped_reply() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = upstream.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            // 丢弃第一个查询，应答第二个
+            let _ = upstream.recv_from(&mut buf).await.unwrap();
+            let (len, peer) = upstream.recv_from(&mut buf).await.unwrap();
+            upstream.send_to(&buf[..len], peer).await.unwrap();
+        });
+        let mut dns = Generic::new(
+            &target,
+            &ResolveOpts {
+                udp_retries: 1,
+                udp_retry_timeout: Duration::from_millis(100),
+                ..ResolveOpts::default()
+            },
+        );
+        let response = dns.resolve(&[0x12, 0x34]).await.unwrap();
+        assert_eq!(response, vec![0x12, 0x34]);
+    }
+
+    #[tokio::test]
+    async fn tcp_fallback_when_udp_silent() {
+        // UDP 端口静默丢弃查询，相同端口的 TCP 正常应答
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+        let _udp = UdpSocket::bind(&target).await.unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let len = stream.read_u16().await.unwrap();
+            let mut buf = vec![0; len as usize];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_u16(len).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+        let opts = ResolveOpts {
+            udp_retries: 1,
+            udp_retry_timeout: Duration::from_millis(100),
+            ..ResolveOpts::default()
+        };
+        let err = Generic::new(&target, &opts)
+            .resolve(&[0x12, 0x34])
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("after 2 attempts"), "{err}");
+        let mut dns = Generic::new(
+            &target,
+            &ResolveOpts {
+                tcp_fallback: true,
+                ..opts
+            },
+        );
+        let response = dns.resolve(&[0x12, 0x34]).await.unwrap();
+        assert_eq!(response, vec![0x12, 0x34]);
+    }
+
+    #[tokio::test]
+    async fn edns_cookie_validation() {
+        // 第一个上游原样返回查询，第二个上游返回不带 OPT 记录的应答
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_target = echo.local_addr().unwrap().to_string();
+        let stripped = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let stripped_target = stripped.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (len, peer) = echo.recv_from(&mut buf).await.unwrap();
+                echo.send_to(&buf[..len], peer).await.unwrap();
+            }
+        });
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (len, peer) = stripped.recv_from(&mut buf).await.unwrap();
+                let mut message = Message::from_bytes(&buf[..len]).unwrap();
+                *message.extensions_mut() = None;
+                stripped.send_to(&message.to_bytes().unwrap(), peer).await.unwrap();
+            }
+        });
+        let opts = ResolveOpts {
+            udp_retries: 0,
+            udp_retry_timeout: Duration::from_millis(200),
+            edns_cookies: true,
+            ..ResolveOpts::default()
+        };
+        let mut query = Message::new();
+        query.set_id(0x1234);
+        let bytes = query.to_bytes().unwrap();
+
+        let response = Generic::new(&echo_target, &opts).resolve(&bytes).await.unwrap();
+        let response = Message::from_bytes(&response).unwrap();
+        assert_eq!(response.id(), 0x1234);
+        // 原始查询不带 EDNS，应答中的 OPT 记录被移除
+        assert!(response.extensions().is_none());
+
+        let err = Generic::new(&stripped_target, &opts)
+            .resolve(&bytes)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("No response"), "{err}");
+        // 未开启 Cookie 时接受该应答
+        let opts = ResolveOpts {
+            edns_cookies: false,
+            ..opts
+        };
+        assert!(Generic::new(&stripped_target, &opts).resolve(&bytes).await.is_ok());
+    }
 }
@@ -1,11 +1,12 @@
 use anyhow::Context;
 use std::collections::HashMap;
-use tokio::io::AsyncReadExt;
-use tokio::net::TcpStream;
-use tokio_rustls::client::TlsStream;
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 pub const PROTOCOL: &str = "HTTP";
 pub const VERSION: &str = "1.1";
+pub const USER_AGENT: &str = concat!("pomelo/", env!("CARGO_PKG_VERSION"));
+/// 响应体的最大长度，避免异常的 content-length 导致过量分配
+pub const MAX_CONTENT_LENGTH: usize = 1024 * 1024;
 #[derive(Debug, Clone)]
 pub struct Response {
     pub status_code: u16,
@@ -22,7 +23,14 @@ enum State {
     HeaderValue,
 }
 impl Response {
-    pub async fn from_stream(stream: &mut TlsStream<TcpStream>) -> anyhow::Result<Self> {
+    pub async fn from_stream<S: AsyncRead + Unpin>(stream: &mut S) -> anyhow::Result<Self> {
+        Self::from_stream_with_limit(stream, MAX_CONTENT_LENGTH).await
+    }
+    /// 按指定的响应体最大长度读取响应
+    pub async fn from_stream_with_limit<S: AsyncRead + Unpin>(
+        stream: &mut S,
+        max_length: usize,
+    ) -> anyhow::Result<Self> {
         let mut byte = [0];
         let mut state = State::Protocol;
         let mut protocol = String::new();
@@ -104,12 +112,23 @@ impl Response {
                 },
             }
         }
-        let length = headers
-            .get("content-length")
-            .and_then(|it| it.parse::<u16>().ok())
-            .with_context(|| "Unknown body length")?;
-        let mut body = vec![0; length as usize];
-        stream.read_exact(&mut body).await?;
+        let chunked = headers
+            .get("transfer-encoding")
+            .is_some_and(|it| it.to_lowercase().contains("chunked"));
+        let body = if chunked {
+            read_chunked(stream, max_length).await?
+        } else {
+            let length = headers
+                .get("content-length")
+                .and_then(|it| it.parse::<usize>().ok())
+                .with_context(|| "Unknown body length")?;
+            if length > max_length {
+                anyhow::bail!("Body length {} exceeds limit {}", length, max_length)
+            }
+            let mut body = vec![0; length];
+            stream.read_exact(&mut body).await?;
+            body
+        };
         Ok(Self {
             status_code: status_code
                 .parse::<u16>()
@@ -120,23 +139,75 @@ impl Response {
         })
     }
 }
-async fn next(stream: &mut TlsStream<TcpStream>, byte: &mut [u8; 1]) -> Option<char> {
+/// 读取 chunked 编码的响应体，忽略 chunk 扩展及 trailer
+async fn read_chunked<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    max_length: usize,
+) -> anyhow::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line = read_line(stream).await?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)
+            .with_context(|| format!("Invalid chunk size: {}", size))?;
+        if size == 0 {
+            while !read_line(stream).await?.is_empty() {}
+            return Ok(body);
+        }
+        if body.len() + size > max_length {
+            anyhow::bail!(
+                "Body length {} exceeds limit {}",
+                body.len() + size,
+                max_length
+            )
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        stream.read_exact(&mut body[start..]).await?;
+        if !read_line(stream).await?.is_empty() {
+            anyhow::bail!("Missing CRLF after chunk")
+        }
+    }
+}
+/// 读取以 CRLF 结尾的一行，限制长度避免无限读取
+async fn read_line<S: AsyncRead + Unpin>(stream: &mut S) -> anyhow::Result<String> {
+    let mut byte = [0];
+    let mut line = String::new();
+    loop {
+        let char = next(stream, &mut byte)
+            .await
+            .with_context(|| "Unexpected end of chunked body")?;
+        match char {
+            '\r' => {}
+            '\n' => return Ok(line),
+            _ if line.len() >= 1024 => anyhow::bail!("Chunk line too long"),
+            _ => line.push(char),
+        }
+    }
+}
+async fn next<S: AsyncRead + Unpin>(stream: &mut S, byte: &mut [u8; 1]) -> Option<char> {
     stream.read_exact(byte).await.ok().map(|_| byte[0] as char)
 }
 
 pub struct Request<'input> {
     headers: HashMap<&'input str, &'input str>,
+    method: &'input str,
     path: &'input str,
     body: Option<&'input [u8]>,
 }
 impl<'input> Request<'input> {
     pub fn new() -> Self {
         Self {
+            method: "POST",
             path: "",
-            headers: HashMap::from([("accept", "*/*")]),
+            headers: HashMap::from([("accept", "*/*"), ("user-agent", USER_AGENT)]),
             body: None,
         }
     }
+    pub fn method(&mut self, method: &'input str) -> &mut Self {
+        self.method = method;
+        self
+    }
     pub fn path(&mut self, path: &'input str) -> &mut Self {
         self.path = path;
         self
@@ -154,7 +225,7 @@ impl<'input> Request<'input> {
         req.extend_from_slice(
             format!(
                 "{method} {path} {PROTOCOL}/{VERSION}\r\n",
-                method = "POST",
+                method = self.method,
                 path = self.path
             )
             .as_bytes(),
@@ -169,3 +240,36 @@ impl<'input> Request<'input> {
         req
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn large_content_length() {
+        let body = vec![0xab; 70000];
+        let mut bytes = b"HTTP/1.1 200 OK\r\ncontent-length: 70000\r\n\r\n".to_vec();
+        bytes.extend_from_slice(&body);
+        let response = Response::from_stream(&mut bytes.as_slice()).await.unwrap();
+        assert_eq!(response.status_code, 200);
+        assert_eq!(response.body, body);
+    }
+
+    #[test]
+    fn request_user_agent() {
+        let bytes = Request::new()
+            .path("/dns-query")
+            .header("host", "dns.example")
+            .as_bytes();
+        let text = String::from_utf8(bytes).unwrap();
+        assert!(text.starts_with("POST /dns-query HTTP/1.1\r\n"));
+        assert!(text.contains(&format!("user-agent: {}\r\n", USER_AGENT)));
+        assert!(text.contains("host: dns.example\r\n"));
+    }
+
+    #[tokio::test]
+    async fn absurd_content_length() {
+        let bytes = b"HTTP/1.1 200 OK\r\ncontent-length: 99999999999\r\n\r\n".to_vec();
+        assert!(Response::from_stream(&mut bytes.as_slice()).await.is_err());
+    }
+}
@@ -1 +1,154 @@
 pub mod h1;
+
+use crate::resolves::dot::{build_tcp_stream, make_tls_config, wrap_tls_stream};
+use crate::resolves::ResolveOpts;
+use anyhow::Context;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio_rustls::TlsConnector;
+use url::Url;
+
+/// 最多跟随的重定向次数
+const MAX_REDIRECTS: usize = 5;
+
+/// 以 GET 请求下载 http/https 地址的内容，跟随有限次数的重定向，仅接受 200 响应
+pub async fn get(target: &Url, opts: &ResolveOpts, max_length: usize) -> anyhow::Result<Vec<u8>> {
+    let mut target = target.clone();
+    for _ in 0..=MAX_REDIRECTS {
+        let response = fetch(&target, opts, max_length).await?;
+        match response.status_code {
+            200 => return Ok(response.body),
+            301 | 302 | 303 | 307 | 308 => {
+                let location = response.headers.get("location").with_context(|| {
+                    format!(
+                        "{} {} without location",
+                        response.status_code, response.status_text
+                    )
+                })?;
+                target = target
+                    .join(location)
+                    .with_context(|| format!("Invalid redirect location '{}'", location))?;
+            }
+            _ => anyhow::bail!("{} {}", response.status_code, response.status_text),
+        }
+    }
+    anyhow::bail!("Too many redirects, last location '{}'", target)
+}
+
+async fn fetch(
+    target: &Url,
+    opts: &ResolveOpts,
+    max_length: usize,
+) -> anyhow::Result<h1::Response> {
+    let stream = build_tcp_stream(target, opts.connect_timeout, opts.tcp_fastopen).await?;
+    match target.scheme() {
+        "https" => {
+            let connector = TlsConnector::from(make_tls_config(&opts.tls)?);
+            let mut stream =
+                wrap_tls_stream(stream, target, &connector, opts.connect_timeout).await?;
+            request(&mut stream, target, max_length).await
+        }
+        "http" => {
+            let mut stream = stream;
+            request(&mut stream, target, max_length).await
+        }
+        scheme => anyhow::bail!("Unsupported scheme '{}' in '{}'", scheme, target),
+    }
+}
+
+async fn request<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    target: &Url,
+    max_length: usize,
+) -> anyhow::Result<h1::Response> {
+    let host = target.host_str().with_context(|| "Missing host")?;
+    let host = match target.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+    let path = match target.query() {
+        Some(query) => format!("{}?{}", target.path(), query),
+        None => target.path().to_string(),
+    };
+    let req = h1::Request::new()
+        .method("GET")
+        .path(&path)
+        .header("host", &host)
+        .header("connection", "close")
+        .as_bytes();
+    stream.write_all(&req).await?;
+    stream.flush().await?;
+    h1::Response::from_stream_with_limit(stream, max_length).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    /// 依次以给定的响应应答每个连接，返回收到的请求行
+    async fn serve(responses: Vec<String>) -> (Url, tokio::task::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!(
+            "http://{}/list.txt",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let server = tokio::spawn(async move {
+            let mut lines = Vec::new();
+            for res in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut req = Vec::new();
+                let mut buf = [0; 1024];
+                while !req.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    req.extend_from_slice(&buf[..n]);
+                }
+                let req = String::from_utf8(req).unwrap();
+                lines.push(req.lines().next().unwrap().to_string());
+                stream.write_all(res.as_bytes()).await.unwrap();
+            }
+            lines
+        });
+        (url, server)
+    }
+
+    #[tokio::test]
+    async fn chunked_body() {
+        let res = "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n5;ext=1\r\nhello\r\nb\r\n, chunked!\n\r\n0\r\nx-trailer: 1\r\n\r\n";
+        let (url, server) = serve(vec![res.to_string(), res.to_string()]).await;
+        let opts = ResolveOpts::default();
+        assert_eq!(get(&url, &opts, 1024).await.unwrap(), b"hello, chunked!\n");
+        assert!(get(&url, &opts, 8).await.is_err());
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn follow_redirects() {
+        let redirect = |location: &str| {
+            format!("HTTP/1.1 302 Found\r\nlocation: {location}\r\ncontent-length: 0\r\n\r\n")
+        };
+        let (url, server) = serve(vec![
+            redirect("/moved.txt"),
+            redirect("final.txt?v=1"),
+            "HTTP/1.1 200 OK\r\ncontent-length: 3\r\n\r\nok\n".to_string(),
+        ])
+        .await;
+        let opts = ResolveOpts::default();
+        assert_eq!(get(&url, &opts, 1024).await.unwrap(), b"ok\n");
+        assert_eq!(
+            server.await.unwrap(),
+            [
+                "GET /list.txt HTTP/1.1",
+                "GET /moved.txt HTTP/1.1",
+                "GET /final.txt?v=1 HTTP/1.1"
+            ]
+        );
+
+        // 超过重定向次数上限
+        let (url, server) = serve(vec![redirect("/list.txt"); MAX_REDIRECTS + 1]).await;
+        let err = get(&url, &opts, 1024).await.unwrap_err();
+        assert!(err.to_string().contains("Too many redirects"), "{err}");
+        server.await.unwrap();
+    }
+}
@@ -1,40 +1,155 @@
 pub mod generic;
 pub mod doh;
 pub mod dot;
-mod http;
+pub mod http;
+pub mod stamp;
 
 use crate::resolves::doh::DoH;
+use crate::resolves::stamp::{Stamp, StampProtocol};
 pub use generic::Generic;
 pub use dot::DoT;
-use std::borrow::Cow;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio_rustls::rustls::crypto::ring::ALL_CIPHER_SUITES;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::rustls::SupportedCipherSuite;
 
 pub trait DNSResolver {
     async fn resolve(&mut self, bytes: &[u8]) -> anyhow::Result<Vec<u8>>;
 }
 
+#[derive(Clone)]
 pub struct ResolveOpts{
-    pub max_payload_size: usize
-}
-
-pub async fn resolve(server: &str, bytes: &[u8], opts: ResolveOpts) -> anyhow::Result<Vec<u8>> {
-    if server.starts_with("tls://") {
-        let (_, addr, port) = split_addr(server);
-        let addr = format!("{}:{}", addr, port.unwrap_or("853"));
-        let mut dns = DoT::new(&addr)?;
-        dns.resolve(bytes).await
-    } else if server.starts_with("https://") {
-        let addr = if server.ends_with("/dns-query") {
-            Cow::Borrowed(server)
-        } else {
-            Cow::Owned(format!("{}/dns-query", server))
-        };
-        let mut dns = DoH::new(&addr)?;
-        dns.resolve(bytes).await
-    } else {
-        let (_, addr, port) = split_addr(server);
-        let addr = format!("{}:{}", addr, port.unwrap_or("53"));
-        let mut dns = Generic::new(&addr, opts);
-        dns.resolve(bytes).await
+    pub max_payload_size: usize,
+    /// 明文 UDP 上游未应答时的重试次数
+    pub udp_retries: usize,
+    /// 明文 UDP 上游每次尝试的超时时间
+    pub udp_retry_timeout: Duration,
+    /// 明文 UDP 上游无应答时改用 TCP 重试
+    pub tcp_fallback: bool,
+    /// 明文上游直接使用 TCP 查询，用于转发客户端通过 TCP 发出的查询
+    pub prefer_tcp: bool,
+    /// 明文 UDP 上游查询附带 EDNS Cookie 并校验应答
+    pub edns_cookies: bool,
+    /// 上游 TCP 连接及 TLS 握手的超时时间
+    pub connect_timeout: Duration,
+    /// 连接 DoT/DoH 上游时启用 TCP Fast Open
+    pub tcp_fastopen: bool,
+    /// 连接 DoT/DoH 上游时使用的地址，不再解析主机名
+    pub connect_addr: Option<IpAddr>,
+    /// DoT 单个连接上同时进行的最大查询数，设置后多个查询按 DNS id 复用同一连接
+    pub dot_max_inflight: Option<usize>,
+    pub tls: TlsOpts,
+}
+
+/// DoT/DoH 上游的 TLS 参数
+#[derive(Debug, Default, Clone)]
+pub struct TlsOpts {
+    /// 将 TLS 密钥写入 SSLKEYLOGFILE，仅用于调试
+    pub keylog: bool,
+    pub min_version: TlsVersion,
+    /// 允许的加密套件，为空时使用 rustls 默认值
+    pub cipher_suites: Vec<SupportedCipherSuite>,
+    /// 除 webpki 内置根证书外额外信任的 CA 证书
+    pub ca_certs: Vec<CertificateDer<'static>>,
+    /// 证书链中须有一张证书的 tbsCertificate SHA256 与其一匹配，为空时不校验
+    pub pinned_hashes: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TlsVersion {
+    #[default]
+    Tls12,
+    Tls13,
+}
+
+impl FromStr for TlsVersion {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1.2" => Ok(TlsVersion::Tls12),
+            "1.3" => Ok(TlsVersion::Tls13),
+            _ => anyhow::bail!("Unsupported TLS version '{}'", s),
+        }
+    }
+}
+
+/// 按名称查找 rustls 支持的加密套件，例如 TLS13_AES_128_GCM_SHA256
+pub fn find_cipher_suite(name: &str) -> anyhow::Result<SupportedCipherSuite> {
+    ALL_CIPHER_SUITES
+        .iter()
+        .find(|it| format!("{:?}", it.suite()).eq_ignore_ascii_case(name))
+        .copied()
+        .ok_or_else(|| anyhow::format_err!("Unknown cipher suite '{}'", name))
+}
+
+impl Default for ResolveOpts {
+    fn default() -> Self {
+        Self {
+            max_payload_size: 4096,
+            udp_retries: 2,
+            udp_retry_timeout: Duration::from_millis(2000),
+            tcp_fallback: false,
+            prefer_tcp: false,
+            edns_cookies: false,
+            connect_timeout: Duration::from_millis(5000),
+            tcp_fastopen: false,
+            connect_addr: None,
+            dot_max_inflight: None,
+            tls: TlsOpts::default(),
+        }
+    }
+}
+
+pub async fn resolve(server: &str, bytes: &[u8], opts: &ResolveOpts) -> anyhow::Result<Vec<u8>> {
+    let target = upstream_target(server);
+    match split_addr(server).0 {
+        Some("tls") => DoT::new(&target, opts)?.resolve(bytes).await,
+        Some("https") => DoH::new(&target, opts)?.resolve(bytes).await,
+        Some("quic") => anyhow::bail!("DNS over QUIC upstream '{}' is not supported", server),
+        Some("sdns") => resolve_stamp(&Stamp::from_str(server)?, bytes, opts).await,
+        _ => Generic::new(&target, opts).resolve(bytes).await,
+    }
+}
+
+/// 按 DNS Stamp 中的协议、地址及证书哈希查询上游
+async fn resolve_stamp(stamp: &Stamp, bytes: &[u8], opts: &ResolveOpts) -> anyhow::Result<Vec<u8>> {
+    let mut opts = opts.clone();
+    opts.connect_addr = stamp.addr;
+    opts.tls.pinned_hashes = stamp.hashes.clone();
+    let target = stamp.target();
+    match stamp.protocol {
+        StampProtocol::DoT => DoT::new(&target, &opts)?.resolve(bytes).await,
+        StampProtocol::DoH => DoH::new(&target, &opts)?.resolve(bytes).await,
+        StampProtocol::Plain => Generic::new(&target, &opts).resolve(bytes).await,
+    }
+}
+
+/// 各协议上游的默认端口
+pub fn default_port(scheme: &str) -> u16 {
+    match scheme {
+        "tls" | "quic" => 853,
+        "https" => 443,
+        "http" => 80,
+        _ => 53,
+    }
+}
+
+/// 补全上游地址的默认端口，DoH 地址补全 /dns-query 路径，端口由 URL 解析时补全
+fn upstream_target(server: &str) -> String {
+    let (protocol, addr, port) = split_addr(server);
+    match protocol {
+        Some("https") if server.ends_with("/dns-query") => server.to_string(),
+        Some("https") => format!("{}/dns-query", server),
+        Some(protocol @ ("tls" | "quic")) => match port {
+            Some(port) => format!("{}://{}:{}", protocol, addr, port),
+            None => format!("{}://{}:{}", protocol, addr, default_port(protocol)),
+        },
+        _ => match port {
+            Some(port) => format!("{}:{}", addr, port),
+            None => format!("{}:{}", addr, default_port("udp")),
+        },
     }
 }
 
@@ -51,3 +166,33 @@ fn split_addr(input: &str) -> (Option<&str>, &str, Option<&str>) {
 
     (protocol, addr, port)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use url::Url;
+
+    #[test]
+    fn default_ports_per_scheme() {
+        let cases = [
+            ("1.1.1.1", "1.1.1.1:53"),
+            ("1.1.1.1:5353", "1.1.1.1:5353"),
+            ("tls://1.1.1.1", "tls://1.1.1.1:853"),
+            ("tls://1.1.1.1:8853", "tls://1.1.1.1:8853"),
+            ("quic://dns.adguard.com", "quic://dns.adguard.com:853"),
+            ("quic://dns.adguard.com:784", "quic://dns.adguard.com:784"),
+            ("https://1.1.1.1", "https://1.1.1.1/dns-query"),
+            ("https://1.1.1.1:8443/dns-query", "https://1.1.1.1:8443/dns-query"),
+        ];
+        for (server, target) in cases {
+            assert_eq!(upstream_target(server), target);
+        }
+        let port = |target: &str| {
+            let url = Url::parse(target).unwrap();
+            url.port().unwrap_or(default_port(url.scheme()))
+        };
+        assert_eq!(port(&upstream_target("https://1.1.1.1")), 443);
+        assert_eq!(port(&upstream_target("https://1.1.1.1:8443")), 8443);
+        assert_eq!(port(&upstream_target("tls://1.1.1.1")), 853);
+    }
+}
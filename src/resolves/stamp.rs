@@ -0,0 +1,208 @@
+use crate::resolves::default_port;
+use anyhow::Context;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+/// DNS Stamp 中描述的上游协议
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StampProtocol {
+    Plain,
+    DoH,
+    DoT,
+}
+
+/// 解析后的 DNS Stamp（sdns://），格式见 https://dnscrypt.info/stamps-specifications
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stamp {
+    pub protocol: StampProtocol,
+    /// 连接地址，为空时解析 hostname
+    pub addr: Option<IpAddr>,
+    pub port: Option<u16>,
+    /// 证书链中某张证书 tbsCertificate 的 SHA256
+    pub hashes: Vec<Vec<u8>>,
+    pub hostname: String,
+    pub path: String,
+}
+
+impl Stamp {
+    /// 转换为对应解析器使用的上游地址
+    pub fn target(&self) -> String {
+        match self.protocol {
+            StampProtocol::DoH => match self.port {
+                Some(port) => format!("https://{}:{}{}", self.hostname, port, self.path),
+                None => format!("https://{}{}", self.hostname, self.path),
+            },
+            StampProtocol::DoT => format!(
+                "tls://{}:{}",
+                self.hostname,
+                self.port.unwrap_or(default_port("tls"))
+            ),
+            StampProtocol::Plain => SocketAddr::new(
+                self.addr.unwrap_or(IpAddr::from([0, 0, 0, 0])),
+                self.port.unwrap_or(default_port("udp")),
+            )
+            .to_string(),
+        }
+    }
+}
+
+impl FromStr for Stamp {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let encoded = s
+            .strip_prefix("sdns://")
+            .with_context(|| format!("Invalid DNS stamp '{}'", s))?;
+        let bytes = URL_SAFE_NO_PAD
+            .decode(encoded)
+            .with_context(|| format!("Invalid base64 in DNS stamp '{}'", s))?;
+        parse(&bytes).with_context(|| format!("Invalid DNS stamp '{}'", s))
+    }
+}
+
+fn parse(bytes: &[u8]) -> anyhow::Result<Stamp> {
+    let mut reader = Reader(bytes);
+    let protocol = match reader.byte()? {
+        0x00 => StampProtocol::Plain,
+        0x02 => StampProtocol::DoH,
+        0x03 => StampProtocol::DoT,
+        0x01 => anyhow::bail!("DNSCrypt stamps are not supported"),
+        0x04 => anyhow::bail!("DNS over QUIC stamps are not supported"),
+        other => anyhow::bail!("Unsupported stamp protocol 0x{:02x}", other),
+    };
+    // props：DNSSEC、不记录日志、不过滤等标志，解析时忽略
+    reader.take(8)?;
+    let (addr, addr_port) = parse_addr(&reader.lp_str()?)?;
+    if protocol == StampProtocol::Plain {
+        if addr.is_none() {
+            anyhow::bail!("Missing server address");
+        }
+        return Ok(Stamp {
+            protocol,
+            addr,
+            port: addr_port,
+            hashes: Vec::new(),
+            hostname: String::new(),
+            path: String::new(),
+        });
+    }
+    let hashes = reader.vlp()?;
+    if hashes.iter().any(|it| it.len() != 32) {
+        anyhow::bail!("Certificate hashes must be SHA256 digests");
+    }
+    let host = reader.lp_str()?;
+    let (hostname, host_port) = match host.rsplit_once(':') {
+        Some((name, port)) => (name.to_string(), Some(port.parse::<u16>()?)),
+        None => (host, None),
+    };
+    if hostname.is_empty() {
+        anyhow::bail!("Missing server hostname");
+    }
+    let path = match protocol {
+        StampProtocol::DoH => reader.lp_str()?,
+        _ => String::new(),
+    };
+    Ok(Stamp {
+        protocol,
+        addr,
+        port: addr_port.or(host_port),
+        hashes,
+        hostname,
+        path,
+    })
+}
+
+/// 解析 "ip"、"ip:port"、"[ipv6]"、"[ipv6]:port" 形式的地址，允许为空
+fn parse_addr(addr: &str) -> anyhow::Result<(Option<IpAddr>, Option<u16>)> {
+    if addr.is_empty() {
+        return Ok((None, None));
+    }
+    if let Ok(addr) = addr.parse::<SocketAddr>() {
+        return Ok((Some(addr.ip()), Some(addr.port())));
+    }
+    let ip = addr.trim_start_matches('[').trim_end_matches(']');
+    let ip = ip
+        .parse::<IpAddr>()
+        .with_context(|| format!("Invalid server address '{}'", addr))?;
+    Ok((Some(ip), None))
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        if self.0.len() < len {
+            anyhow::bail!("Unexpected end of stamp");
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head)
+    }
+    fn byte(&mut self) -> anyhow::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+    fn lp_str(&mut self) -> anyhow::Result<String> {
+        let len = self.byte()? as usize;
+        Ok(std::str::from_utf8(self.take(len)?)?.to_string())
+    }
+    /// 可变长度列表，长度字节最高位表示后面还有元素
+    fn vlp(&mut self) -> anyhow::Result<Vec<Vec<u8>>> {
+        let mut items = Vec::new();
+        loop {
+            let len = self.byte()?;
+            let item = self.take((len & 0x7f) as usize)?;
+            if !item.is_empty() {
+                items.push(item.to_vec());
+            }
+            if len & 0x80 == 0 {
+                return Ok(items);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PIN: &str = "28a608c7ef2dc2a07ab074d4fc24221ba0e84685f880c3b49891a491fd657a0a";
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|it| format!("{:02x}", it)).collect()
+    }
+
+    #[test]
+    fn decode_doh_stamp() {
+        // Cloudflare 公开的 DoH stamp
+        let stamp = Stamp::from_str(
+            "sdns://AgcAAAAAAAAABzEuMC4wLjEAEmRucy5jbG91ZGZsYXJlLmNvbQovZG5zLXF1ZXJ5",
+        )
+        .unwrap();
+        assert_eq!(stamp.protocol, StampProtocol::DoH);
+        assert_eq!(stamp.addr, Some("1.0.0.1".parse().unwrap()));
+        assert!(stamp.hashes.is_empty());
+        assert_eq!(stamp.target(), "https://dns.cloudflare.com/dns-query");
+
+        let stamp = Stamp::from_str("sdns://AgcAAAAAAAAABzEuMS4xLjEgKKYIx-8twqB6sHTU_CQiG6DoRoX4gMO0mJGkkf1legoSY2xvdWRmbGFyZS1kbnMuY29tCi9kbnMtcXVlcnk").unwrap();
+        assert_eq!(stamp.addr, Some("1.1.1.1".parse().unwrap()));
+        assert_eq!(stamp.hashes.iter().map(|it| hex(it)).collect::<Vec<_>>(), vec![PIN]);
+        assert_eq!(stamp.target(), "https://cloudflare-dns.com/dns-query");
+    }
+
+    #[test]
+    fn decode_other_stamps() {
+        let stamp = Stamp::from_str("sdns://AwAAAAAAAAAAFlsyNjA2OjQ3MDA6OjExMTFdOjg4NTOgKKYIx-8twqB6sHTU_CQiG6DoRoX4gMO0mJGkkf1legogKKYIx-8twqB6sHTU_CQiG6DoRoX4gMO0mJGkkf1legoPb25lLm9uZS5vbmUub25l").unwrap();
+        assert_eq!(stamp.protocol, StampProtocol::DoT);
+        assert_eq!(stamp.addr, Some("2606:4700::1111".parse().unwrap()));
+        assert_eq!(stamp.hashes.len(), 2);
+        assert_eq!(stamp.target(), "tls://one.one.one.one:8853");
+
+        let stamp = Stamp::from_str("sdns://AAAAAAAAAAAABzkuOS45Ljk").unwrap();
+        assert_eq!(stamp.protocol, StampProtocol::Plain);
+        assert_eq!(stamp.target(), "9.9.9.9:53");
+
+        assert!(Stamp::from_str("sdns://AQ").is_err());
+        assert!(Stamp::from_str("sdns://AgcAAAAAAAAABzEuMC4wLjEA").is_err());
+    }
+}
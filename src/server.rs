@@ -1,14 +1,25 @@
 use crate::cache::Cache;
-use crate::config::Config;
+use crate::config::{validate_blocklist, Config};
 use crate::handler::Handler;
 use crate::logs::LogWriter;
+use crate::proxy_protocol;
+use crate::resolves::{dot, http, ResolveOpts, TlsOpts};
+use crate::stats;
 use crate::MAX_CONNECTIONS;
+use anyhow::Context;
 use futures_util::FutureExt;
 use std::net::SocketAddr;
+use std::path::Path;
+#[cfg(unix)]
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream, UdpSocket},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpSocket, TcpStream, UdpSocket},
     signal,
     sync::Semaphore,
     task::JoinSet,
@@ -17,16 +28,10 @@ use tokio_util::sync::CancellationToken;
 
 const MAX_UDP_PACKET_SIZE: usize = 4096;
 
-trait ServerTrait {
-    async fn run(&mut self) -> anyhow::Result<()>;
-    async fn reply(&mut self, bytes: &[u8]) -> anyhow::Result<()>;
-}
-
 pub struct UdpServer {
     socket: Arc<UdpSocket>,
     limit_connections: Arc<Semaphore>,
     shutdown_signal: CancellationToken,
-    shared_buf: [u8; MAX_UDP_PACKET_SIZE],
     cache: Arc<Cache>,
     config: Arc<Config>,
 }
@@ -47,7 +52,6 @@ impl UdpServer {
                 },
                 _ = shutdown_signal.cancelled() => break,
             };
-            let bytes = req.to_vec();
             let group = self.config.access().attribute_group(&addr.ip());
             let mut handler =
                 Handler::new("udp", addr, group, self.cache.clone(), self.config.clone());
@@ -57,7 +61,7 @@ impl UdpServer {
                     socket.send_to(&bytes, addr).await?;
                     Ok(())
                 };
-                handler.run(bytes, ret).await;
+                handler.run(req, ret).await;
                 drop(permit)
             });
             while FutureExt::now_or_never(join_set.join_next())
@@ -71,9 +75,12 @@ impl UdpServer {
             anyhow::bail!("Unexpected close of UDP socket")
         }
     }
-    pub async fn accept(&mut self) -> anyhow::Result<(&[u8], SocketAddr)> {
-        let (len, addr) = self.socket.recv_from(&mut self.shared_buf).await?;
-        Ok((&self.shared_buf[..len], addr))
+    /// 每个数据报使用独立的缓冲区，以便直接移交给处理任务
+    pub async fn accept(&self) -> anyhow::Result<(Vec<u8>, SocketAddr)> {
+        let mut buf = vec![0; MAX_UDP_PACKET_SIZE];
+        let (len, addr) = self.socket.recv_from(&mut buf).await?;
+        buf.truncate(len);
+        Ok((buf, addr))
     }
 }
 
@@ -91,7 +98,7 @@ impl TcpServer {
         loop {
             let permit = self.limit_connections.clone().acquire_owned().await?;
             let shutdown_signal = self.shutdown_signal.clone();
-            let (mut stream, req, addr) = tokio::select! {
+            let (stream, addr) = tokio::select! {
                 v = self.accept()  => match v{
                     Ok(v) => v,
                     Err(err) => {
@@ -101,18 +108,18 @@ impl TcpServer {
                 },
                 _ = shutdown_signal.cancelled() => break,
             };
-            let bytes = req.to_vec();
-            let group = self.config.access().attribute_group(&addr.ip());
-            let mut handler =
-                Handler::new("tcp", addr, group, self.cache.clone(), self.config.clone());
+            let cache = self.cache.clone();
+            let config = self.config.clone();
             join_set.spawn(async move {
-                let ret = |bytes: Vec<u8>, _addr| async move {
-                    let len_bytes = (bytes.len() as u16).to_be_bytes();
-                    stream.write_all(&len_bytes).await?;
-                    stream.write_all(&bytes).await?;
-                    Ok(())
+                let Some((stream, addr)) = Self::proxied(stream, addr, &config).await else {
+                    return;
+                };
+                let (group, limits) = {
+                    let config = config.access();
+                    (config.attribute_group(&addr.ip()), StreamLimits::new(&config))
                 };
-                handler.run(bytes, ret).await;
+                let handler = Handler::new("tcp", addr, group, cache, config);
+                serve_stream(stream, handler, addr, limits).await;
                 drop(permit);
             });
             while FutureExt::now_or_never(join_set.join_next())
@@ -126,15 +133,301 @@ impl TcpServer {
             anyhow::bail!("Unexpected close of TCP connection")
         }
     }
-    pub async fn accept(&mut self) -> anyhow::Result<(TcpStream, Vec<u8>, SocketAddr)> {
-        let (mut stream, addr) = self.socket.accept().await?;
+    pub async fn accept(&mut self) -> anyhow::Result<(TcpStream, SocketAddr)> {
+        Ok(self.socket.accept().await?)
+    }
+    /// 配置 proxy-protocol 时读取连接开头的 PROXY 协议头，以真实的客户端地址归属分组及记录日志；
+    /// 在连接的任务中读取，避免慢速客户端阻塞 accept 循环。
+    /// 不在 proxy-protocol-from 中的对端或头部无效时丢弃连接
+    async fn proxied(
+        mut stream: TcpStream,
+        addr: SocketAddr,
+        config: &Config,
+    ) -> Option<(TcpStream, SocketAddr)> {
+        let (enabled, trusted, timeout) = {
+            let config = config.access();
+            (
+                config.metadata.proxy_protocol,
+                config.metadata.is_trusted_proxy(&addr.ip()),
+                config.metadata.tcp_read_timeout,
+            )
+        };
+        if !enabled {
+            return Some((stream, addr));
+        }
+        if !trusted {
+            tracing::warn!("Dropped connection from untrusted proxy {}", addr);
+            return None;
+        }
+        match proxy_protocol::read_header(&mut stream, timeout).await {
+            Ok(source) => Some((stream, source.unwrap_or(addr))),
+            Err(err) => {
+                tracing::warn!("Dropped connection from {}: {:?}", addr, err);
+                None
+            }
+        }
+    }
+}
+
+/// Unix 套接字上的客户端没有网络地址，按本机回环地址归属分组
+#[cfg(unix)]
+const UNIX_PEER: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 0);
+
+/// 监听 Unix 域套接字，协议与 TCP 相同，适用于 sidecar 部署
+#[cfg(unix)]
+pub struct UnixServer {
+    socket: UnixListener,
+    path: PathBuf,
+    limit_connections: Arc<Semaphore>,
+    shutdown_signal: CancellationToken,
+    cache: Arc<Cache>,
+    config: Arc<Config>,
+}
+
+#[cfg(unix)]
+impl UnixServer {
+    pub async fn run(&mut self) -> anyhow::Result<()> {
+        let mut join_set = JoinSet::new();
+        loop {
+            let permit = self.limit_connections.clone().acquire_owned().await?;
+            let shutdown_signal = self.shutdown_signal.clone();
+            let stream = tokio::select! {
+                v = self.accept()  => match v{
+                    Ok(v) => v,
+                    Err(err) => {
+                        tracing::error!("{:?}", err);
+                        continue;
+                    }
+                },
+                _ = shutdown_signal.cancelled() => break,
+            };
+            let (group, limits) = {
+                let config = self.config.access();
+                (config.attribute_group(&UNIX_PEER.ip()), StreamLimits::new(&config))
+            };
+            let handler =
+                Handler::new("unix", UNIX_PEER, group, self.cache.clone(), self.config.clone());
+            join_set.spawn(async move {
+                serve_stream(stream, handler, UNIX_PEER, limits).await;
+                drop(permit);
+            });
+            while FutureExt::now_or_never(join_set.join_next())
+                .flatten()
+                .is_some()
+            {}
+        }
+        if self.shutdown_signal.is_cancelled() {
+            Ok(())
+        } else {
+            anyhow::bail!("Unexpected close of Unix socket")
+        }
+    }
+    pub async fn accept(&mut self) -> anyhow::Result<UnixStream> {
+        Ok(self.socket.accept().await?.0)
+    }
+}
+
+/// 退出时删除套接字文件
+#[cfg(unix)]
+impl Drop for UnixServer {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            tracing::warn!("Failed to remove unix socket {:?}: {}", self.path, err);
+        }
+    }
+}
+
+/// 绑定 Unix 域套接字，先删除上次运行遗留的套接字文件；路径上是其它类型的文件时报错，不删除
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> anyhow::Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            anyhow::bail!("Path {:?} exists and is not a unix socket", path);
+        }
+        std::fs::remove_file(path)
+            .with_context(|| format!("Unable to remove stale unix socket {:?}", path))?;
+    }
+    UnixListener::bind(path).with_context(|| format!("could not bind to unix: {:?}", path))
+}
+
+/// 流式连接的读取限制
+struct StreamLimits {
+    max_size: usize,
+    read_timeout: Duration,
+    /// 配置 edns-tcp-keepalive 时，连接在空闲超时前可继续发送查询
+    keepalive: Option<Duration>,
+}
+
+impl StreamLimits {
+    fn new(config: &crate::config::Inner) -> Self {
+        Self {
+            max_size: config.metadata.tcp_max_message_size,
+            read_timeout: config.metadata.tcp_read_timeout,
+            keepalive: config.metadata.edns_tcp_keepalive,
+        }
+    }
+}
+
+/// 处理流式连接上的请求，应答带两字节长度前缀；未配置 keepalive 时仅处理一个请求
+async fn serve_stream<S>(mut stream: S, mut handler: Handler, addr: SocketAddr, limits: StreamLimits)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut timeout = limits.read_timeout;
+    let mut first = true;
+    loop {
+        // 在任务中读取请求，避免慢速客户端阻塞 accept 循环
+        let bytes = match read_request(&mut stream, limits.max_size, timeout).await {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                // 后续请求读取失败即连接空闲超时或被客户端关闭
+                if first {
+                    tracing::warn!("Dropped stream request from {}: {:?}", addr, err);
+                }
+                return;
+            }
+        };
+        handler.start = tokio::time::Instant::now();
+        let writer = &mut stream;
+        let ret = move |bytes: Vec<u8>, _addr| async move { write_response(writer, &bytes).await };
+        handler.run(bytes, ret).await;
+        match limits.keepalive {
+            Some(keepalive) => timeout = keepalive,
+            None => return,
+        }
+        first = false;
+    }
+}
+
+/// 写入带两字节长度前缀的应答，超出长度前缀所能表示的应答不写入，避免破坏数据流
+async fn write_response<S: AsyncWrite + Unpin>(stream: &mut S, bytes: &[u8]) -> anyhow::Result<()> {
+    let len = u16::try_from(bytes.len())
+        .map_err(|_| anyhow::format_err!("Response size {} exceeds 65535 bytes", bytes.len()))?;
+    stream.write_all(&len.to_be_bytes()).await?;
+    stream.write_all(bytes).await?;
+    Ok(())
+}
+
+/// 读取带长度前缀的 DNS 消息，拒绝超长或超时的请求
+async fn read_request<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    max_size: usize,
+    timeout: Duration,
+) -> anyhow::Result<Vec<u8>> {
+    tokio::time::timeout(timeout, async {
         let mut len_bytes = [0; 2];
         stream.read_exact(&mut len_bytes).await?;
         let len = u16::from_be_bytes(len_bytes) as usize;
+        if len > max_size {
+            anyhow::bail!("Message size {} exceeds limit {}", len, max_size);
+        }
         let mut buf = vec![0; len];
         stream.read_exact(&mut buf).await?;
-        Ok((stream, buf, addr))
+        Ok(buf)
+    })
+    .await
+    .with_context(|| format!("Read timeout after {}ms", timeout.as_millis()))?
+}
+
+/// 绑定 UDP 监听地址；Linux 上 workers 大于 1 时以 SO_REUSEPORT 绑定多个套接字，由内核分发数据报
+pub async fn bind_udp(
+    bind: &str,
+    workers: usize,
+    v6only: Option<bool>,
+) -> anyhow::Result<Vec<UdpSocket>> {
+    let mut addr = lookup_bind(bind).await?;
+    #[cfg(not(target_os = "linux"))]
+    let workers = if workers > 1 {
+        tracing::warn!("udp-workers requires SO_REUSEPORT on Linux, using a single socket");
+        1
+    } else {
+        workers
+    };
+    let mut sockets = Vec::with_capacity(workers);
+    for _ in 0..workers {
+        let socket = bind_udp_socket(addr, workers > 1, v6only)
+            .with_context(|| format!("could not bind to udp: {}", bind))?;
+        // 端口为 0 时其余套接字绑定到第一个套接字分配的端口
+        addr = socket.local_addr()?;
+        sockets.push(socket);
     }
+    Ok(sockets)
+}
+
+async fn lookup_bind(bind: &str) -> anyhow::Result<SocketAddr> {
+    tokio::net::lookup_host(bind)
+        .await
+        .with_context(|| format!("could not resolve bind address: {}", bind))?
+        .next()
+        .with_context(|| format!("could not resolve bind address: {}", bind))
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+fn bind_udp_socket(
+    addr: SocketAddr,
+    reuseport: bool,
+    v6only: Option<bool>,
+) -> anyhow::Result<UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    #[cfg(target_os = "linux")]
+    if reuseport {
+        socket
+            .set_reuse_port(true)
+            .with_context(|| "could not set SO_REUSEPORT")?;
+    }
+    if let (SocketAddr::V6(_), Some(v6only)) = (addr, v6only) {
+        socket
+            .set_only_v6(v6only)
+            .with_context(|| "could not set IPV6_V6ONLY")?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+/// 以指定的监听队列长度绑定 TCP 端口
+pub async fn bind_tcp(
+    bind: &str,
+    backlog: u32,
+    v6only: Option<bool>,
+) -> anyhow::Result<TcpListener> {
+    let addr = lookup_bind(bind).await?;
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4(),
+        SocketAddr::V6(_) => TcpSocket::new_v6(),
+    }?;
+    // 与 TcpListener::bind 一致，允许重启后立即复用端口
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    if let (SocketAddr::V6(_), Some(v6only)) = (addr, v6only) {
+        socket2::SockRef::from(&socket)
+            .set_only_v6(v6only)
+            .with_context(|| "could not set IPV6_V6ONLY")?;
+    }
+    socket
+        .bind(addr)
+        .and_then(|_| socket.listen(backlog))
+        .with_context(|| format!("could not bind to tcp: {}", bind))
+}
+
+/// 设置 UDP 套接字的收发缓冲区，返回内核实际分配的大小
+pub fn apply_socket_buffers(
+    socket: &UdpSocket,
+    rcvbuf: Option<usize>,
+    sndbuf: Option<usize>,
+) -> anyhow::Result<(usize, usize)> {
+    let sock = socket2::SockRef::from(socket);
+    if let Some(size) = rcvbuf {
+        sock.set_recv_buffer_size(size)
+            .with_context(|| format!("could not set SO_RCVBUF to {}", size))?;
+    }
+    if let Some(size) = sndbuf {
+        sock.set_send_buffer_size(size)
+            .with_context(|| format!("could not set SO_SNDBUF to {}", size))?;
+    }
+    Ok((sock.recv_buffer_size()?, sock.send_buffer_size()?))
 }
 
 pub struct ServerArgs {
@@ -142,9 +435,97 @@ pub struct ServerArgs {
     pub logs: Arc<LogWriter>,
 }
 
+/// 重载配置并输出结果，失败时继续使用原有配置
+pub fn reload_config(config: &Config) {
+    match config.reload_changed() {
+        Ok(true) => tracing::info!("Hosts and blocklist files reloaded successfully."),
+        Ok(false) => tracing::info!("Config reloaded successfully."),
+        Err(err) => tracing::error!("Failed to reload config, keep the running config: {err:?}"),
+    }
+}
+
+/// 下载的拦截列表的最大长度
+const MAX_BLOCKLIST_SIZE: usize = 64 * 1024 * 1024;
+const BLOCKLIST_FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// 启动时及按固定间隔下载 URL 拦截列表，interval 为 None 时仅下载一次
+pub async fn refresh_blocklists(config: Arc<Config>, interval: Option<Duration>) {
+    update_blocklists(&config).await;
+    let Some(interval) = interval else {
+        return;
+    };
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        update_blocklists(&config).await;
+    }
+}
+
+/// 下载 URL 拦截列表并写入缓存文件，随后只重新读取这些文件；下载或校验失败的列表保留原有内容
+async fn update_blocklists(config: &Config) {
+    let (sources, opts) = {
+        let config = config.access();
+        let opts = ResolveOpts {
+            connect_timeout: config.metadata.connect_timeout,
+            tcp_fastopen: config.metadata.tcp_fastopen,
+            tls: TlsOpts {
+                min_version: config.metadata.tls_min_version,
+                cipher_suites: config.metadata.tls_cipher_suites.clone(),
+                ca_certs: config.metadata.ca_certs.clone(),
+                ..TlsOpts::default()
+            },
+            ..ResolveOpts::default()
+        };
+        (config.blocklist_sources(), opts)
+    };
+    let mut updated = Vec::new();
+    for (name, url, path) in sources {
+        match fetch_blocklist(&url, &path, &opts).await {
+            Ok(count) => {
+                tracing::info!("Blocklist '{}' downloaded, {} domains", name, count);
+                updated.push(path);
+            }
+            Err(err) => tracing::warn!(
+                "Failed to download blocklist '{}' from {}, keep the last list: {:?}",
+                name,
+                url,
+                err
+            ),
+        }
+    }
+    if updated.is_empty() {
+        return;
+    }
+    if let Err(err) = config.reload_paths(&updated) {
+        tracing::error!("Failed to reload downloaded blocklists: {err:?}");
+    }
+}
+
+async fn fetch_blocklist(url: &Url, path: &Path, opts: &ResolveOpts) -> anyhow::Result<usize> {
+    let body = tokio::time::timeout(
+        BLOCKLIST_FETCH_TIMEOUT,
+        http::get(url, opts, MAX_BLOCKLIST_SIZE),
+    )
+    .await
+    .with_context(|| format!("Timeout after {}s", BLOCKLIST_FETCH_TIMEOUT.as_secs()))??;
+    let text = String::from_utf8(body).with_context(|| "Blocklist is not valid UTF-8")?;
+    let count = validate_blocklist(&text)?;
+    // 先写入临时文件再替换，避免读取到写入一半的缓存文件
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    tokio::fs::write(&partial, text)
+        .await
+        .with_context(|| format!("Unable to write blocklist cache {:?}", partial))?;
+    tokio::fs::rename(&partial, path)
+        .await
+        .with_context(|| format!("Unable to write blocklist cache {:?}", path))?;
+    Ok(count)
+}
+
 pub async fn run_until_done(
     args: ServerArgs,
-    binds: (TcpListener, UdpSocket),
+    binds: (TcpListener, Vec<UdpSocket>),
 ) -> anyhow::Result<()> {
     let cache = Arc::new(Cache::with_capacity(
         args.config.access().metadata.cache_size,
@@ -152,13 +533,12 @@ pub async fn run_until_done(
     let mut join_set = JoinSet::new();
     let shutdown_signal = CancellationToken::new();
     let limit_connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
-    // register udp
-    {
+    // register udp, one server per SO_REUSEPORT socket
+    for socket in binds.1 {
         let mut udp_server = UdpServer {
-            socket: Arc::new(binds.1),
+            socket: Arc::new(socket),
             limit_connections: limit_connections.clone(),
             shutdown_signal: shutdown_signal.clone(),
-            shared_buf: [0; MAX_UDP_PACKET_SIZE],
             config: args.config.clone(),
             cache: cache.clone(),
         };
@@ -175,6 +555,43 @@ pub async fn run_until_done(
         };
         join_set.spawn(async move { tcp_server.run().await });
     }
+    // register unix socket
+    #[cfg(unix)]
+    if let Some(path) = args.config.access().metadata.unix_bind.clone() {
+        let mut unix_server = UnixServer {
+            socket: bind_unix(&path)?,
+            path,
+            limit_connections: limit_connections.clone(),
+            shutdown_signal: shutdown_signal.clone(),
+            config: args.config.clone(),
+            cache: cache.clone(),
+        };
+        tracing::info!("unix://{}", unix_server.path.display());
+        join_set.spawn(async move { unix_server.run().await });
+    }
+    // register periodic stats report
+    if let Some(interval) = args.config.access().metadata.stats_interval {
+        join_set.spawn(async move {
+            stats::report(interval).await;
+            Ok(())
+        });
+    }
+    // register blocklist downloads
+    {
+        let config = args.config.clone();
+        let interval = config.access().metadata.blocklist_update_interval;
+        join_set.spawn(async move {
+            refresh_blocklists(config, interval).await;
+            Ok(())
+        });
+    }
+    // register DoT idle connection sweep
+    if let Some(max_idle) = args.config.access().metadata.dot_idle_timeout {
+        join_set.spawn(async move {
+            dot::sweep(max_idle).await;
+            Ok(())
+        });
+    }
     // register ctrl+c signal
     {
         let shutdown_signal = shutdown_signal.clone();
@@ -186,6 +603,7 @@ pub async fn run_until_done(
     }
     // register usr1 signal to reopen log file when received
     // register sighup signal to reload config when received
+    // register usr2 signal to reload mmdb when received
     #[cfg(target_os = "linux")]
     {
         let shutdown_signal = shutdown_signal.clone();
@@ -193,15 +611,20 @@ pub async fn run_until_done(
         join_set.spawn(async move {
             let mut sighup = signal::unix::signal(signal::unix::SignalKind::hangup())?;
             let mut usr1 = signal::unix::signal(signal::unix::SignalKind::user_defined1())?;
+            let mut usr2 = signal::unix::signal(signal::unix::SignalKind::user_defined2())?;
             let mut sigterm = signal::unix::signal(signal::unix::SignalKind::terminate())?;
             loop {
                 tokio::select! {
                     _ = sighup.recv() => {
                         tracing::debug!("Received SIGNUP signal, start reloading config");
-                            match args.config.reload() {
-                                Ok(_) => tracing::info!("Config reloaded successfully."),
-                                Err(err) => tracing::error!("Failed to reload config: {err:?}")
-                            }
+                        reload_config(&args.config);
+                    }
+                    _ = usr2.recv() => {
+                        tracing::debug!("Received USR2 signal, start reloading mmdb");
+                        match args.config.reload_mmdb() {
+                            Ok(_) => tracing::info!("Mmdb reloaded successfully."),
+                            Err(err) => tracing::error!("Failed to reload mmdb: {err:?}")
+                        }
                     }
                     _ = sigterm.recv() => {
                         tracing::debug!("Received SIGTERM signal, start terminating");
@@ -233,3 +656,500 @@ pub async fn run_until_done(
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::EDNS_TCP_KEEPALIVE;
+    use hickory_proto::op::{Edns, Message, Query};
+    use hickory_proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+    use hickory_proto::rr::{Name, RecordType};
+    use hickory_proto::serialize::binary::BinDecodable;
+    use std::collections::HashSet;
+
+    #[tokio::test]
+    async fn edns_tcp_keepalive() {
+        let config = Arc::new(
+            Config::from_text(
+                "[server]\ndefault 127.0.0.1:1\n[hosts]\n10.0.0.1 app.lan\n[metadata]\nedns-tcp-keepalive 5000\n",
+            )
+            .unwrap(),
+        );
+        let (mut client, server) = tokio::io::duplex(4096);
+        let addr = "127.0.0.1:5353".parse().unwrap();
+        let handler = Handler::new(
+            "tcp",
+            addr,
+            "default".to_string(),
+            Arc::new(Cache::with_capacity(0)),
+            config.clone(),
+        );
+        let limits = StreamLimits::new(&config.access());
+        let served = tokio::spawn(serve_stream(server, handler, addr, limits));
+        async fn exchange(client: &mut tokio::io::DuplexStream, keepalive: bool) -> Option<Vec<u8>> {
+            let mut req = Message::new();
+            req.set_id(0x1234)
+                .set_recursion_desired(true)
+                .add_query(Query::query(Name::from_ascii("app.lan.").unwrap(), RecordType::A));
+            let mut edns = Edns::new();
+            if keepalive {
+                edns.options_mut()
+                    .insert(EdnsOption::Unknown(EDNS_TCP_KEEPALIVE, Vec::new()));
+            }
+            req.set_edns(edns);
+            write_response(client, &req.to_vec().unwrap()).await.unwrap();
+            let len = client.read_u16().await.unwrap();
+            let mut buf = vec![0; len as usize];
+            client.read_exact(&mut buf).await.unwrap();
+            let res = Message::from_bytes(&buf).unwrap();
+            match res.extensions().as_ref().unwrap().option(EdnsCode::from(EDNS_TCP_KEEPALIVE)) {
+                Some(EdnsOption::Unknown(_, data)) => Some(data.clone()),
+                _ => None,
+            }
+        }
+        // 超时时间以 100 毫秒为单位
+        assert_eq!(exchange(&mut client, true).await, Some(vec![0, 50]));
+        // 同一连接上的后续查询，客户端未携带选项时不附带
+        assert_eq!(exchange(&mut client, false).await, None);
+        drop(client);
+        served.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn oversized_tcp_response() {
+        let (mut client, mut server) = tokio::io::duplex(1 << 20);
+        let err = write_response(&mut server, &vec![0; 70000]).await.unwrap_err();
+        assert!(err.to_string().contains("exceeds 65535 bytes"), "{err}");
+        write_response(&mut server, &[0x12, 0x34]).await.unwrap();
+        drop(server);
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, vec![0x00, 0x02, 0x12, 0x34]);
+    }
+
+    #[tokio::test]
+    async fn udp_concurrent_datagrams() {
+        const QUERIES: u16 = 500;
+        let config = Arc::new(
+            Config::from_text("[server]\ndefault 127.0.0.1:1\n[hosts]\n10.0.0.1 app.lan\n")
+                .unwrap(),
+        );
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        let shutdown_signal = CancellationToken::new();
+        let mut server = UdpServer {
+            socket: Arc::new(socket),
+            limit_connections: Arc::new(Semaphore::new(MAX_CONNECTIONS)),
+            shutdown_signal: shutdown_signal.clone(),
+            cache: Arc::new(Cache::with_capacity(0)),
+            config,
+        };
+        let server = tokio::spawn(async move { server.run().await });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for id in 0..QUERIES {
+            let mut req = Message::new();
+            req.set_id(id)
+                .set_recursion_desired(true)
+                .add_query(Query::query(Name::from_ascii("app.lan.").unwrap(), RecordType::A));
+            client.send_to(&req.to_vec().unwrap(), server_addr).await.unwrap();
+        }
+        let mut ids = HashSet::new();
+        let mut buf = [0; MAX_UDP_PACKET_SIZE];
+        while ids.len() < QUERIES as usize {
+            let len = match tokio::time::timeout(Duration::from_secs(1), client.recv(&mut buf)).await {
+                Ok(len) => len.unwrap(),
+                // 发送过快时内核可能丢弃部分数据报
+                Err(_) => break,
+            };
+            let res = Message::from_bytes(&buf[..len]).unwrap();
+            assert_eq!(res.answers().len(), 1);
+            ids.insert(res.id());
+        }
+        assert!(ids.len() > QUERIES as usize / 2, "only {} responses", ids.len());
+
+        shutdown_signal.cancel();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn tcp_slow_and_oversized_requests() {
+        let config = Arc::new(
+            Config::from_text(
+                "[server]\ndefault 127.0.0.1:1\n[metadata]\ntcp-max-message-size 512\ntcp-read-timeout 200\n[hosts]\n10.0.0.1 app.lan\n",
+            )
+            .unwrap(),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let shutdown_signal = CancellationToken::new();
+        let mut server = TcpServer {
+            socket: Arc::new(listener),
+            limit_connections: Arc::new(Semaphore::new(MAX_CONNECTIONS)),
+            shutdown_signal: shutdown_signal.clone(),
+            cache: Arc::new(Cache::with_capacity(0)),
+            config,
+        };
+        let server = tokio::spawn(async move { server.run().await });
+
+        // 声明最大长度但不发送任何数据
+        let mut slow = TcpStream::connect(server_addr).await.unwrap();
+        slow.write_all(&u16::MAX.to_be_bytes()).await.unwrap();
+        let mut oversized = TcpStream::connect(server_addr).await.unwrap();
+        oversized.write_all(&1024u16.to_be_bytes()).await.unwrap();
+
+        // 慢速客户端不影响其它连接
+        let mut client = TcpStream::connect(server_addr).await.unwrap();
+        let mut req = Message::new();
+        req.set_id(0x1234)
+            .add_query(Query::query(Name::from_ascii("app.lan.").unwrap(), RecordType::A));
+        let bytes = req.to_vec().unwrap();
+        client.write_all(&(bytes.len() as u16).to_be_bytes()).await.unwrap();
+        client.write_all(&bytes).await.unwrap();
+        let mut len_bytes = [0; 2];
+        client.read_exact(&mut len_bytes).await.unwrap();
+        let mut buf = vec![0; u16::from_be_bytes(len_bytes) as usize];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(Message::from_bytes(&buf).unwrap().answers().len(), 1);
+
+        let mut buf = [0; 1];
+        let closed = tokio::time::timeout(Duration::from_millis(100), oversized.read(&mut buf))
+            .await
+            .unwrap();
+        assert_eq!(closed.unwrap(), 0);
+        let closed = tokio::time::timeout(Duration::from_secs(1), slow.read(&mut buf))
+            .await
+            .unwrap();
+        assert_eq!(closed.unwrap(), 0);
+
+        shutdown_signal.cancel();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn blocklist_from_url() {
+        use crate::config::BlockAction;
+        let cache = std::env::temp_dir().join(format!("pomelo-blocklist-{}.txt", std::process::id()));
+        let _ = std::fs::remove_file(&cache);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let http = tokio::spawn(async move {
+            for (status, body) in [
+                ("200 OK", "ads.example\n*.tracker.example # comment\n"),
+                ("500 Internal Server Error", ""),
+            ] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut req = Vec::new();
+                let mut buf = [0; 1024];
+                while !req.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    req.extend_from_slice(&buf[..n]);
+                }
+                assert!(req.starts_with(b"GET /ads.txt HTTP/1.1\r\n"));
+                let res = format!("HTTP/1.1 {status}\r\ncontent-length: {}\r\n\r\n{body}", body.len());
+                stream.write_all(res.as_bytes()).await.unwrap();
+            }
+        });
+        let text = format!(
+            "[server]\ndefault 127.0.0.1:1\n[blocklist]\nads http://{server_addr}/ads.txt {} refused\n",
+            cache.display()
+        );
+        let config = Config::from_text(&text).unwrap();
+        let blocked = |config: &Config, domain: &str| {
+            config
+                .access()
+                .get_block_action(&Name::from_ascii(domain).unwrap())
+                .map(|(name, action)| (name.to_string(), action))
+        };
+        assert_eq!(blocked(&config, "ads.example."), None);
+
+        update_blocklists(&config).await;
+        assert_eq!(
+            blocked(&config, "ads.example."),
+            Some(("ads".to_string(), BlockAction::Refused))
+        );
+        assert!(blocked(&config, "cdn.tracker.example.").is_some());
+
+        // 下载失败时保留原有列表
+        update_blocklists(&config).await;
+        assert!(blocked(&config, "ads.example.").is_some());
+        http.await.unwrap();
+
+        // 重载配置时从缓存文件读取
+        config.reload_from_text(&text).unwrap();
+        assert!(blocked(&config, "ads.example.").is_some());
+        std::fs::remove_file(&cache).unwrap();
+    }
+
+    #[tokio::test]
+    async fn proxy_protocol_client_addr() {
+        let text = |trusted: &str| {
+            format!(
+                "[group]\nbehind-lb 203.0.113.0/24\n[server]\ndefault 127.0.0.1:1\n[metadata]\nproxy-protocol true\n{trusted}[hosts]\n10.0.0.1 app.lan\n[hosts.behind-lb]\n10.0.0.9 app.lan\n"
+            )
+        };
+        // 未配置受信任的代理时拒绝加载
+        assert!(Config::from_text(&text("")).is_err());
+        let config =
+            Arc::new(Config::from_text(&text("proxy-protocol-from 127.0.0.1\n")).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let shutdown_signal = CancellationToken::new();
+        let mut server = TcpServer {
+            socket: Arc::new(listener),
+            limit_connections: Arc::new(Semaphore::new(MAX_CONNECTIONS)),
+            shutdown_signal: shutdown_signal.clone(),
+            cache: Arc::new(Cache::with_capacity(0)),
+            config: config.clone(),
+        };
+        let server = tokio::spawn(async move { server.run().await });
+
+        let mut req = Message::new();
+        req.set_id(0x1234)
+            .add_query(Query::query(Name::from_ascii("app.lan.").unwrap(), RecordType::A));
+        let bytes = req.to_vec().unwrap();
+        for (header, expected) in [
+            (&b"PROXY TCP4 203.0.113.7 127.0.0.1 5555 53\r\n"[..], "10.0.0.9"),
+            (&b"PROXY TCP4 198.51.100.7 127.0.0.1 5555 53\r\n"[..], "10.0.0.1"),
+        ] {
+            let mut client = TcpStream::connect(server_addr).await.unwrap();
+            client.write_all(header).await.unwrap();
+            client.write_all(&(bytes.len() as u16).to_be_bytes()).await.unwrap();
+            client.write_all(&bytes).await.unwrap();
+            let mut len_bytes = [0; 2];
+            client.read_exact(&mut len_bytes).await.unwrap();
+            let mut buf = vec![0; u16::from_be_bytes(len_bytes) as usize];
+            client.read_exact(&mut buf).await.unwrap();
+            let res = Message::from_bytes(&buf).unwrap();
+            assert_eq!(res.answers()[0].data().unwrap().to_string(), expected);
+        }
+
+        // 缺少 PROXY 协议头或来自不受信任的代理的连接被丢弃，未读取的数据可能使连接被重置
+        let dropped = |header: &'static [u8]| {
+            let bytes = bytes.clone();
+            async move {
+                let mut client = TcpStream::connect(server_addr).await.unwrap();
+                client.write_all(header).await.unwrap();
+                client.write_all(&(bytes.len() as u16).to_be_bytes()).await.unwrap();
+                client.write_all(&bytes).await.unwrap();
+                let mut buf = [0; 1];
+                let closed = tokio::time::timeout(Duration::from_secs(1), client.read(&mut buf))
+                    .await
+                    .unwrap();
+                matches!(closed, Ok(0) | Err(_))
+            }
+        };
+        assert!(dropped(b"").await);
+        config
+            .reload_from_text(&text("proxy-protocol-from 192.0.2.0/24\n"))
+            .unwrap();
+        assert!(dropped(b"PROXY TCP4 203.0.113.7 127.0.0.1 5555 53\r\n").await);
+
+        shutdown_signal.cancel();
+        server.await.unwrap().unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn unix_socket_requests() {
+        let config = Arc::new(
+            Config::from_text("[server]\ndefault 127.0.0.1:1\n[hosts]\n10.0.0.1 app.lan\n")
+                .unwrap(),
+        );
+        let path = std::env::temp_dir().join(format!("pomelo-{}.sock", std::process::id()));
+        // 路径上的普通文件不会被删除
+        std::fs::write(&path, b"data").unwrap();
+        assert!(bind_unix(&path).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"data");
+        std::fs::remove_file(&path).unwrap();
+        // 遗留的套接字文件不影响绑定
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        let shutdown_signal = CancellationToken::new();
+        let mut server = UnixServer {
+            socket: bind_unix(&path).unwrap(),
+            path: path.clone(),
+            limit_connections: Arc::new(Semaphore::new(MAX_CONNECTIONS)),
+            shutdown_signal: shutdown_signal.clone(),
+            cache: Arc::new(Cache::with_capacity(0)),
+            config,
+        };
+        let server = tokio::spawn(async move { server.run().await });
+
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        let mut req = Message::new();
+        req.set_id(0x1234)
+            .add_query(Query::query(Name::from_ascii("app.lan.").unwrap(), RecordType::A));
+        let bytes = req.to_vec().unwrap();
+        client.write_all(&(bytes.len() as u16).to_be_bytes()).await.unwrap();
+        client.write_all(&bytes).await.unwrap();
+        let mut len_bytes = [0; 2];
+        client.read_exact(&mut len_bytes).await.unwrap();
+        let mut buf = vec![0; u16::from_be_bytes(len_bytes) as usize];
+        client.read_exact(&mut buf).await.unwrap();
+        let res = Message::from_bytes(&buf).unwrap();
+        assert_eq!(res.id(), 0x1234);
+        assert_eq!(res.answers().len(), 1);
+
+        shutdown_signal.cancel();
+        server.await.unwrap().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn udp_socket_buffers() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (rcvbuf, sndbuf) = apply_socket_buffers(&socket, Some(65536), Some(32768)).unwrap();
+        // Linux 会将设置值加倍以预留内核开销
+        assert!(rcvbuf >= 65536, "{rcvbuf}");
+        assert!(sndbuf >= 32768, "{sndbuf}");
+        let (default_rcvbuf, _) = apply_socket_buffers(
+            &UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            Some(4096),
+            None,
+        )
+        .unwrap();
+        assert!(default_rcvbuf < rcvbuf);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn tcp_listen_backlog() {
+        use std::os::fd::AsRawFd;
+        // 监听状态下 tcp_info.tcpi_sacked（偏移 28）为 listen 时指定的队列长度
+        let max_backlog = |listener: &TcpListener| {
+            let mut info = [0u8; 104];
+            let mut len = info.len() as libc::socklen_t;
+            let ret = unsafe {
+                libc::getsockopt(
+                    listener.as_raw_fd(),
+                    libc::IPPROTO_TCP,
+                    libc::TCP_INFO,
+                    info.as_mut_ptr() as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+            assert_eq!(ret, 0);
+            u32::from_ne_bytes(info[28..32].try_into().unwrap())
+        };
+        let listener = bind_tcp("127.0.0.1:0", 37, None).await.unwrap();
+        assert_eq!(max_backlog(&listener), 37);
+        let listener = bind_tcp("127.0.0.1:0", 1024, None).await.unwrap();
+        assert_eq!(max_backlog(&listener), 1024);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn udp_reuseport_workers() {
+        let config = Arc::new(
+            Config::from_text("[server]\ndefault 127.0.0.1:1\n[hosts]\n10.0.0.1 app.lan\n")
+                .unwrap(),
+        );
+        let sockets = bind_udp("127.0.0.1:0", 3, None).await.unwrap();
+        assert_eq!(sockets.len(), 3);
+        let server_addr = sockets[0].local_addr().unwrap();
+        assert!(sockets.iter().all(|it| it.local_addr().unwrap() == server_addr));
+        let shutdown_signal = CancellationToken::new();
+        let cache = Arc::new(Cache::with_capacity(0));
+        let mut servers = JoinSet::new();
+        for socket in sockets {
+            let mut server = UdpServer {
+                socket: Arc::new(socket),
+                limit_connections: Arc::new(Semaphore::new(MAX_CONNECTIONS)),
+                shutdown_signal: shutdown_signal.clone(),
+                cache: cache.clone(),
+                config: config.clone(),
+            };
+            servers.spawn(async move { server.run().await });
+        }
+
+        // 不同源端口的查询由内核分发到各个套接字
+        for id in 0..30u16 {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut req = Message::new();
+            req.set_id(id)
+                .add_query(Query::query(Name::from_ascii("app.lan.").unwrap(), RecordType::A));
+            client.send_to(&req.to_vec().unwrap(), server_addr).await.unwrap();
+            let mut buf = [0; MAX_UDP_PACKET_SIZE];
+            let len = tokio::time::timeout(Duration::from_secs(1), client.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let res = Message::from_bytes(&buf[..len]).unwrap();
+            assert_eq!(res.id(), id);
+            assert_eq!(res.answers().len(), 1);
+        }
+
+        shutdown_signal.cancel();
+        while let Some(result) = servers.join_next().await {
+            result.unwrap().unwrap();
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn ipv6_dual_stack_bind() {
+        let config = Arc::new(
+            Config::from_text("[server]\ndefault 127.0.0.1:1\n[hosts]\n10.0.0.1 app.lan\n")
+                .unwrap(),
+        );
+        let udp = bind_udp("[::]:0", 1, Some(false)).await.unwrap().remove(0);
+        let tcp = bind_tcp("[::]:0", 1024, Some(false)).await.unwrap();
+        assert!(!socket2::SockRef::from(&udp).only_v6().unwrap());
+        assert!(!socket2::SockRef::from(&tcp).only_v6().unwrap());
+        let udp_port = udp.local_addr().unwrap().port();
+        let tcp_port = tcp.local_addr().unwrap().port();
+        let shutdown_signal = CancellationToken::new();
+        let cache = Arc::new(Cache::with_capacity(0));
+        let mut udp_server = UdpServer {
+            socket: Arc::new(udp),
+            limit_connections: Arc::new(Semaphore::new(MAX_CONNECTIONS)),
+            shutdown_signal: shutdown_signal.clone(),
+            cache: cache.clone(),
+            config: config.clone(),
+        };
+        let mut tcp_server = TcpServer {
+            socket: Arc::new(tcp),
+            limit_connections: Arc::new(Semaphore::new(MAX_CONNECTIONS)),
+            shutdown_signal: shutdown_signal.clone(),
+            cache,
+            config,
+        };
+        let udp_server = tokio::spawn(async move { udp_server.run().await });
+        let tcp_server = tokio::spawn(async move { tcp_server.run().await });
+
+        // IPv4 客户端经由映射地址访问绑定在 [::] 上的服务
+        let mut req = Message::new();
+        req.set_id(0x1234)
+            .add_query(Query::query(Name::from_ascii("app.lan.").unwrap(), RecordType::A));
+        let bytes = req.to_vec().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(&bytes, ("127.0.0.1", udp_port)).await.unwrap();
+        let mut buf = [0; MAX_UDP_PACKET_SIZE];
+        let len = tokio::time::timeout(Duration::from_secs(1), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(Message::from_bytes(&buf[..len]).unwrap().answers().len(), 1);
+
+        let mut client = TcpStream::connect(("127.0.0.1", tcp_port)).await.unwrap();
+        client.write_all(&(bytes.len() as u16).to_be_bytes()).await.unwrap();
+        client.write_all(&bytes).await.unwrap();
+        let mut len_bytes = [0; 2];
+        client.read_exact(&mut len_bytes).await.unwrap();
+        let mut buf = vec![0; u16::from_be_bytes(len_bytes) as usize];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(Message::from_bytes(&buf).unwrap().answers().len(), 1);
+
+        // 显式开启时仅接收 IPv6
+        let listener = bind_tcp("[::]:0", 1024, Some(true)).await.unwrap();
+        assert!(socket2::SockRef::from(&listener).only_v6().unwrap());
+        let port = listener.local_addr().unwrap().port();
+        assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+
+        shutdown_signal.cancel();
+        udp_server.await.unwrap().unwrap();
+        tcp_server.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,122 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+/// 应答阶段：D 拒绝客户端、B 拦截、L 本地、C 缓存、R 拒绝 ANY、根域及顶级域、超长域名、多问题、不支持的操作码及 RD=0 的转发、V 版本、状态及自身域名查询、F 转发、E 错误
+const STAGES: [char; 8] = ['D', 'B', 'L', 'C', 'R', 'V', 'F', 'E'];
+
+pub struct Stats {
+    queries: AtomicU64,
+    stages: [AtomicU64; STAGES.len()],
+    upstream_queries: AtomicU64,
+    upstream_micros: AtomicU64,
+    dot_reused: AtomicU64,
+    dot_created: AtomicU64,
+}
+
+pub static STATS: Stats = Stats::new();
+
+/// 进程启动时间，由 mark_started 在启动时初始化
+static STARTED: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+pub fn mark_started() {
+    LazyLock::force(&STARTED);
+}
+
+pub fn uptime() -> Duration {
+    STARTED.elapsed()
+}
+
+impl Stats {
+    const fn new() -> Self {
+        Self {
+            queries: AtomicU64::new(0),
+            stages: [const { AtomicU64::new(0) }; STAGES.len()],
+            upstream_queries: AtomicU64::new(0),
+            upstream_micros: AtomicU64::new(0),
+            dot_reused: AtomicU64::new(0),
+            dot_created: AtomicU64::new(0),
+        }
+    }
+    /// 记录一次查询及其应答阶段
+    pub fn record_stage(&self, stage: char) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        if let Some(idx) = STAGES.iter().position(|it| *it == stage) {
+            self.stages[idx].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    /// 记录一次成功的上游查询耗时
+    pub fn record_upstream(&self, elapsed: Duration) {
+        self.upstream_queries.fetch_add(1, Ordering::Relaxed);
+        self.upstream_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+    /// 记录一次 DoT 连接获取，reuse 表示取自连接池
+    pub fn record_dot_stream(&self, reuse: bool) {
+        let counter = if reuse {
+            &self.dot_reused
+        } else {
+            &self.dot_created
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+    /// DoT 连接池命中及新建连接的次数
+    pub fn dot_streams(&self) -> (u64, u64) {
+        (
+            self.dot_reused.load(Ordering::Relaxed),
+            self.dot_created.load(Ordering::Relaxed),
+        )
+    }
+    pub fn queries(&self) -> u64 {
+        self.queries.load(Ordering::Relaxed)
+    }
+    fn stage(&self, c: char) -> u64 {
+        STAGES
+            .iter()
+            .position(|it| *it == c)
+            .map(|idx| self.stages[idx].load(Ordering::Relaxed))
+            .unwrap_or_default()
+    }
+    /// 缓存应答占缓存及转发应答的百分比
+    pub fn hit_ratio(&self) -> f64 {
+        let (cached, forwarded) = (self.stage('C'), self.stage('F'));
+        if cached + forwarded == 0 {
+            0.0
+        } else {
+            cached as f64 * 100.0 / (cached + forwarded) as f64
+        }
+    }
+    pub fn summary(&self) -> String {
+        let upstream_queries = self.upstream_queries.load(Ordering::Relaxed);
+        let latency = if upstream_queries == 0 {
+            0.0
+        } else {
+            self.upstream_micros.load(Ordering::Relaxed) as f64 / upstream_queries as f64 / 1000.0
+        };
+        let mut stages = String::new();
+        for c in STAGES {
+            let _ = write!(stages, " {}={}", c, self.stage(c));
+        }
+        let (dot_reused, dot_created) = self.dot_streams();
+        format!(
+            "Stats: queries={} cache-hit={:.1}% upstream-latency={:.1}ms dot-pool: reused={} created={} stages:{}",
+            self.queries(),
+            self.hit_ratio(),
+            latency,
+            dot_reused,
+            dot_created,
+            stages
+        )
+    }
+}
+
+/// 按固定间隔输出统计信息
+pub async fn report(interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        tracing::info!("{}", STATS.summary());
+    }
+}